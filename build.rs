@@ -8,6 +8,8 @@ fn main() {
     bindgen("Witch");
     bindgen("FlashLiquidator");
     bindgen("IMulticall2");
+    bindgen("IERC20");
+    bindgen("IOracle");
 }
 
 #[allow(dead_code)]
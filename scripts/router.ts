@@ -6,6 +6,7 @@ import { parse } from 'ts-command-line-args'
 import { Logger } from 'tslog'
 
 import { providers, BigNumber, Contract } from 'ethers'
+import * as readline from 'readline'
 
 const logger: Logger = new Logger()
 
@@ -13,13 +14,22 @@ interface Args {
   rpc_url: string
   chain_id: number
   v3_swap_router_address: string
+  from_address?: string
+  token_in?: string
+  token_out?: string
+  amount_out?: string
+  duration: number
+  slippage_pct: number
+  silent: boolean
+  daemon: boolean
+}
+
+// What to swap; in daemon mode, the params of a `route` request
+interface SwapRequest {
   from_address: string
   token_in: string
   token_out: string
   amount_out: string
-  duration: number
-  slippage_pct: number
-  silent: boolean
 }
 
 async function getDecimals(provider: providers.BaseProvider, address: string): Promise<number> {
@@ -43,57 +53,102 @@ async function getDecimals(provider: providers.BaseProvider, address: string): P
   return await token.callStatic.decimals()
 }
 
+async function route(
+  provider: providers.JsonRpcProvider,
+  router: AlphaRouter,
+  chain_id: number,
+  args: Args,
+  request: SwapRequest
+) {
+  const token_in = new Token(chain_id, request.token_in, await getDecimals(provider, request.token_in), '', '')
+
+  const token_out = new Token(chain_id, request.token_out, await getDecimals(provider, request.token_out), '', '')
+
+  const token_out_amount = CurrencyAmount.fromRawAmount(token_out, request.amount_out)
+
+  logger.info('Router built; quoting...')
+  const route = await router.route(token_out_amount, token_in, TradeType.EXACT_OUTPUT, {
+    recipient: request.from_address,
+    slippageTolerance: new Percent(args.slippage_pct, 100),
+    deadline: (await provider.getBlock(await provider.getBlockNumber())).timestamp + args.duration,
+  })
+
+  logger.info(`Quote Exact Out: ${route!.quote.toFixed(2)}`)
+  logger.info(`Gas Adjusted Quote Out: ${route!.quoteGasAdjusted.toFixed(2)}`)
+  logger.info(`Gas Used USD: ${route!.estimatedGasUsedUSD.toFixed(6)}`)
+
+  return {
+    data: route!.methodParameters!.calldata,
+    to: args.v3_swap_router_address,
+    value: BigNumber.from(route!.methodParameters!.value).toString(),
+    from: request.from_address,
+    gasPrice: BigNumber.from(route!.gasPriceWei).toString(),
+    amount_in: route!.quote.quotient.toString(),
+  }
+}
+
+// Answers newline-delimited JSON-RPC requests (`{"jsonrpc": "2.0", "id": 1, "method": "route", "params": {...}}`)
+// from stdin, one at a time, on stdout. Logs go to stderr
+async function serve(provider: providers.JsonRpcProvider, router: AlphaRouter, chain_id: number, args: Args) {
+  const lines = readline.createInterface({ input: process.stdin, terminal: false })
+  for await (const line of lines) {
+    if (line.trim() === '') {
+      continue
+    }
+    let id = null
+    try {
+      const request = JSON.parse(line)
+      id = request.id
+      if (request.method !== 'route') {
+        throw new Error(`unknown method: ${request.method}`)
+      }
+      const result = await route(provider, router, chain_id, args, request.params)
+      process.stdout.write(JSON.stringify({ jsonrpc: '2.0', id, result }) + '\n')
+    } catch (e) {
+      logger.error('Failed to route: ', e)
+      process.stdout.write(JSON.stringify({ jsonrpc: '2.0', id, error: { code: -32000, message: `${e}` } }) + '\n')
+    }
+  }
+}
+
 async function main() {
   const args = parse<Args>({
     rpc_url: { type: String },
     chain_id: { type: Number, defaultValue: 1 },
     // https://docs.uniswap.org/protocol/reference/deployments
     v3_swap_router_address: { type: String, defaultValue: '0x68b3465833fb72A70ecDF485E0e4C7bD8665Fc45' },
-    from_address: { type: String },
-    token_in: { type: String },
-    token_out: { type: String },
-    amount_out: { type: String },
+    from_address: { type: String, optional: true },
+    token_in: { type: String, optional: true },
+    token_out: { type: String, optional: true },
+    amount_out: { type: String, optional: true },
     duration: { type: Number, defaultValue: 300 },
     slippage_pct: { type: Number, defaultValue: 3 },
     silent: { type: Boolean, defaultValue: false },
+    daemon: { type: Boolean, defaultValue: false },
   })
 
   if (args.silent) {
     logger.setSettings({ suppressStdOutput: true })
+  } else if (args.daemon) {
+    // stdout is for the responses
+    logger.setSettings({ stdOut: process.stderr })
   }
 
   const chain_id = args.chain_id == 31337 ? 1 : args.chain_id // pretend hardhat is mainnet
   const provider = new providers.JsonRpcProvider(args.rpc_url)
   const router = new AlphaRouter({ chainId: chain_id, provider })
 
-  // logger.info("token in decimals: ", );
-  // logger.info("token out decimals: ", await getDecimals(provider, args.token_out));
-  // return;
-
-  const token_in = new Token(chain_id, args.token_in, await getDecimals(provider, args.token_in), '', '')
-
-  const token_out = new Token(chain_id, args.token_out, await getDecimals(provider, args.token_out), '', '')
-
-  const token_out_amount = CurrencyAmount.fromRawAmount(token_out, args.amount_out)
+  if (args.daemon) {
+    await serve(provider, router, chain_id, args)
+    return
+  }
 
-  logger.info('Router built; quoting...')
-  const route = await router.route(token_out_amount, token_in, TradeType.EXACT_OUTPUT, {
-    recipient: args.from_address,
-    slippageTolerance: new Percent(args.slippage_pct, 100),
-    deadline: (await provider.getBlock(await provider.getBlockNumber())).timestamp + args.duration,
+  const transaction = await route(provider, router, chain_id, args, {
+    from_address: args.from_address!,
+    token_in: args.token_in!,
+    token_out: args.token_out!,
+    amount_out: args.amount_out!,
   })
-
-  logger.info(`Quote Exact Out: ${route!.quote.toFixed(2)}`)
-  logger.info(`Gas Adjusted Quote Out: ${route!.quoteGasAdjusted.toFixed(2)}`)
-  logger.info(`Gas Used USD: ${route!.estimatedGasUsedUSD.toFixed(6)}`)
-
-  const transaction = {
-    data: route!.methodParameters!.calldata,
-    to: args.v3_swap_router_address,
-    value: BigNumber.from(route!.methodParameters!.value).toString(),
-    from: args.from_address,
-    gasPrice: BigNumber.from(route!.gasPriceWei).toString(),
-  }
   if (args.silent) {
     console.log(JSON.stringify(transaction))
   } else {
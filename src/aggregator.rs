@@ -0,0 +1,149 @@
+//! HTTP swap aggregator
+//!
+//! Asks an HTTP service for the swap calldata: `GET {url}/quote` with the
+//! chain id, the tokens, the output amount, the recipient and the slippage,
+//! answering `{"data": "0x...", "amountIn": "..."}`. The calldata must be a
+//! SwapRouter02 exact-output call, since that's where the flash liquidator
+//! sends it; `SwapRouter` checks it, and doesn't trust `amountIn`.
+use crate::swap_router::{SwapCalldata, SwapRouteProvider, SwapRouterError};
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::instrument;
+
+#[derive(Error, Debug)]
+pub enum AggregatorError {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("aggregator error: {0}")]
+    Aggregator(String),
+}
+
+#[derive(Deserialize)]
+struct AggregatorQuote {
+    data: Bytes,
+    #[serde(rename = "amountIn")]
+    amount_in: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpAggregator {
+    url: String,
+    chain_id: u64,
+    /// How much more than quoted the swap may take, percent
+    slippage_pct: u16,
+    http: reqwest::Client,
+}
+
+impl HttpAggregator {
+    /// Constructor
+    pub fn new(url: String, chain_id: u64, slippage_pct: u16) -> Self {
+        HttpAggregator {
+            url,
+            chain_id,
+            slippage_pct,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn quote(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<SwapCalldata, AggregatorError> {
+        let response = self
+            .http
+            .get(format!("{}/quote", self.url.trim_end_matches('/')))
+            .query(&[
+                ("chainId", self.chain_id.to_string()),
+                ("tokenIn", format!("{:?}", token_in)),
+                ("tokenOut", format!("{:?}", token_out)),
+                ("amountOut", amount_out.to_string()),
+                ("recipient", format!("{:?}", recipient)),
+                ("slippagePct", self.slippage_pct.to_string()),
+            ])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(AggregatorError::Aggregator(format!("{}: {}", status, body)));
+        }
+        let quote: AggregatorQuote = response.json().await?;
+        let amount_in = U256::from_dec_str(&quote.amount_in)
+            .map_err(|x| AggregatorError::Aggregator(format!("bad amountIn {:?}: {}", quote.amount_in, x)))?;
+        Ok(SwapCalldata { calldata: quote.data.to_vec(), amount_in })
+    }
+}
+
+#[async_trait]
+impl SwapRouteProvider for HttpAggregator {
+    fn name(&self) -> &str {
+        "aggregator"
+    }
+
+    async fn build_swap_exact_out(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<SwapCalldata, SwapRouterError> {
+        Ok(self.quote(recipient, token_in, token_out, amount_out).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use std::convert::Infallible;
+
+    /// Serves a quote for 1000 of the output token, and a 400 otherwise
+    async fn mock_aggregator() -> String {
+        let make_service = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let query = req.uri().query().unwrap_or_default().to_string();
+                let response = if req.uri().path() == "/quote" && query.contains("amountOut=1000") {
+                    Response::new(Body::from(r#"{"data": "0x0102", "amountIn": "42"}"#))
+                } else {
+                    Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from("no route"))
+                        .unwrap()
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn quotes_from_the_aggregator() {
+        let aggregator = HttpAggregator::new(mock_aggregator().await, 1, 3);
+        let (token_in, token_out) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+
+        let swap = SwapRouteProvider::build_swap_exact_out(&aggregator, Address::zero(), token_in, token_out, 1000.into())
+            .await
+            .unwrap();
+        assert_eq!(swap.calldata, vec![1, 2]);
+        assert_eq!(swap.amount_in, 42.into());
+
+        let err = SwapRouteProvider::build_swap_exact_out(&aggregator, Address::zero(), token_in, token_out, 1.into())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("aggregator error"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Historical backfill
+//!
+//! Splits a (potentially huge) block range into chunks small enough for
+//! `eth_getLogs`, shrinking them when the provider rejects a range and growing
+//! them back once it accepts them again.
+
+/// Walks `from..=to` in chunks of at most `max_chunk` blocks
+#[derive(Clone, Debug)]
+pub struct Backfill {
+    /// First block which wasn't processed yet
+    next: u64,
+    /// Last block to process
+    to: u64,
+    /// Size of the next chunk
+    chunk: u64,
+    max_chunk: u64,
+}
+
+impl Backfill {
+    /// Constructor
+    pub fn new(from: u64, to: u64, max_chunk: u64) -> Self {
+        let max_chunk = std::cmp::max(max_chunk, 1);
+        Backfill {
+            next: from,
+            to,
+            chunk: max_chunk,
+            max_chunk,
+        }
+    }
+
+    /// The next range to query (both ends inclusive), if any
+    pub fn next_range(&self) -> Option<(u64, u64)> {
+        if self.next > self.to {
+            return None;
+        }
+        Some((self.next, std::cmp::min(self.next + self.chunk - 1, self.to)))
+    }
+
+    /// The last range was processed: move on, with a bigger chunk if it was
+    /// shrunk before
+    pub fn on_success(&mut self) {
+        if let Some((_, to)) = self.next_range() {
+            self.next = to + 1;
+        }
+        self.chunk = std::cmp::min(self.chunk * 2, self.max_chunk);
+    }
+
+    /// The last range was rejected: halve it.
+    ///
+    /// Returns false if it was a single block already, i.e. there's nothing
+    /// left to try
+    pub fn on_failure(&mut self) -> bool {
+        let size = match self.next_range() {
+            Some((from, to)) => to - from + 1,
+            None => return false,
+        };
+        if size <= 1 {
+            return false;
+        }
+        self.chunk = size / 2;
+        true
+    }
+
+    /// Percentage of the range which was processed
+    pub fn progress_pct(&self, from: u64) -> u64 {
+        if self.to < from {
+            return 100;
+        }
+        std::cmp::min(self.next.saturating_sub(from) * 100 / (self.to - from + 1), 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_range_in_chunks() {
+        let mut backfill = Backfill::new(10, 34, 10);
+        let mut ranges = vec![];
+        while let Some(range) = backfill.next_range() {
+            ranges.push(range);
+            backfill.on_success();
+        }
+        assert_eq!(ranges, vec![(10, 19), (20, 29), (30, 34)]);
+        assert_eq!(backfill.progress_pct(10), 100);
+    }
+
+    #[test]
+    fn halves_rejected_ranges_and_grows_back() {
+        let mut backfill = Backfill::new(0, 99, 40);
+        assert_eq!(backfill.next_range(), Some((0, 39)));
+        assert!(backfill.on_failure());
+        assert_eq!(backfill.next_range(), Some((0, 19)));
+        assert!(backfill.on_failure());
+        assert_eq!(backfill.next_range(), Some((0, 9)));
+        backfill.on_success();
+        assert_eq!(backfill.next_range(), Some((10, 29)));
+        backfill.on_success();
+        assert_eq!(backfill.next_range(), Some((30, 69)));
+        assert_eq!(backfill.progress_pct(0), 30);
+    }
+
+    #[test]
+    fn gives_up_on_single_block() {
+        let mut backfill = Backfill::new(5, 5, 1);
+        assert_eq!(backfill.next_range(), Some((5, 5)));
+        assert!(!backfill.on_failure());
+    }
+}
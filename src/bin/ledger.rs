@@ -0,0 +1,40 @@
+use yield_liquidator::ledger::{write_pnl_csv, Grouping, Ledger};
+
+use gumdrop::Options;
+use std::{fs::File, io::Write, path::PathBuf};
+
+// CLI Options
+#[derive(Debug, Options, Clone)]
+struct Opts {
+    help: bool,
+
+    #[options(help = "the ledger written by the liquidator", default = "ledger.jsonl")]
+    ledger: PathBuf,
+
+    #[options(help = "group the PnL by 'day' (UTC) or 'ilk'", default = "day")]
+    by: String,
+
+    #[options(help = "where to write the CSV (defaults to stdout)")]
+    output: Option<PathBuf>,
+}
+
+fn main_impl() -> anyhow::Result<()> {
+    let opts = Opts::parse_args_default_or_exit();
+    let grouping: Grouping = opts.by.parse()?;
+    let entries = Ledger::new(opts.ledger).read()?;
+
+    let mut out: Box<dyn Write> = match &opts.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    write_pnl_csv(&entries, grouping, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = main_impl() {
+        eprintln!("Error: {}", e);
+        std::process::exit(exitcode::DATAERR);
+    }
+}
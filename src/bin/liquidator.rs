@@ -1,10 +1,29 @@
 use ethers::prelude::*;
-use yield_liquidator::{escalator::GeometricGasPrice, keeper::Keeper, bindings::BaseIdType, swap_router::SwapRouter};
+use yield_liquidator::{
+    breaker::BreakerLimits, cancel::CancelPolicy, escalator::GeometricGasPrice, fees::{FeeStrategy, TxFormat}, bindings::{BaseIdType, IlkIdType},
+    aggregator::HttpAggregator,
+    router_daemon::{DaemonConfig, DaemonRouteProvider},
+    swap_router::{BinaryRouteProvider, SwapRouteProvider, SwapRouter},
+    uniswap::{UniswapV3Router, DEFAULT_FEE_TIERS, MAINNET_INTERMEDIARIES, MAINNET_QUOTER_V2},
+    keeper::{Keeper, Shutdown, ShutdownOutcome},
+    failover::FailoverClient,
+    journal::Journal,
+    ledger::Ledger,
+    relay::BundleRelay,
+    store::{JsonFileStore, SqliteStore, StateStore},
+    strategy::{Strategy, StrategyOverride, StrategyParams},
+    routes::FlashRouteConfig,
+    server,
+};
 
 use gumdrop::Options;
 use serde::Deserialize;
 use std::{convert::{TryFrom, TryInto}, path::PathBuf, sync::Arc, time::Duration, collections::HashMap};
-use tracing::info;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{error, info, warn};
 use tracing_subscriber::{filter::EnvFilter, fmt::Subscriber};
 
 // CLI Options
@@ -16,25 +35,37 @@ struct Opts {
     config: PathBuf,
 
     #[options(
-        help = "the Ethereum node endpoint (HTTP or WS)",
+        help = "the Ethereum node endpoint (HTTP or WS), or a comma-separated list of HTTP endpoints to fail over between",
         default = "http://localhost:8545"
     )]
     url: String,
 
+    #[options(help = "with several endpoints: how many of them have to agree on eth_call results", default = "1")]
+    rpc_quorum: usize,
+
+    #[options(help = "with several endpoints: avoid the ones lagging more than this many blocks behind", default = "3")]
+    max_rpc_lag: u64,
+
     #[options(help = "chain id", default = "1")]
     chain_id: u64,
 
     #[options(help = "path to your private key")]
     private_key: PathBuf,
 
-    #[options(help = "polling interval (ms)", default = "1000")]
+    #[options(help = "how often to poll for transaction receipts (ms)", default = "1000")]
     interval: u64,
 
+    #[options(help = "how often to poll for new blocks when not using a WS endpoint (s)", default = "30")]
+    poll_interval: u64,
+
     #[options(help = "Multicall batch size", default = "500")]
     multicall_batch_size: usize,
 
-    #[options(help = "the file to be used for persistence", default = "data.json")]
-    file: PathBuf,
+    #[options(help = "the file to be used for persistence (default: data.json, or data.dry-run.json with --dry-run)")]
+    file: Option<PathBuf>,
+
+    #[options(help = "persistence backend: 'json' or 'sqlite'", default = "json")]
+    store: String,
 
     #[options(help = "the minimum ratio (collateral/debt) to trigger liquidation, percents", default = "110")]
     min_ratio: u16,
@@ -45,21 +76,120 @@ struct Opts {
     #[options(help = "Don't bump gas until the transaction is this many seconds old", default = "90")]
     bump_gas_delay: u64,
 
+    #[options(help = "cancel transactions which were bumped this many times", default = "10")]
+    max_bumps: u32,
+
+    #[options(help = "cancel transactions still pending this many seconds after they were submitted", default = "1800")]
+    cancel_after: u64,
+
+    #[options(help = "max fee per gas, as a multiple of the base fee (plus the tip)", default = "2")]
+    base_fee_multiplier: u64,
+
+    #[options(help = "tip the median of this eth_feeHistory reward percentile", default = "50")]
+    tip_percentile: f64,
+
+    #[options(help = "how many blocks of eth_feeHistory to look at", default = "10")]
+    fee_history_blocks: u64,
+
+    #[options(help = "minimum tip (gwei)", default = "1")]
+    min_tip: u64,
+
+    #[options(help = "transactions to send: 'legacy', 'eip2930', 'eip1559', or 'auto' to follow the blocks' base fee", default = "auto")]
+    tx_format: String,
+
+    #[options(help = "never bid more than this gas price (gwei); stuck transactions are cancelled instead", default = "5000")]
+    max_gas_price: u64,
+
+    #[options(help = "cancellations may pay up to this many times --max-gas-price", default = "3")]
+    max_cancel_price_multiplier: u64,
+
+    #[options(help = "stop sending transactions when our mined transactions cost more than this in the last hour (ETH)")]
+    max_gas_per_hour: Option<f64>,
+
+    #[options(help = "stop sending transactions when our mined transactions cost more than this in the last day (ETH)")]
+    max_gas_per_day: Option<f64>,
+
+    #[options(help = "stop sending transactions when this many of ours reverted in the last --failure-window seconds")]
+    max_failures: Option<u32>,
+
+    #[options(help = "the window of --max-failures, seconds", default = "3600")]
+    failure_window: u64,
+
+    #[options(help = "stop sending transactions when this many of ours for the same vault reverted in a row")]
+    max_consecutive_reverts: Option<u32>,
+
+    #[options(help = "resume sending transactions this many seconds after the circuit breaker tripped, if no limit is breached anymore (defaults to waiting for POST /breaker/resume on --admin-address)")]
+    breaker_cooldown: Option<u64>,
+
     #[options(help = "Buy an auction as soon as this much collateral percentage is offered", default = "90")]
     target_collateral_offer: u16,
 
+    #[options(help = "prepare buys (swap calldata, gas) this many seconds before they're due", default = "60")]
+    prepare_ahead: u64,
+
     #[options(help = "the block to start watching from")]
     start_block: Option<u64>,
 
+    #[options(help = "max number of blocks to query logs for at once when catching up", default = "10000")]
+    backfill_chunk_size: u64,
+
     #[options(default="false", help="Use JSON as log format")]
     json_log: bool,
 
     #[options(default="false", help="Only run 1 iteration and exit")]
     one_shot: bool,
 
+    #[options(help = "simulate transactions instead of sending them, and record them in the journal")]
+    dry_run: bool,
+
+    #[options(help = "dry-run mode: where to record the simulated transactions", default = "journal.jsonl")]
+    journal: PathBuf,
+
+    #[options(help = "where to record what our mined bids earned", default = "ledger.jsonl")]
+    ledger: PathBuf,
+
+    #[options(help = "send bids as bundles to this relay (eth_sendBundle) instead of the public mempool")]
+    relay_url: Option<String>,
+
+    #[options(help = "path to the key signing the requests to the relay (defaults to --private-key)")]
+    relay_auth_key: Option<PathBuf>,
+
+    #[options(help = "on SIGTERM/SIGINT, how long to wait for pending transactions to be mined (s)", default = "0")]
+    shutdown_timeout: u64,
+
     #[options(help = "Path to the swap router binary")]
     swap_router_binary: String,
 
+    #[options(help = "where to get swaps from, comma-separated: 'native' (Uniswap V3 quoter), 'daemon' (--swap-router-binary, kept running, run for each swap when starting or busy), 'binary' (--swap-router-binary, run for each swap), 'aggregator' (--swap-aggregator-url)", default = "native")]
+    swap_router: String,
+
+    #[options(help = "how long to wait for the swap routers (s)", default = "10")]
+    swap_router_timeout: u64,
+
+    #[options(help = "how many swaps may wait for the swap router daemon", default = "8")]
+    swap_router_queue: usize,
+
+    #[options(help = "how long the swap router daemon has to answer once it got a swap, before it's restarted (s)", default = "5")]
+    swap_router_daemon_timeout: u64,
+
+    #[options(help = "HTTP swap aggregator to ask for swaps")]
+    swap_aggregator_url: Option<String>,
+
+    #[options(help = "native swap router and aggregator: how much more collateral than quoted a swap may take, percent", default = "3")]
+    swap_slippage: u16,
+
+    #[options(help = "native swap router: the Uniswap V3 fee tiers to quote, comma-separated (defaults to all of them)")]
+    uniswap_fee_tiers: Option<String>,
+
+    #[options(help = "address to serve Prometheus metrics and health checks on, e.g. 0.0.0.0:9100 (disabled by default)")]
+    metrics_address: Option<String>,
+
+    #[options(help = "health checks fail if no block was processed for this long (s)", default = "300")]
+    max_block_age: u64,
+
+    #[options(help = "loopback address to serve POST /breaker/resume on, e.g. 127.0.0.1:9101 (disabled by default)")]
+    admin_address: Option<String>,
+
     #[options(
         help = "Instance name (used for logging)",
         default = "undefined"
@@ -74,12 +204,28 @@ struct Config {
     witch: Address,
     #[serde(rename = "Flash")]
     flashloan: Address,
+    /// Flash liquidators for the ilks which can't go through `Flash`
+    #[serde(rename = "IlkFlashLiquidators", default)]
+    ilk_flash_liquidators: HashMap<String, FlashRouteConfig>,
     #[serde(rename = "Multicall2")]
     multicall2: Address,
     #[serde(rename = "SwapRouter02")]
     swap_router_02: Address,
+    /// Uniswap's QuoterV2, for the native swap router (required off mainnet)
+    #[serde(rename = "QuoterV2", default)]
+    quoter_v2: Option<Address>,
+    /// The tokens two-hop swaps go through (required off mainnet, defaults to WETH, USDC and DAI on mainnet)
+    #[serde(rename = "SwapIntermediaries", default)]
+    swap_intermediaries: Option<Vec<Address>>,
     #[serde(rename = "BaseToDebtThreshold")]
-    base_to_debt_threshold: HashMap<String, String>
+    base_to_debt_threshold: HashMap<String, String>,
+    /// Minimum profit after gas, in base units. Bases which are not listed
+    /// only need to break even
+    #[serde(rename = "BaseToMinProfit", default)]
+    base_to_min_profit: HashMap<String, String>,
+    /// Overrides of the strategy CLI options, per base, ilk or (base, ilk) pair
+    #[serde(rename = "Strategies", default)]
+    strategies: Vec<StrategyOverride>,
 }
 
 fn init_logger(use_json: bool) {
@@ -99,29 +245,101 @@ fn init_logger(use_json: bool) {
     }
 }
 
-async fn main_impl() -> anyhow::Result<()> {
+/// Exit code when we were asked to stop, but some of our transactions were
+/// still pending
+const EXIT_UNSETTLED: i32 = exitcode::TEMPFAIL;
+
+async fn main_impl() -> anyhow::Result<i32> {
     let opts = Opts::parse_args_default_or_exit();
 
     init_logger(opts.json_log);
 
+    if let Some(metrics_address) = &opts.metrics_address {
+        let addr: std::net::SocketAddr = metrics_address.parse()?;
+        let max_block_age = opts.max_block_age;
+        tokio::spawn(async move {
+            if let Err(x) = server::serve(addr, max_block_age).await {
+                error!(err=?x, "HTTP server failed");
+            }
+        });
+    }
+
+    if let Some(admin_address) = &opts.admin_address {
+        let addr: std::net::SocketAddr = admin_address.parse()?;
+        if !addr.ip().is_loopback() {
+            anyhow::bail!("the admin server must listen on a loopback address, got: {}", addr);
+        }
+        tokio::spawn(async move {
+            if let Err(x) = server::serve_admin(addr).await {
+                error!(err=?x, "Admin HTTP server failed");
+            }
+        });
+    }
 
-    if opts.url.starts_with("http") {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("SIGTERM received"),
+            _ = tokio::signal::ctrl_c() => info!("SIGINT received"),
+        }
+        // the keeper stops once it's done with the current block
+        let _ = shutdown_tx.send(true);
+    });
+    let shutdown = Shutdown {
+        signal: shutdown_rx,
+        settle_timeout: Duration::from_secs(opts.shutdown_timeout),
+    };
+    let poll_interval = Duration::from_secs(opts.poll_interval);
+
+    let urls = opts.url.split(',').map(|x| x.trim().to_string()).collect::<Vec<_>>();
+    let outcome = if urls.len() > 1 {
+        if let Some(url) = urls.iter().find(|x| !x.starts_with("http")) {
+            anyhow::bail!("only HTTP endpoints can be combined, got: {}", url);
+        }
+        let provider = Provider::new(FailoverClient::new(&urls, opts.rpc_quorum, opts.max_rpc_lag)?);
+        let (mut keeper, mut store) = build_keeper(&opts, provider).await?;
+        if opts.one_shot {
+            keeper.one_shot().await?;
+            info!("One shot done");
+            None
+        } else {
+            Some(keeper.run(store.as_mut(), opts.start_block, poll_interval, shutdown).await?)
+        }
+    } else if opts.url.starts_with("http") {
         let provider = Provider::<Http>::try_from(opts.url.clone())?;
-        run(opts, provider).await?;
+        let (mut keeper, mut store) = build_keeper(&opts, provider).await?;
+        if opts.one_shot {
+            keeper.one_shot().await?;
+            info!("One shot done");
+            None
+        } else {
+            Some(keeper.run(store.as_mut(), opts.start_block, poll_interval, shutdown).await?)
+        }
     } else {
         let ws = Ws::connect(opts.url.clone()).await?;
         let provider = Provider::new(ws);
-        run(opts, provider).await?;
-    }
+        let (mut keeper, mut store) = build_keeper(&opts, provider).await?;
+        if opts.one_shot {
+            keeper.one_shot().await?;
+            info!("One shot done");
+            None
+        } else {
+            Some(keeper.run_subscribed(store.as_mut(), opts.start_block, shutdown).await?)
+        }
+    };
 
-    Ok(())
+    match outcome {
+        Some(ShutdownOutcome::Unsettled) => Ok(EXIT_UNSETTLED),
+        Some(ShutdownOutcome::Settled) | None => Ok(exitcode::OK),
+    }
 }
 
 #[tokio::main]
 async fn main() {
     match main_impl().await {
-        Ok(_) => {
-            std::process::exit(exitcode::OK);
+        Ok(code) => {
+            std::process::exit(code);
         }
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -130,13 +348,29 @@ async fn main() {
     };
 }
 
-async fn run<P: JsonRpcClient + 'static>(opts: Opts, provider: Provider<P>) -> anyhow::Result<()> {
+type Client<P> = NonceManagerMiddleware<SignerMiddleware<Provider<P>, LocalWallet>>;
+
+async fn build_keeper<P: JsonRpcClient + 'static>(
+    opts: &Opts,
+    provider: Provider<P>,
+) -> anyhow::Result<(Keeper<Client<P>>, Box<dyn StateStore>)> {
     info!("Starting Yield-v2 Liquidator.");
     let provider = provider.interval(Duration::from_millis(opts.interval));
-    let private_key = std::fs::read_to_string(opts.private_key)?.trim().to_string();
+    let private_key = std::fs::read_to_string(&opts.private_key)?.trim().to_string();
     let wallet: LocalWallet = private_key.parse()?;
     let wallet = wallet.with_chain_id(opts.chain_id);
     let address = wallet.address();
+    let relay = match &opts.relay_url {
+        Some(url) => {
+            let auth_signer = match &opts.relay_auth_key {
+                Some(path) => std::fs::read_to_string(path)?.trim().parse::<LocalWallet>()?,
+                None => wallet.clone(),
+            };
+            info!("Bids will be sent to the relay at {} (auth: {:?})", url, auth_signer.address());
+            Some(BundleRelay::new(url.clone(), wallet.clone(), auth_signer))
+        }
+        None => None,
+    };
     let client = SignerMiddleware::new(provider, wallet);
     let client = NonceManagerMiddleware::new(client, address);
     let client = Arc::new(client);
@@ -144,65 +378,192 @@ async fn run<P: JsonRpcClient + 'static>(opts: Opts, provider: Provider<P>) -> a
 
     info!(instance_name=opts.instance_name.as_str(), "Node: {}", opts.url);
 
-    let cfg: Config = serde_json::from_reader(std::fs::File::open(opts.config)?)?;
+    let cfg: Config = serde_json::from_reader(std::fs::File::open(&opts.config)?)?;
     info!("Witch: {:?}", cfg.witch);
     info!("Multicall2: {:?}", cfg.multicall2);
     info!("FlashLiquidator {:?}", cfg.flashloan);
-    info!("Persistent data will be stored at: {:?}", opts.file);
+    // a dry run must not pick up (and act on) the pending transactions of a real one
+    let file = match &opts.file {
+        Some(x) => x.clone(),
+        None if opts.dry_run => PathBuf::from("data.dry-run.json"),
+        None => PathBuf::from("data.json"),
+    };
+    info!("Persistent data will be stored at: {:?} ({})", file, opts.store);
+
+    let mut store: Box<dyn StateStore> = match opts.store.as_str() {
+        "json" => Box::new(JsonFileStore::new(file.clone(), 10)),
+        "sqlite" => Box::new(SqliteStore::open(file.clone())?),
+        x => anyhow::bail!("unknown store: {}", x),
+    };
+    let state = store.load()?;
 
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&opts.file)
-        .unwrap();
-    let state = serde_json::from_reader(&file).unwrap_or_default();
+    let journal = if opts.dry_run {
+        info!("Dry run: transactions will be simulated and recorded at {:?}", opts.journal);
+        Some(Journal::new(opts.journal.clone()))
+    } else {
+        None
+    };
+
+    // nothing gets mined in dry-run mode
+    let ledger = if opts.dry_run { None } else { Some(Ledger::new(opts.ledger.clone())) };
 
     let mut gas_escalator = GeometricGasPrice::new();
     gas_escalator.coefficient = 1.12501;
-    gas_escalator.every_secs = 5; // TODO: Make this be 90s
-    gas_escalator.max_price = Some(U256::from(5000 * 1e9 as u64)); // 5k gwei
+    gas_escalator.max_price = Some(U256::from(opts.max_gas_price) * U256::exp10(9));
+    let fee_strategy = FeeStrategy {
+        base_fee_multiplier: opts.base_fee_multiplier,
+        tip_percentile: opts.tip_percentile,
+        history_blocks: opts.fee_history_blocks,
+        min_tip: U256::from(opts.min_tip) * U256::exp10(9),
+        tx_format: match opts.tx_format.as_str() {
+            "auto" => None,
+            x => Some(x.parse::<TxFormat>()?),
+        },
+        escalator: gas_escalator,
+        max_cancel_price_multiplier: opts.max_cancel_price_multiplier,
+    };
 
     let base_to_debt_threshold: HashMap<BaseIdType, u128> = cfg.base_to_debt_threshold.iter()
         .map(|(k, v)| { 
             (hex::decode(k).unwrap().try_into().unwrap(), v.parse::<u128>().unwrap())
         })
         .collect();
+    let base_to_min_profit: HashMap<BaseIdType, u128> = cfg.base_to_min_profit.iter()
+        .map(|(k, v)| {
+            let min_profit = v.parse::<u128>()
+                .map_err(|x| anyhow::anyhow!("invalid BaseToMinProfit for {}: {:?} ({})", k, v, x))?;
+            Ok((parse_asset_id("BaseToMinProfit", k)?, min_profit))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let ilk_flash_liquidators: HashMap<IlkIdType, FlashRouteConfig> = cfg.ilk_flash_liquidators.iter()
+        .map(|(k, v)| {
+            info!("FlashLiquidator for {}: {:?} ({:?})", k, v.flash_liquidator, v.calldata);
+            Ok((parse_asset_id("IlkFlashLiquidators", k)?, v.clone()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let strategy = Strategy::new(
+        StrategyParams {
+            min_ratio: opts.min_ratio,
+            target_collateral_offer: opts.target_collateral_offer,
+            gas_boost: opts.gas_boost,
+        },
+        cfg.strategies,
+    )?;
 
     let instance_name = format!("{}.witch={:?}.flash={:?}", opts.instance_name, cfg.witch, cfg.flashloan);
     
+    // the routers only need one endpoint
+    let router_url = opts.url.split(',').next().unwrap_or_default().trim().to_string();
+    let mut route_providers: Vec<Arc<dyn SwapRouteProvider>> = vec![];
+    for provider in opts.swap_router.split(',').map(|x| x.trim()) {
+        match provider {
+            "binary" => route_providers.push(Arc::new(BinaryRouteProvider::new(
+                router_url.clone(),
+                opts.chain_id,
+                opts.swap_router_binary.clone(),
+            ))),
+            "daemon" => {
+                // the swap router's timeout includes the time spent in the queue
+                if opts.swap_router_daemon_timeout >= opts.swap_router_timeout {
+                    warn!("--swap-router-daemon-timeout should be shorter than --swap-router-timeout, or a hung daemon is only restarted after we gave up");
+                }
+                route_providers.push(Arc::new(DaemonRouteProvider::new(DaemonConfig {
+                    router_binary_path: opts.swap_router_binary.clone(),
+                    rpc_url: router_url.clone(),
+                    chain_id: opts.chain_id,
+                    request_timeout: Duration::from_secs(opts.swap_router_daemon_timeout),
+                    queue_size: opts.swap_router_queue,
+                })));
+            }
+            "native" => {
+                let fee_tiers = match &opts.uniswap_fee_tiers {
+                    Some(x) => x.split(',').map(|x| x.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>()?,
+                    None => DEFAULT_FEE_TIERS.to_vec(),
+                };
+                // the defaults are mainnet's addresses
+                let (quoter_v2, intermediaries) = match (cfg.quoter_v2, &cfg.swap_intermediaries) {
+                    (Some(quoter_v2), Some(intermediaries)) => (quoter_v2, intermediaries.clone()),
+                    (quoter_v2, intermediaries) if opts.chain_id == 1 => (
+                        quoter_v2.unwrap_or(MAINNET_QUOTER_V2.parse()?),
+                        match intermediaries {
+                            Some(x) => x.clone(),
+                            None => MAINNET_INTERMEDIARIES.iter().map(|x| x.parse()).collect::<Result<Vec<_>, _>>()?,
+                        },
+                    ),
+                    _ => anyhow::bail!("the native swap router needs QuoterV2 and SwapIntermediaries in the config off mainnet, or pick another --swap-router"),
+                };
+                route_providers.push(Arc::new(UniswapV3Router::new(
+                    client.clone(),
+                    quoter_v2,
+                    cfg.swap_router_02,
+                    fee_tiers,
+                    intermediaries,
+                    opts.swap_slippage,
+                )));
+            }
+            "aggregator" => {
+                let url = opts.swap_aggregator_url.clone()
+                    .ok_or_else(|| anyhow::anyhow!("--swap-aggregator-url is required by the aggregator swap router"))?;
+                route_providers.push(Arc::new(HttpAggregator::new(url, opts.chain_id, opts.swap_slippage)));
+            }
+            x => anyhow::bail!("unknown swap router: {}", x),
+        }
+    }
     let swap_router = SwapRouter::new(
-        opts.url, 
-        opts.chain_id,
-        cfg.swap_router_02,
+        route_providers,
+        Duration::from_secs(opts.swap_router_timeout),
         cfg.flashloan,
-        opts.swap_router_binary,
         instance_name.clone()
     );
 
-    let mut keeper = Keeper::new(
+    let keeper = Keeper::new(
         client,
         cfg.witch,
         cfg.flashloan,
+        ilk_flash_liquidators,
         cfg.multicall2,
         opts.multicall_batch_size,
-        opts.min_ratio,
-        opts.gas_boost,
-        gas_escalator,
+        strategy,
+        fee_strategy,
         opts.bump_gas_delay,
-        opts.target_collateral_offer,
+        CancelPolicy {
+            max_bumps: opts.max_bumps,
+            deadline: opts.cancel_after,
+        },
+        BreakerLimits {
+            max_gas_per_hour: opts.max_gas_per_hour.map(eth_to_wei),
+            max_gas_per_day: opts.max_gas_per_day.map(eth_to_wei),
+            max_failures: opts.max_failures,
+            failure_window: opts.failure_window,
+            max_consecutive_reverts: opts.max_consecutive_reverts,
+            cooldown: opts.breaker_cooldown,
+        },
         base_to_debt_threshold,
+        base_to_min_profit,
+        opts.prepare_ahead,
         state,
         swap_router,
+        opts.backfill_chunk_size,
+        journal,
+        relay,
+        ledger,
         instance_name
     ).await?;
 
-    if opts.one_shot {
-        keeper.one_shot().await?;
-        info!("One shot done");
-    } else {
-        keeper.run(opts.file, opts.start_block).await?;
-    }
+    Ok((keeper, store))
+}
+
+/// Parses a hex asset id, a key of the config's `map`
+fn parse_asset_id(map: &str, key: &str) -> anyhow::Result<[u8; 6]> {
+    hex::decode(key.trim_start_matches("0x"))
+        .ok()
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid asset id in {}: {:?}", map, key))
+}
 
-    Ok(())
+/// Converts an amount of ETH, with up to 9 decimals, to wei
+fn eth_to_wei(eth: f64) -> U256 {
+    U256::from((eth * 1e9) as u64) * U256::exp10(9)
 }
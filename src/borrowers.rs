@@ -3,6 +3,7 @@
 //! This module is responsible for keeping track of the users that have open
 //! positions and observing their debt healthiness.
 use crate::{
+    metrics,
     bindings::Cauldron, bindings::IMulticall2, bindings::IMulticall2Call, bindings::IlkIdType,
     bindings::SeriesIdType, bindings::VaultIdType, bindings::Witch, Result, cache::ImmutableCache,
 };
@@ -33,7 +34,7 @@ pub struct Borrowers<M> {
     instance_name: String,
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 /// A vault's details
 pub struct Vault {
     pub vault_id: VaultIdType,
@@ -85,16 +86,7 @@ impl<M: Middleware> Borrowers<M> {
 
         // get the new vaults
         // TODO: Improve this logic to be more optimized
-        let new_vaults = self
-            .cauldron
-            .vault_poured_filter()
-            .from_block(from_block)
-            .to_block(to_block)
-            .query()
-            .await?
-            .into_iter()
-            .map(|x| x.vault_id)
-            .collect::<Vec<_>>();
+        let new_vaults = self.new_vaults(from_block, to_block).await?;
 
         if new_vaults.len() > 0 {
             debug!("New vaults: {}", new_vaults.len());
@@ -136,9 +128,42 @@ impl<M: Middleware> Borrowers<M> {
                     );
                 }
             });
+
+        metrics::VAULTS.set(self.vaults.len() as i64);
+        metrics::UNDERCOLLATERALIZED_VAULTS.set(
+            self.vaults
+                .values()
+                .filter(|x| x.is_initialized && !x.is_collateralized)
+                .count() as i64,
+        );
         Ok(())
     }
 
+    /// Returns the ids of the vaults which were poured into in the given block range
+    pub async fn new_vaults(&self, from_block: U64, to_block: U64) -> Result<Vec<VaultIdType>, M> {
+        Ok(self
+            .cauldron
+            .vault_poured_filter()
+            .from_block(from_block)
+            .to_block(to_block)
+            .query()
+            .await?
+            .into_iter()
+            .map(|x| x.vault_id)
+            .collect::<Vec<_>>())
+    }
+
+    /// Starts tracking the given vaults. Their details are fetched on the next
+    /// call to `update_vaults`
+    pub fn add_vaults(&mut self, vault_ids: Vec<VaultIdType>) {
+        for vault_id in vault_ids {
+            self.vaults.entry(vault_id).or_insert_with(|| Vault {
+                vault_id,
+                ..Default::default()
+            });
+        }
+    }
+
     /// Fetches vault info for a set of vaults
     ///
     /// It relies on Multicall2 and does 2 levels of batching:
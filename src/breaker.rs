@@ -0,0 +1,370 @@
+//! Circuit breaker
+//!
+//! Stops the keeper from burning ETH on reverting bids, or on gas spikes. The
+//! gas our mined transactions cost and their outcomes are tracked over
+//! rolling windows; when a limit is breached, the keeper stops sending new
+//! transactions (it keeps monitoring, and keeps bumping the ones in flight)
+//! until the breaker is reset, either automatically after a cooldown or by
+//! the operator (`POST /breaker/resume` on the admin HTTP server). It's part
+//! of the persisted state, so that a restart doesn't reset it.
+//!
+//! All the timestamps are the local clock's (`unix_now`).
+use crate::bindings::VaultIdType;
+
+use ethers::prelude::*;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * HOUR;
+
+lazy_static! {
+    /// Lets the HTTP server see the breaker, and the operator reset it
+    pub static ref BREAKER: BreakerControl = BreakerControl::default();
+}
+
+/// The limits; `None` disables a limit
+#[derive(Clone, Debug, Default)]
+pub struct BreakerLimits {
+    /// Max gas cost (wei) of our transactions mined in the last hour
+    pub max_gas_per_hour: Option<U256>,
+    /// Max gas cost (wei) of our transactions mined in the last day
+    pub max_gas_per_day: Option<U256>,
+    /// Max number of our transactions which reverted in the last `failure_window` seconds
+    pub max_failures: Option<u32>,
+    pub failure_window: u64,
+    /// Max number of our transactions for the same vault which reverted in a row
+    pub max_consecutive_reverts: Option<u32>,
+    /// Resume automatically this many seconds after tripping, if no limit is
+    /// breached anymore. `None` to wait for the operator
+    pub cooldown: Option<u64>,
+}
+
+/// Why the breaker tripped
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trip {
+    GasPerHour,
+    GasPerDay,
+    Failures,
+    /// Too many reverts in a row for this vault (hex encoded)
+    VaultReverts(#[serde(with = "hex_vault_id")] VaultIdType),
+}
+
+impl fmt::Display for Trip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trip::GasPerHour => write!(f, "gas_per_hour"),
+            Trip::GasPerDay => write!(f, "gas_per_day"),
+            Trip::Failures => write!(f, "failures"),
+            Trip::VaultReverts(_) => write!(f, "vault_reverts"),
+        }
+    }
+}
+
+mod hex_vault_id {
+    use super::VaultIdType;
+    use serde::{de::Error, Deserialize};
+    use std::convert::TryInto;
+
+    pub fn serialize<S: serde::Serializer>(x: &VaultIdType, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(x))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<VaultIdType, D::Error> {
+        let x = String::deserialize(d)?;
+        hex::decode(&x)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| D::Error::custom(format!("invalid vault id {}", x)))
+    }
+}
+
+/// What the breaker looks like, as reported by the HTTP server
+#[derive(Clone, Debug, Serialize)]
+pub struct BreakerStatus {
+    pub paused: bool,
+    pub trip: Option<Trip>,
+    /// When the breaker tripped (unix timestamp, seconds)
+    pub tripped_at: Option<u64>,
+    pub gas_last_hour: String,
+    pub gas_last_day: String,
+    pub failures_in_window: usize,
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    /// Set from the CLI on every start, so they're not persisted
+    #[serde(skip)]
+    limits: BreakerLimits,
+    /// (mined at, gas cost) of our transactions of the last day
+    spent: VecDeque<(u64, U256)>,
+    /// When our reverted transactions of the last `failure_window` were mined
+    failures: VecDeque<u64>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    consecutive_reverts: HashMap<VaultIdType, u32>,
+    tripped: Option<(Trip, u64)>,
+}
+
+impl CircuitBreaker {
+    /// Constructor
+    pub fn new(limits: BreakerLimits) -> Self {
+        CircuitBreaker {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// The persisted breaker, with the current limits
+    pub fn with_limits(self, limits: BreakerLimits) -> Self {
+        CircuitBreaker { limits, ..self }
+    }
+
+    /// Records one of our transactions for `vault_id`, mined at `now`.
+    /// Returns the reason, if it trips the breaker
+    pub fn record(&mut self, now: u64, vault_id: VaultIdType, gas_cost: U256, success: bool) -> Option<Trip> {
+        self.spent.push_back((now, gas_cost));
+        if success {
+            self.consecutive_reverts.remove(&vault_id);
+        } else {
+            self.failures.push_back(now);
+            *self.consecutive_reverts.entry(vault_id).or_default() += 1;
+        }
+        self.check(now, Some(vault_id))
+    }
+
+    /// Records the gas cost of one of our transactions which isn't a bid nor
+    /// an auction start (i.e. a cancellation), mined at `now`
+    pub fn spend(&mut self, now: u64, gas_cost: U256) -> Option<Trip> {
+        self.spent.push_back((now, gas_cost));
+        self.check(now, None)
+    }
+
+    /// Whether new transactions must not be sent. Resumes automatically once
+    /// the cooldown is over, unless a limit is still breached
+    pub fn is_paused(&mut self, now: u64) -> bool {
+        let (trip, tripped_at) = match self.tripped {
+            Some(x) => x,
+            None => return false,
+        };
+        match self.limits.cooldown {
+            Some(cooldown) if now >= tripped_at + cooldown && self.breach(now).is_none() => {
+                if let Trip::VaultReverts(vault_id) = trip {
+                    self.consecutive_reverts.remove(&vault_id);
+                }
+                self.tripped = None;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Resumes on the operator's command: the rolling windows are kept, but
+    /// the failure and revert counts start over
+    pub fn resume(&mut self) {
+        self.failures.clear();
+        self.consecutive_reverts.clear();
+        self.tripped = None;
+    }
+
+    pub fn status(&mut self, now: u64) -> BreakerStatus {
+        self.expire(now);
+        BreakerStatus {
+            paused: self.tripped.is_some(),
+            trip: self.tripped.map(|x| x.0),
+            tripped_at: self.tripped.map(|x| x.1),
+            gas_last_hour: self.spent_within(now, HOUR).to_string(),
+            gas_last_day: self.spent_within(now, DAY).to_string(),
+            failures_in_window: self.failures.len(),
+        }
+    }
+
+    /// Trips the breaker if a limit is breached, unless it's tripped already
+    fn check(&mut self, now: u64, vault_id: Option<VaultIdType>) -> Option<Trip> {
+        if self.tripped.is_some() {
+            return None;
+        }
+        let reverts = vault_id.and_then(|x| self.consecutive_reverts.get(&x).copied()).unwrap_or_default();
+        let trip = match (self.limits.max_consecutive_reverts, vault_id) {
+            (Some(max), Some(vault_id)) if reverts >= max => Trip::VaultReverts(vault_id),
+            _ => self.breach(now)?,
+        };
+        self.tripped = Some((trip, now));
+        Some(trip)
+    }
+
+    /// The rolling limit which is breached as of `now`, if any
+    fn breach(&mut self, now: u64) -> Option<Trip> {
+        self.expire(now);
+        if matches!(self.limits.max_gas_per_hour, Some(max) if self.spent_within(now, HOUR) >= max) {
+            return Some(Trip::GasPerHour);
+        }
+        if matches!(self.limits.max_gas_per_day, Some(max) if self.spent_within(now, DAY) >= max) {
+            return Some(Trip::GasPerDay);
+        }
+        if matches!(self.limits.max_failures, Some(max) if self.failures.len() >= max as usize) {
+            return Some(Trip::Failures);
+        }
+        None
+    }
+
+    fn expire(&mut self, now: u64) {
+        while matches!(self.spent.front(), Some((at, _)) if *at + DAY <= now) {
+            self.spent.pop_front();
+        }
+        while matches!(self.failures.front(), Some(at) if *at + self.limits.failure_window <= now) {
+            self.failures.pop_front();
+        }
+    }
+
+    /// Gas cost of our transactions mined in the last `window` seconds
+    fn spent_within(&self, now: u64, window: u64) -> U256 {
+        self.spent
+            .iter()
+            .filter(|(at, _)| at + window > now)
+            .fold(U256::zero(), |acc, (_, x)| acc + x)
+    }
+}
+
+/// Shared between the keeper, which owns the breaker, and the HTTP server
+#[derive(Debug, Default)]
+pub struct BreakerControl {
+    resume_requested: AtomicBool,
+    status: Mutex<Option<BreakerStatus>>,
+}
+
+impl BreakerControl {
+    /// Asks the keeper to resume
+    pub fn request_resume(&self) {
+        self.resume_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the operator asked to resume since the last call
+    pub fn take_resume_request(&self) -> bool {
+        self.resume_requested.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn set_status(&self, status: BreakerStatus) {
+        *self.status.lock().unwrap() = Some(status);
+    }
+
+    /// The last status reported by the keeper
+    pub fn status(&self) -> Option<BreakerStatus> {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> BreakerLimits {
+        BreakerLimits {
+            max_gas_per_hour: Some(100.into()),
+            max_gas_per_day: Some(150.into()),
+            max_failures: Some(3),
+            failure_window: 600,
+            max_consecutive_reverts: Some(2),
+            cooldown: Some(60),
+        }
+    }
+
+    #[test]
+    fn trips_on_gas_per_hour_and_resumes_after_the_window() {
+        let mut breaker = CircuitBreaker::new(limits());
+        assert_eq!(breaker.record(0, [1; 12], 60.into(), true), None);
+        assert_eq!(breaker.record(10, [2; 12], 40.into(), true), Some(Trip::GasPerHour));
+        assert!(breaker.is_paused(100));
+        // the cooldown is over, but the spending is still within the hour
+        assert!(breaker.is_paused(1000));
+        assert!(!breaker.is_paused(HOUR + 1));
+    }
+
+    #[test]
+    fn counts_cancellations_as_spending_only() {
+        let mut breaker = CircuitBreaker::new(limits());
+        breaker.record(0, [1; 12], 1.into(), false);
+        assert_eq!(breaker.spend(1, 50.into()), None);
+        // the cancellation didn't reset the vault's reverts
+        assert_eq!(breaker.record(2, [1; 12], 1.into(), false), Some(Trip::VaultReverts([1; 12])));
+        assert_eq!(breaker.status(2).gas_last_hour, "52");
+    }
+
+    #[test]
+    fn trips_on_gas_per_day() {
+        let mut breaker = CircuitBreaker::new(limits());
+        breaker.record(0, [1; 12], 90.into(), true);
+        assert_eq!(breaker.record(2 * HOUR, [1; 12], 90.into(), true), Some(Trip::GasPerDay));
+        assert!(breaker.is_paused(3 * HOUR));
+        assert!(!breaker.is_paused(DAY + 1));
+    }
+
+    #[test]
+    fn trips_on_failures() {
+        let mut limits = limits();
+        limits.max_consecutive_reverts = None;
+        let mut breaker = CircuitBreaker::new(limits);
+        assert_eq!(breaker.record(0, [1; 12], 1.into(), false), None);
+        assert_eq!(breaker.record(1, [2; 12], 1.into(), false), None);
+        assert_eq!(breaker.record(2, [3; 12], 1.into(), false), Some(Trip::Failures));
+        assert_eq!(breaker.status(2).failures_in_window, 3);
+        assert!(breaker.is_paused(100));
+        assert!(!breaker.is_paused(700));
+    }
+
+    #[test]
+    fn trips_on_consecutive_reverts_per_vault() {
+        let mut breaker = CircuitBreaker::new(limits());
+        assert_eq!(breaker.record(0, [1; 12], 1.into(), false), None);
+        // a success resets the count
+        assert_eq!(breaker.record(1, [1; 12], 1.into(), true), None);
+        assert_eq!(breaker.record(2, [1; 12], 1.into(), false), None);
+        assert_eq!(breaker.record(3, [1; 12], 1.into(), false), Some(Trip::VaultReverts([1; 12])));
+        assert!(breaker.status(3).paused);
+    }
+
+    #[test]
+    fn resumes_on_command_only_without_cooldown() {
+        let mut limits = limits();
+        limits.cooldown = None;
+        let mut breaker = CircuitBreaker::new(limits);
+        breaker.record(0, [1; 12], 1.into(), false);
+        breaker.record(1, [1; 12], 1.into(), false);
+        assert!(breaker.is_paused(DAY * 10));
+        breaker.resume();
+        assert!(!breaker.is_paused(DAY * 10));
+        assert!(!breaker.status(DAY * 10).paused);
+    }
+
+    #[test]
+    fn stays_tripped_across_restarts() {
+        let mut breaker = CircuitBreaker::new(limits());
+        breaker.record(0, [1; 12], 1.into(), false);
+        breaker.record(1, [1; 12], 1.into(), false);
+        let saved = serde_json::to_string(&breaker).unwrap();
+
+        let mut restored = serde_json::from_str::<CircuitBreaker>(&saved).unwrap().with_limits(limits());
+        assert!(restored.is_paused(2));
+        assert_eq!(restored.status(2).trip, Some(Trip::VaultReverts([1; 12])));
+        assert!(!restored.is_paused(100));
+    }
+
+    #[test]
+    fn shares_resume_requests() {
+        let control = BreakerControl::default();
+        assert!(!control.take_resume_request());
+        control.request_resume();
+        assert!(control.take_resume_request());
+        assert!(!control.take_resume_request());
+    }
+}
@@ -0,0 +1,117 @@
+//! Stuck transaction cancellation
+//!
+//! A transaction which can't be bumped any further still occupies its nonce,
+//! and every later transaction from the account waits behind it. Such a
+//! transaction is replaced with a zero-value transfer to ourselves at the same
+//! nonce: it's cheap, and it can't revert.
+use crate::fees::Fees;
+
+use ethers::prelude::*;
+use ethers_core::types::transaction::eip2718::TypedTransaction;
+use std::fmt;
+
+/// Gas limit of a plain transfer
+pub const CANCELLATION_GAS: u64 = 21_000;
+
+/// Why a transaction was cancelled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CancelReason {
+    /// It was bumped `max_bumps` times
+    MaxBumps,
+    /// It was still pending `deadline` seconds after being submitted
+    Deadline,
+    /// Its fees reached `max_price`: it can't be replaced by a bump anymore
+    MaxPrice,
+    /// The bumped transaction was rejected by the node
+    RebroadcastFailed,
+    /// It was a bundle which won't be sent anymore, and later transactions
+    /// of ours wait for its nonce
+    BundleDropped,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CancelReason::MaxBumps => write!(f, "max_bumps"),
+            CancelReason::Deadline => write!(f, "deadline"),
+            CancelReason::MaxPrice => write!(f, "max_price"),
+            CancelReason::RebroadcastFailed => write!(f, "rebroadcast_failed"),
+            CancelReason::BundleDropped => write!(f, "bundle_dropped"),
+        }
+    }
+}
+
+/// When to give up on bumping a transaction
+#[derive(Clone, Debug)]
+pub struct CancelPolicy {
+    /// Cancel transactions which were bumped this many times
+    pub max_bumps: u32,
+    /// Cancel transactions still pending this many seconds after they were
+    /// first submitted
+    pub deadline: u64,
+}
+
+impl CancelPolicy {
+    /// Whether a transaction bumped `bumps` times, and pending for
+    /// `pending_for` seconds, has to be cancelled
+    pub fn reason(&self, bumps: u32, pending_for: u64) -> Option<CancelReason> {
+        if bumps >= self.max_bumps {
+            Some(CancelReason::MaxBumps)
+        } else if pending_for >= self.deadline {
+            Some(CancelReason::Deadline)
+        } else {
+            None
+        }
+    }
+}
+
+/// A zero-value transfer from `sender` to itself, using `nonce`
+pub fn cancellation(sender: Address, nonce: U256, fees: &Fees) -> TypedTransaction {
+    let mut tx: TypedTransaction = TransactionRequest::new()
+        .from(sender)
+        .to(sender)
+        .value(0)
+        .gas(CANCELLATION_GAS)
+        .nonce(nonce)
+        .into();
+    fees.apply(&mut tx);
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::TxFormat;
+
+    #[test]
+    fn cancels_after_max_bumps_or_deadline() {
+        let policy = CancelPolicy { max_bumps: 3, deadline: 600 };
+        assert_eq!(policy.reason(0, 0), None);
+        assert_eq!(policy.reason(2, 599), None);
+        assert_eq!(policy.reason(3, 10), Some(CancelReason::MaxBumps));
+        assert_eq!(policy.reason(1, 600), Some(CancelReason::Deadline));
+    }
+
+    #[test]
+    fn builds_self_transfers() {
+        let sender = Address::repeat_byte(7);
+        let fees = Fees {
+            format: TxFormat::Eip1559,
+            base_fee: 10.into(),
+            max_fee_per_gas: 30.into(),
+            max_priority_fee_per_gas: 5.into(),
+        };
+        let tx = cancellation(sender, 12.into(), &fees);
+        assert_eq!(tx.nonce(), Some(&12.into()));
+        assert_eq!(tx.to(), Some(&sender.into()));
+        assert_eq!(Fees::of(&tx, 10.into()), fees);
+        match tx {
+            TypedTransaction::Eip1559(x) => {
+                assert_eq!(x.from, Some(sender));
+                assert_eq!(x.value, Some(U256::zero()));
+                assert_eq!(x.gas, Some(CANCELLATION_GAS.into()));
+            }
+            x => panic!("unexpected transaction {:?}", x),
+        }
+    }
+}
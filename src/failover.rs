@@ -0,0 +1,580 @@
+//! Multi-endpoint RPC client
+//!
+//! A `JsonRpcClient` which spreads requests over several HTTP endpoints: it
+//! prefers the endpoint with the highest block, fails over to the next one on
+//! errors, and can require several endpoints to agree on the reads that
+//! trigger our transactions (`Cauldron.level`, `Witch.auctions`,
+//! `collateralToDebtRatio`), made directly or through a multicall. Those are
+//! pinned to a block all the voting endpoints have, so that lagging endpoints
+//! don't disagree with the others. Filters only exist on the endpoint which
+//! created them, so they're always polled there. When every endpoint fails,
+//! the RPC is reported unreachable to the health checks.
+use crate::health::HEALTH;
+
+use async_trait::async_trait;
+use ethers::{
+    abi::{ParamType, Token},
+    prelude::*,
+    utils::id,
+};
+use futures_util::future::join_all;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+#[derive(Error, Debug)]
+pub enum FailoverError {
+    #[error("invalid endpoint url {0}: {1}")]
+    InvalidUrl(String, String),
+    #[error("all endpoints failed; last error: {0}")]
+    AllFailed(String),
+    #[error("no quorum for {method}: at most {agreeing} of the required {required} endpoints agree")]
+    NoQuorum {
+        method: String,
+        agreeing: usize,
+        required: usize,
+    },
+    #[error("rpc error: {0}")]
+    Rpc(String),
+    #[error("the endpoint of filter {0} failed: {1}")]
+    FilterLost(String, String),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Why an endpoint didn't answer with a result
+#[derive(Clone, Debug, PartialEq)]
+enum EndpointError {
+    /// The node answered with an error, e.g. a revert
+    Rpc(String),
+    /// The node couldn't be reached, or answered garbage
+    Transport(String),
+}
+
+impl EndpointError {
+    fn into_message(self) -> String {
+        match self {
+            EndpointError::Rpc(x) | EndpointError::Transport(x) => x,
+        }
+    }
+}
+
+/// Whether the `eth_call` params are one of the reads our transactions are
+/// triggered by, or a multicall containing one
+fn is_trigger_read(params: &Value) -> bool {
+    let data = params
+        .get(0)
+        .and_then(|x| x.get("data").or_else(|| x.get("input")))
+        .and_then(|x| x.as_str())
+        .and_then(|x| hex::decode(x.trim_start_matches("0x")).ok());
+    match data {
+        Some(data) => is_trigger_calldata(&data),
+        None => false,
+    }
+}
+
+fn is_trigger_calldata(data: &[u8]) -> bool {
+    if data.len() < 4 {
+        return false;
+    }
+    let selector = &data[..4];
+    let triggers = [
+        id("level(bytes12)"),
+        id("auctions(bytes12)"),
+        id("collateralToDebtRatio(bytes12)"),
+    ];
+    if triggers.iter().any(|x| x == selector) {
+        return true;
+    }
+    // the multicall entry points, and the params before their calls
+    let calls = ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::Address, ParamType::Bytes])));
+    let params = if selector == id("aggregate((address,bytes)[])") {
+        vec![calls]
+    } else if selector == id("tryAggregate(bool,(address,bytes)[])") {
+        vec![ParamType::Bool, calls]
+    } else {
+        return false;
+    };
+    let tokens = match ethers::abi::decode(&params, &data[4..]) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    match tokens.last() {
+        Some(Token::Array(calls)) => calls.iter().any(|call| match call {
+            Token::Tuple(x) => matches!(x.get(1), Some(Token::Bytes(x)) if is_trigger_calldata(x)),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+impl From<FailoverError> for ProviderError {
+    fn from(src: FailoverError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(src))
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    client: Http,
+    /// The latest block this endpoint reported
+    block: AtomicU64,
+    /// Consecutive failed requests
+    failures: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct FailoverClient {
+    endpoints: Vec<Endpoint>,
+    /// How many endpoints have to return the same `eth_call` result
+    quorum: usize,
+    /// Endpoints more than this many blocks behind the best one are only
+    /// used if all the others fail
+    max_lag: u64,
+    /// The url of the endpoint which created each filter, by filter id
+    filters: Mutex<HashMap<String, String>>,
+}
+
+impl FailoverClient {
+    /// Constructor
+    pub fn new(urls: &[String], quorum: usize, max_lag: u64) -> Result<Self, FailoverError> {
+        let endpoints = urls
+            .iter()
+            .map(|url| {
+                Ok(Endpoint {
+                    url: url.clone(),
+                    client: Http::from_str(url)
+                        .map_err(|e| FailoverError::InvalidUrl(url.clone(), e.to_string()))?,
+                    block: AtomicU64::new(0),
+                    failures: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<Vec<_>, FailoverError>>()?;
+        Ok(FailoverClient {
+            quorum: std::cmp::min(std::cmp::max(quorum, 1), endpoints.len()),
+            endpoints,
+            max_lag,
+            filters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The endpoints, best first: up to date endpoints before lagging ones,
+    /// then the ones with the fewest recent failures and the highest block
+    fn ranked(&self) -> Vec<&Endpoint> {
+        let best_block = self
+            .endpoints
+            .iter()
+            .map(|x| x.block.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or_default();
+        let mut endpoints = self.endpoints.iter().collect::<Vec<_>>();
+        endpoints.sort_by_key(|x| {
+            let block = x.block.load(Ordering::Relaxed);
+            (
+                block + self.max_lag < best_block,
+                x.failures.load(Ordering::Relaxed),
+                std::cmp::Reverse(block),
+            )
+        });
+        endpoints
+    }
+
+    async fn request_endpoint(&self, endpoint: &Endpoint, method: &str, params: &Value) -> Result<Value, EndpointError> {
+        match endpoint.client.request::<_, Value>(method, params.clone()).await {
+            Ok(x) => {
+                endpoint.failures.store(0, Ordering::Relaxed);
+                Ok(x)
+            }
+            // the node is fine, the request isn't
+            Err(HttpClientError::JsonRpcError(x)) => {
+                endpoint.failures.store(0, Ordering::Relaxed);
+                debug!(url = endpoint.url.as_str(), method, err = ?x, "RPC error");
+                Err(EndpointError::Rpc(x.message))
+            }
+            Err(x) => {
+                endpoint.failures.fetch_add(1, Ordering::Relaxed);
+                warn!(url = endpoint.url.as_str(), method, err = ?x, "RPC endpoint failed");
+                Err(EndpointError::Transport(x.to_string()))
+            }
+        }
+    }
+
+    /// Sends the request to the best endpoint, failing over to the next ones.
+    /// Returns the endpoint which answered with the result
+    async fn failover_request(&self, method: &str, params: &Value) -> Result<(&Endpoint, Value), FailoverError> {
+        let mut last_error = String::from("no endpoints");
+        for endpoint in self.ranked() {
+            match self.request_endpoint(endpoint, method, params).await {
+                Ok(x) => return Ok((endpoint, x)),
+                Err(x) => last_error = x.into_message(),
+            }
+        }
+        Err(FailoverError::AllFailed(last_error))
+    }
+
+    /// Creates a filter, and remembers where
+    async fn new_filter(&self, method: &str, params: &Value) -> Result<Value, FailoverError> {
+        let (endpoint, id) = self.failover_request(method, params).await?;
+        if let Some(x) = id.as_str() {
+            self.filters.lock().unwrap().insert(x.to_string(), endpoint.url.clone());
+        }
+        Ok(id)
+    }
+
+    /// Sends a request about a filter to the endpoint which created it. If it's
+    /// down, the filter is lost: the caller has to create another one
+    async fn filter_request(&self, method: &str, params: &Value) -> Result<Value, FailoverError> {
+        let filter_id = params.get(0).and_then(|x| x.as_str()).unwrap_or_default().to_string();
+        let url = self.filters.lock().unwrap().get(&filter_id).cloned();
+        let endpoint = match url.and_then(|url| self.endpoints.iter().find(|x| x.url == url)) {
+            Some(x) => x,
+            // not one of ours, but a node may know it
+            None => return Ok(self.failover_request(method, params).await?.1),
+        };
+        if method == "eth_uninstallFilter" {
+            self.filters.lock().unwrap().remove(&filter_id);
+        }
+        match self.request_endpoint(endpoint, method, params).await {
+            Ok(x) => Ok(x),
+            Err(EndpointError::Rpc(x)) => Err(FailoverError::Rpc(x)),
+            Err(EndpointError::Transport(x)) => {
+                self.filters.lock().unwrap().remove(&filter_id);
+                Err(FailoverError::FilterLost(filter_id, x))
+            }
+        }
+    }
+
+    /// Asks every endpoint for its block number and returns the highest one
+    async fn block_number(&self, params: &Value) -> Result<Value, FailoverError> {
+        let responses = join_all(
+            self.endpoints
+                .iter()
+                .map(|x| self.request_endpoint(x, "eth_blockNumber", params)),
+        )
+        .await;
+
+        let mut best: Option<U64> = None;
+        let mut last_error = String::from("no endpoints");
+        for (endpoint, response) in self.endpoints.iter().zip(responses) {
+            match response
+                .map_err(EndpointError::into_message)
+                .and_then(|x| serde_json::from_value::<U64>(x).map_err(|e| e.to_string()))
+            {
+                Ok(block) => {
+                    endpoint.block.store(block.as_u64(), Ordering::Relaxed);
+                    best = std::cmp::max(best, Some(block));
+                }
+                Err(x) => last_error = x,
+            }
+        }
+        match best {
+            Some(x) => {
+                debug!(block = x.as_u64(), "Best block");
+                Ok(serde_json::to_value(x)?)
+            }
+            None => Err(FailoverError::AllFailed(last_error)),
+        }
+    }
+
+    /// Sends the request to the up to date endpoints and returns the result
+    /// (or the error, e.g. a revert) at least `quorum` of them agree on.
+    ///
+    /// Requests for the latest block are pinned to the highest block at least
+    /// `quorum` endpoints have reached, and only sent to those endpoints
+    async fn quorum_request(&self, method: &str, params: &Value) -> Result<Value, FailoverError> {
+        let mut blocks = self
+            .endpoints
+            .iter()
+            .map(|x| x.block.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        blocks.sort_unstable_by(|a, b| b.cmp(a));
+        let pinned_block = blocks.get(self.quorum - 1).cloned().unwrap_or_default();
+
+        let mut params = params.clone();
+        let latest = matches!(params.get(1).and_then(|x| x.as_str()), None | Some("latest"));
+        let endpoints = if latest && pinned_block > 0 {
+            if let Some(x) = params.as_array_mut() {
+                x.truncate(1);
+                x.push(serde_json::to_value(U64::from(pinned_block))?);
+            }
+            self.ranked()
+                .into_iter()
+                .filter(|x| x.block.load(Ordering::Relaxed) >= pinned_block)
+                .collect::<Vec<_>>()
+        } else {
+            let best_block = blocks.first().cloned().unwrap_or_default();
+            self.ranked()
+                .into_iter()
+                .filter(|x| x.block.load(Ordering::Relaxed) + self.max_lag >= best_block)
+                .collect::<Vec<_>>()
+        };
+        let responses = join_all(endpoints.iter().map(|x| self.request_endpoint(x, method, &params))).await;
+
+        // unreachable endpoints don't vote
+        let mut votes: Vec<(Result<Value, EndpointError>, usize)> = vec![];
+        for response in responses {
+            if matches!(response, Err(EndpointError::Transport(_))) {
+                continue;
+            }
+            match votes.iter_mut().find(|(value, _)| *value == response) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((response, 1)),
+            }
+        }
+        let agreeing = votes.iter().map(|x| x.1).max().unwrap_or_default();
+        match votes.into_iter().find(|(_, count)| *count >= self.quorum) {
+            Some((Ok(value), _)) => Ok(value),
+            Some((Err(x), _)) => Err(FailoverError::Rpc(x.into_message())),
+            None => Err(FailoverError::NoQuorum {
+                method: method.to_string(),
+                agreeing,
+                required: self.quorum,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for FailoverClient {
+    type Error = FailoverError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, FailoverError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(params)?;
+        let value = match method {
+            "eth_blockNumber" => self.block_number(&params).await,
+            "eth_call" if self.quorum > 1 && is_trigger_read(&params) => self.quorum_request(method, &params).await,
+            "eth_newFilter" | "eth_newBlockFilter" | "eth_newPendingTransactionFilter" => {
+                self.new_filter(method, &params).await
+            }
+            "eth_getFilterChanges" | "eth_getFilterLogs" | "eth_uninstallFilter" => {
+                self.filter_request(method, &params).await
+            }
+            _ => self.failover_request(method, &params).await.map(|x| x.1),
+        };
+        if let Err(FailoverError::AllFailed(_)) = &value {
+            HEALTH.set_rpc_reachable(false);
+        }
+        Ok(serde_json::from_value(value?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use serde_json::json;
+    use std::{convert::Infallible, sync::Arc};
+
+    /// An endpoint at `block`. `eth_call` at block `n` answers `n + offset`,
+    /// or reverts with `revert`
+    async fn mock_endpoint(block: u64, offset: u64, revert: Option<&'static str>) -> String {
+        let make_service = make_service_fn(move |_conn| async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                let id = request["id"].clone();
+                let response = match (request["method"].as_str().unwrap(), revert) {
+                    ("eth_blockNumber", _) => json!({"jsonrpc": "2.0", "id": id, "result": format!("{:#x}", block)}),
+                    (_, Some(message)) => {
+                        json!({"jsonrpc": "2.0", "id": id, "error": {"code": 3, "message": message}})
+                    }
+                    _ => {
+                        let at = match request["params"][1].as_str() {
+                            Some("latest") | None => block,
+                            Some(x) => u64::from_str_radix(x.trim_start_matches("0x"), 16).unwrap(),
+                        };
+                        json!({"jsonrpc": "2.0", "id": id, "result": format!("{:#066x}", at + offset)})
+                    }
+                };
+                Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    async fn client(endpoints: Vec<(u64, u64, Option<&'static str>)>, quorum: usize) -> FailoverClient {
+        let mut urls = vec![];
+        for (block, offset, revert) in endpoints {
+            urls.push(mock_endpoint(block, offset, revert).await);
+        }
+        let client = FailoverClient::new(&urls, quorum, 3).unwrap();
+        client.request::<_, U64>("eth_blockNumber", ()).await.unwrap();
+        client
+    }
+
+    /// `Cauldron.level` of a vault
+    fn level_call() -> Value {
+        let mut data = id("level(bytes12)").to_vec();
+        data.extend_from_slice(&[0u8; 32]);
+        json!([{"to": format!("{:?}", Address::zero()), "data": format!("0x{}", hex::encode(data))}, "latest"])
+    }
+
+    #[tokio::test]
+    async fn quorum_reads_need_agreeing_endpoints() {
+        let client = client(vec![(100, 0, None), (100, 0, None), (100, 1, None)], 2).await;
+        let level: U256 = client.request("eth_call", level_call()).await.unwrap();
+        assert_eq!(level, 100.into());
+
+        let client = client(vec![(100, 0, None), (100, 1, None), (100, 2, None)], 2).await;
+        let err = client.request::<_, U256>("eth_call", level_call()).await.unwrap_err();
+        assert!(matches!(err, FailoverError::NoQuorum { agreeing: 1, required: 2, .. }));
+
+        // other calls don't need a quorum
+        let call = json!([{"to": format!("{:?}", Address::zero()), "data": "0x12345678"}, "latest"]);
+        assert!(client.request::<_, U256>("eth_call", call).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn quorum_reads_are_pinned_to_a_block() {
+        // unpinned, the lagging endpoint would disagree with the others
+        let client = client(vec![(100, 0, None), (99, 0, None), (98, 0, None)], 2).await;
+        let level: U256 = client.request("eth_call", level_call()).await.unwrap();
+        assert_eq!(level, 99.into());
+    }
+
+    #[tokio::test]
+    async fn quorum_reads_can_revert() {
+        let client = client(vec![(100, 0, Some("execution reverted")), (100, 0, Some("execution reverted"))], 2).await;
+        let err = client.request::<_, U256>("eth_call", level_call()).await.unwrap_err();
+        assert!(matches!(err, FailoverError::Rpc(x) if x == "execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn reports_the_rpc_unreachable_when_all_endpoints_fail() {
+        // nothing listens on port 1
+        let client = FailoverClient::new(&["http://127.0.0.1:1".to_string()], 1, 3).unwrap();
+        let err = client.request::<_, U64>("eth_getBalance", ()).await.unwrap_err();
+        assert!(matches!(err, FailoverError::AllFailed(_)));
+        assert!(!HEALTH.report(0, 0).rpc_reachable);
+    }
+
+    /// An endpoint at `block`, which knows only the filter `filter_id`
+    async fn filter_endpoint(block: Arc<AtomicU64>, filter_id: &'static str) -> String {
+        let make_service = make_service_fn(move |_conn| {
+            let block = block.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let block = block.load(Ordering::Relaxed);
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        let id = request["id"].clone();
+                        let response = match request["method"].as_str().unwrap() {
+                            "eth_blockNumber" => json!({"jsonrpc": "2.0", "id": id, "result": format!("{:#x}", block)}),
+                            "eth_newFilter" => json!({"jsonrpc": "2.0", "id": id, "result": filter_id}),
+                            "eth_uninstallFilter" if request["params"][0] == filter_id => {
+                                json!({"jsonrpc": "2.0", "id": id, "result": true})
+                            }
+                            _ if request["params"][0] == filter_id => json!({"jsonrpc": "2.0", "id": id, "result": []}),
+                            _ => json!({"jsonrpc": "2.0", "id": id, "error": {"code": -32000, "message": "filter not found"}}),
+                        };
+                        Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn polls_filters_where_they_were_created() {
+        let first_block = Arc::new(AtomicU64::new(100));
+        let second_block = Arc::new(AtomicU64::new(99));
+        let urls = vec![
+            filter_endpoint(first_block.clone(), "0x1").await,
+            filter_endpoint(second_block.clone(), "0x2").await,
+        ];
+        let client = FailoverClient::new(&urls, 1, 3).unwrap();
+        client.request::<_, U64>("eth_blockNumber", ()).await.unwrap();
+        let filter_id: String = client.request("eth_newFilter", json!([{}])).await.unwrap();
+        assert_eq!(filter_id, "0x1");
+
+        // the second endpoint is now the best one
+        second_block.store(110, Ordering::Relaxed);
+        client.request::<_, U64>("eth_blockNumber", ()).await.unwrap();
+        let changes: Vec<Value> = client.request("eth_getFilterChanges", json!([filter_id])).await.unwrap();
+        assert!(changes.is_empty());
+        assert!(client.request::<_, bool>("eth_uninstallFilter", json!([filter_id])).await.unwrap());
+        assert!(client.filters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_trigger_reads_in_multicalls() {
+        let mut level = id("level(bytes12)").to_vec();
+        level.extend_from_slice(&[0u8; 32]);
+        let multicall = |selector: &str, calls: Vec<Vec<u8>>| {
+            let calls = Token::Array(
+                calls
+                    .into_iter()
+                    .map(|x| Token::Tuple(vec![Token::Address(Address::zero()), Token::Bytes(x)]))
+                    .collect(),
+            );
+            let mut data = id(selector).to_vec();
+            if selector.starts_with("tryAggregate") {
+                data.extend(ethers::abi::encode(&[Token::Bool(false), calls]));
+            } else {
+                data.extend(ethers::abi::encode(&[calls]));
+            }
+            data
+        };
+        assert!(is_trigger_calldata(&level));
+        assert!(is_trigger_calldata(&multicall("aggregate((address,bytes)[])", vec![vec![1, 2, 3, 4], level.clone()])));
+        assert!(is_trigger_calldata(&multicall("tryAggregate(bool,(address,bytes)[])", vec![level])));
+        assert!(!is_trigger_calldata(&multicall("aggregate((address,bytes)[])", vec![vec![1, 2, 3, 4]])));
+        assert!(!is_trigger_calldata(&[1, 2]));
+    }
+
+    #[test]
+    fn ranks_up_to_date_endpoints_first() {
+        let client = FailoverClient::new(
+            &[
+                "http://a:8545".to_string(),
+                "http://b:8545".to_string(),
+                "http://c:8545".to_string(),
+            ],
+            2,
+            3,
+        )
+        .unwrap();
+        client.endpoints[0].block.store(90, Ordering::Relaxed);
+        client.endpoints[1].block.store(100, Ordering::Relaxed);
+        client.endpoints[2].block.store(99, Ordering::Relaxed);
+        let ranked = client.ranked().iter().map(|x| x.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(ranked, vec!["http://b:8545", "http://c:8545", "http://a:8545"]);
+
+        // failing endpoints go after the healthy ones
+        client.endpoints[1].failures.store(2, Ordering::Relaxed);
+        let ranked = client.ranked().iter().map(|x| x.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(ranked, vec!["http://c:8545", "http://b:8545", "http://a:8545"]);
+    }
+
+    #[test]
+    fn quorum_is_capped_by_endpoint_count() {
+        let client = FailoverClient::new(&["http://a:8545".to_string()], 3, 3).unwrap();
+        assert_eq!(client.quorum, 1);
+    }
+}
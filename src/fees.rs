@@ -0,0 +1,400 @@
+//! EIP-1559 fees
+//!
+//! `max_fee_per_gas` is a multiple of the base fee plus the tip, so that the
+//! transaction survives a few blocks of rising base fees, and the tip follows
+//! what recent blocks paid (`eth_feeHistory` reward percentiles). Bumping a
+//! stuck transaction raises the tip geometrically.
+//!
+//! Chains without EIP-1559 get legacy (or EIP-2930) transactions priced with
+//! `eth_gasPrice` instead, and bumping raises their gas price the same way.
+use crate::escalator::GeometricGasPrice;
+
+use ethers::prelude::*;
+use ethers_core::types::transaction::{
+    eip1559::Eip1559TransactionRequest,
+    eip2718::TypedTransaction,
+    eip2930::{AccessList, Eip2930TransactionRequest},
+};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("unknown transaction format: {0} (expected 'legacy', 'eip2930' or 'eip1559')")]
+pub struct UnknownTxFormat(String);
+
+/// The kind of transaction we send
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxFormat {
+    /// A single gas price
+    Legacy,
+    /// A single gas price, and an access list
+    Eip2930,
+    /// A max fee and a tip, on top of the block's base fee
+    Eip1559,
+}
+
+impl Default for TxFormat {
+    fn default() -> Self {
+        TxFormat::Eip1559
+    }
+}
+
+impl FromStr for TxFormat {
+    type Err = UnknownTxFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(TxFormat::Legacy),
+            "eip2930" => Ok(TxFormat::Eip2930),
+            "eip1559" => Ok(TxFormat::Eip1559),
+            x => Err(UnknownTxFormat(x.to_string())),
+        }
+    }
+}
+
+/// Nodes only replace a pending transaction if both its max fee and its tip
+/// go up by at least this much (percent)
+pub const MIN_REPLACEMENT_INCREASE_PCT: u64 = 10;
+
+/// The fees of a transaction, and the base fee they were computed for.
+///
+/// Legacy and EIP-2930 transactions have no base fee, and their gas price is
+/// both the max fee and the tip
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fees {
+    pub format: TxFormat,
+    pub base_fee: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+impl Fees {
+    /// What we expect to pay per gas if the base fee doesn't move
+    pub fn expected_gas_price(&self) -> U256 {
+        std::cmp::min(self.max_fee_per_gas, self.base_fee + self.max_priority_fee_per_gas)
+    }
+
+    /// The fees `tx` was sent with
+    pub fn of(tx: &TypedTransaction, base_fee: U256) -> Fees {
+        match tx {
+            TypedTransaction::Eip1559(x) => Fees {
+                format: TxFormat::Eip1559,
+                base_fee,
+                max_fee_per_gas: x.max_fee_per_gas.unwrap_or_default(),
+                max_priority_fee_per_gas: x.max_priority_fee_per_gas.unwrap_or_default(),
+            },
+            TypedTransaction::Legacy(x) => Fees::gas_price(TxFormat::Legacy, x.gas_price.unwrap_or_default()),
+            TypedTransaction::Eip2930(x) => Fees::gas_price(TxFormat::Eip2930, x.tx.gas_price.unwrap_or_default()),
+        }
+    }
+
+    fn gas_price(format: TxFormat, gas_price: U256) -> Fees {
+        Fees {
+            format,
+            base_fee: U256::zero(),
+            max_fee_per_gas: gas_price,
+            max_priority_fee_per_gas: gas_price,
+        }
+    }
+
+    /// Sets the fees of `tx`, converting it to `self.format` if needed
+    pub fn apply(&self, tx: &mut TypedTransaction) {
+        let (mut request, access_list) = match tx.clone() {
+            TypedTransaction::Legacy(x) => (x, AccessList::default()),
+            TypedTransaction::Eip2930(x) => (x.tx, x.access_list),
+            TypedTransaction::Eip1559(x) => {
+                let mut request = TransactionRequest::new();
+                request.from = x.from;
+                request.to = x.to;
+                request.gas = x.gas;
+                request.value = x.value;
+                request.data = x.data;
+                request.nonce = x.nonce;
+                (request, x.access_list)
+            }
+        };
+        *tx = match self.format {
+            TxFormat::Legacy => {
+                request.gas_price = Some(self.max_fee_per_gas);
+                TypedTransaction::Legacy(request)
+            }
+            TxFormat::Eip2930 => {
+                request.gas_price = Some(self.max_fee_per_gas);
+                TypedTransaction::Eip2930(Eip2930TransactionRequest::new(request, access_list))
+            }
+            TxFormat::Eip1559 => {
+                let mut x = Eip1559TransactionRequest::new();
+                x.from = request.from;
+                x.to = request.to;
+                x.gas = request.gas;
+                x.value = request.value;
+                x.data = request.data;
+                x.nonce = request.nonce;
+                x.access_list = access_list;
+                x.max_fee_per_gas = Some(self.max_fee_per_gas);
+                x.max_priority_fee_per_gas = Some(self.max_priority_fee_per_gas);
+                TypedTransaction::Eip1559(x)
+            }
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FeeStrategy {
+    /// `max_fee_per_gas` is this many times the base fee, plus the tip
+    pub base_fee_multiplier: u64,
+    /// The `eth_feeHistory` reward percentile to tip
+    pub tip_percentile: f64,
+    /// How many blocks of `eth_feeHistory` to look at
+    pub history_blocks: u64,
+    /// Never tip less than this (wei)
+    pub min_tip: U256,
+    /// The kind of transactions to send; `None` to pick EIP-1559 if blocks
+    /// carry a base fee, and legacy otherwise
+    pub tx_format: Option<TxFormat>,
+    /// Raises the tip when bumping; also caps `max_fee_per_gas`
+    pub escalator: GeometricGasPrice,
+    /// Cancellations may go over the escalator's `max_price`, up to this many
+    /// times it
+    pub max_cancel_price_multiplier: u64,
+}
+
+impl FeeStrategy {
+    /// Fees for a new transaction, given the current base fee and tip
+    pub fn fees(&self, base_fee: U256, tip: U256) -> Fees {
+        let tip = std::cmp::max(tip, self.min_tip);
+        self.capped(Fees {
+            format: TxFormat::Eip1559,
+            base_fee,
+            max_fee_per_gas: base_fee * self.base_fee_multiplier + tip,
+            max_priority_fee_per_gas: tip,
+        })
+    }
+
+    /// Fees for a new legacy or EIP-2930 transaction, given the current gas price
+    pub fn legacy_fees(&self, format: TxFormat, gas_price: U256) -> Fees {
+        self.capped(Fees::gas_price(format, gas_price))
+    }
+
+    /// Fees to replace a transaction sent with `current`: the tip goes up by
+    /// the escalator's coefficient, and the max fee follows `base_fee`. Both
+    /// go up by at least `MIN_REPLACEMENT_INCREASE_PCT`. Legacy gas prices are
+    /// bumped like tips
+    pub fn bump(&self, current: Fees, base_fee: U256) -> Fees {
+        self.capped(self.bump_uncapped(current, base_fee))
+    }
+
+    /// Fees to cancel a transaction sent with `current`. Cancellations only
+    /// burn 21000 gas, so they may go over `max_price` to replace it, up to
+    /// `max_cancel_price_multiplier` times it
+    pub fn cancellation_fees(&self, current: Fees, base_fee: U256) -> Fees {
+        let mut fees = self.bump_uncapped(current, base_fee);
+        if let Some(max_price) = self.escalator.max_price {
+            let max_price = max_price * self.max_cancel_price_multiplier;
+            fees.max_fee_per_gas = std::cmp::min(fees.max_fee_per_gas, max_price);
+            fees.max_priority_fee_per_gas = std::cmp::min(fees.max_priority_fee_per_gas, fees.max_fee_per_gas);
+        }
+        fees
+    }
+
+    fn bump_uncapped(&self, current: Fees, base_fee: U256) -> Fees {
+        let base_fee = match current.format {
+            TxFormat::Eip1559 => base_fee,
+            _ => U256::zero(),
+        };
+        let escalated_tip = U256::from(
+            (current.max_priority_fee_per_gas.as_u128() as f64 * self.escalator.coefficient).ceil() as u128,
+        );
+        let tip = std::cmp::max(escalated_tip, min_replacement(current.max_priority_fee_per_gas));
+        let max_fee = std::cmp::max(
+            base_fee * self.base_fee_multiplier + tip,
+            min_replacement(current.max_fee_per_gas),
+        );
+        Fees {
+            format: current.format,
+            base_fee,
+            max_fee_per_gas: max_fee,
+            max_priority_fee_per_gas: tip,
+        }
+    }
+
+    /// Reads the next block's base fee and the recent tips from `eth_feeHistory`,
+    /// or the gas price if we send legacy transactions
+    pub async fn estimate<M: Middleware>(&self, client: &M) -> Result<Fees, M::Error> {
+        let format = match self.tx_format {
+            Some(x) => x,
+            None => detect_format(client).await?,
+        };
+        if format != TxFormat::Eip1559 {
+            let gas_price = client.get_gas_price().await?;
+            return Ok(self.legacy_fees(format, gas_price));
+        }
+
+        let history = client
+            .fee_history(self.history_blocks, BlockNumber::Latest, &[self.tip_percentile])
+            .await?;
+        // the last entry is the base fee of the next block
+        let base_fee = history.base_fee_per_gas.last().copied().unwrap_or_default();
+        let tip = median(history.reward.iter().filter_map(|x| x.first().copied()).collect());
+        Ok(self.fees(base_fee, tip))
+    }
+
+    fn capped(&self, mut fees: Fees) -> Fees {
+        if let Some(max_price) = self.escalator.max_price {
+            fees.max_fee_per_gas = std::cmp::min(fees.max_fee_per_gas, max_price);
+            fees.max_priority_fee_per_gas = std::cmp::min(fees.max_priority_fee_per_gas, fees.max_fee_per_gas);
+        }
+        fees
+    }
+}
+
+/// EIP-1559 if the latest block carries a base fee, legacy otherwise
+pub async fn detect_format<M: Middleware>(client: &M) -> Result<TxFormat, M::Error> {
+    let block = client.get_block(BlockNumber::Latest).await?;
+    Ok(match block.and_then(|x| x.base_fee_per_gas) {
+        Some(_) => TxFormat::Eip1559,
+        None => TxFormat::Legacy,
+    })
+}
+
+/// Whether a transaction with fees `new` can replace one with `current`
+pub fn is_replacement(current: &Fees, new: &Fees) -> bool {
+    new.max_fee_per_gas >= min_replacement(current.max_fee_per_gas)
+        && new.max_priority_fee_per_gas >= min_replacement(current.max_priority_fee_per_gas)
+}
+
+/// `value` increased by `MIN_REPLACEMENT_INCREASE_PCT`, rounded up
+fn min_replacement(value: U256) -> U256 {
+    (value * (100 + MIN_REPLACEMENT_INCREASE_PCT) + 99) / 100
+}
+
+fn median(mut values: Vec<U256>) -> U256 {
+    if values.is_empty() {
+        return U256::zero();
+    }
+    values.sort_unstable();
+    values[values.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strategy() -> FeeStrategy {
+        let mut escalator = GeometricGasPrice::new();
+        escalator.coefficient = 1.125;
+        escalator.max_price = Some(U256::from(1000));
+        FeeStrategy {
+            base_fee_multiplier: 2,
+            tip_percentile: 50.0,
+            history_blocks: 10,
+            min_tip: U256::from(2),
+            tx_format: None,
+            escalator,
+            max_cancel_price_multiplier: 2,
+        }
+    }
+
+    #[test]
+    fn max_fee_is_a_base_fee_multiple_plus_the_tip() {
+        let fees = strategy().fees(100.into(), 10.into());
+        assert_eq!(fees.max_fee_per_gas, 210.into());
+        assert_eq!(fees.max_priority_fee_per_gas, 10.into());
+        assert_eq!(fees.expected_gas_price(), 110.into());
+
+        // minimum tip
+        assert_eq!(strategy().fees(100.into(), 1.into()).max_priority_fee_per_gas, 2.into());
+        // cap
+        let fees = strategy().fees(600.into(), 10.into());
+        assert_eq!(fees.max_fee_per_gas, 1000.into());
+    }
+
+    #[test]
+    fn bumps_the_tip_by_at_least_the_replacement_minimum() {
+        let strategy = strategy();
+        let fees = strategy.fees(100.into(), 10.into());
+
+        // same base fee: the tip goes up by 12.5%, and so does the max fee
+        let bumped = strategy.bump(fees, 100.into());
+        assert_eq!(bumped.max_priority_fee_per_gas, 12.into());
+        assert_eq!(bumped.max_fee_per_gas, 231.into());
+
+        // the base fee went down: the max fee still has to go up
+        let bumped = strategy.bump(fees, 10.into());
+        assert_eq!(bumped.max_fee_per_gas, 231.into());
+
+        // the base fee went up: the max fee follows it
+        let bumped = strategy.bump(fees, 200.into());
+        assert_eq!(bumped.max_fee_per_gas, 412.into());
+        assert!(is_replacement(&fees, &bumped));
+
+        // max price: the bump can't replace the transaction, but a cancellation can
+        let bumped = strategy.bump(fees, 600.into());
+        assert_eq!(bumped.max_fee_per_gas, 1000.into());
+        let capped = strategy.bump(bumped, 600.into());
+        assert!(!is_replacement(&bumped, &capped));
+        let cancellation = strategy.cancellation_fees(bumped, 600.into());
+        assert!(is_replacement(&bumped, &cancellation));
+
+        // cancellations have their own cap
+        let mut cancellation = cancellation;
+        for _ in 0..10 {
+            cancellation = strategy.cancellation_fees(cancellation, 600.into());
+        }
+        assert_eq!(cancellation.max_fee_per_gas, 2000.into());
+        let capped = strategy.cancellation_fees(cancellation, 600.into());
+        assert!(!is_replacement(&cancellation, &capped));
+    }
+
+    #[test]
+    fn bumps_legacy_gas_prices() {
+        let strategy = strategy();
+        let fees = strategy.legacy_fees(TxFormat::Legacy, 100.into());
+        assert_eq!(fees.expected_gas_price(), 100.into());
+
+        // the base fee is ignored
+        let bumped = strategy.bump(fees, 50.into());
+        assert_eq!(bumped.format, TxFormat::Legacy);
+        assert_eq!(bumped.max_fee_per_gas, 113.into());
+        assert_eq!(bumped.expected_gas_price(), 113.into());
+    }
+
+    #[test]
+    fn converts_transactions() {
+        let mut tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(Address::repeat_byte(1))
+            .nonce(3)
+            .gas(100_000)
+            .into();
+
+        let legacy = Fees::gas_price(TxFormat::Legacy, 7.into());
+        legacy.apply(&mut tx);
+        assert!(matches!(tx, TypedTransaction::Legacy(_)));
+        assert_eq!(Fees::of(&tx, 5.into()), legacy);
+        assert_eq!(tx.nonce(), Some(&3.into()));
+
+        let eip2930 = Fees::gas_price(TxFormat::Eip2930, 8.into());
+        eip2930.apply(&mut tx);
+        assert_eq!(Fees::of(&tx, 5.into()), eip2930);
+
+        let eip1559 = strategy().fees(5.into(), 10.into());
+        eip1559.apply(&mut tx);
+        assert_eq!(Fees::of(&tx, 5.into()), eip1559);
+        assert_eq!(tx.nonce(), Some(&3.into()));
+        assert_eq!(tx.to(), Some(&Address::repeat_byte(1).into()));
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!("legacy".parse::<TxFormat>().unwrap(), TxFormat::Legacy);
+        assert_eq!("eip2930".parse::<TxFormat>().unwrap(), TxFormat::Eip2930);
+        assert_eq!("eip1559".parse::<TxFormat>().unwrap(), TxFormat::Eip1559);
+        assert!("auto".parse::<TxFormat>().is_err());
+    }
+
+    #[test]
+    fn median_reward() {
+        assert_eq!(median(vec![]), U256::zero());
+        assert_eq!(median(vec![3.into(), 1.into(), 2.into()]), 2.into());
+    }
+}
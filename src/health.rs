@@ -0,0 +1,151 @@
+//! Keeper health
+//!
+//! The keeper reports its progress here, and the HTTP server in `server`
+//! exposes it to orchestrators.
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+lazy_static! {
+    /// The health of this process' keeper
+    pub static ref HEALTH: Health = Health::new(unix_now());
+}
+
+/// Progress and reachability of the keeper's dependencies
+#[derive(Debug)]
+pub struct Health {
+    /// When the process started (unix timestamp, seconds)
+    started_at: u64,
+    /// The last block for which `on_block` completed
+    last_block: AtomicU64,
+    /// When `on_block` last completed (unix timestamp, seconds). 0 if never
+    last_block_at: AtomicU64,
+    /// Consecutive failures to poll for new blocks
+    err_count: AtomicU64,
+    rpc_reachable: AtomicBool,
+    swap_router_reachable: AtomicBool,
+}
+
+/// A snapshot of `Health`, as reported by the HTTP endpoints
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub last_block: u64,
+    /// Seconds since `on_block` last completed (or since startup, if it never did)
+    pub last_block_age_secs: u64,
+    pub err_count: u64,
+    pub rpc_reachable: bool,
+    pub swap_router_reachable: bool,
+    /// `on_block` completed recently enough
+    pub healthy: bool,
+    /// Healthy, and all the dependencies are reachable
+    pub ready: bool,
+}
+
+impl Health {
+    /// Constructor
+    pub fn new(started_at: u64) -> Self {
+        Health {
+            started_at,
+            last_block: AtomicU64::new(0),
+            last_block_at: AtomicU64::new(0),
+            err_count: AtomicU64::new(0),
+            rpc_reachable: AtomicBool::new(true),
+            swap_router_reachable: AtomicBool::new(true),
+        }
+    }
+
+    /// `on_block` completed for `block_number`
+    pub fn block_processed(&self, block_number: u64) {
+        self.last_block.store(block_number, Ordering::Relaxed);
+        self.last_block_at.store(unix_now(), Ordering::Relaxed);
+        self.rpc_reachable.store(true, Ordering::Relaxed);
+    }
+
+    /// Records the number of consecutive failures to poll for new blocks
+    pub fn set_err_count(&self, err_count: u64) {
+        self.err_count.store(err_count, Ordering::Relaxed);
+        if err_count > 0 {
+            self.rpc_reachable.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Set by the failover client when none of the endpoints can be reached;
+    /// processing a block sets it back
+    pub fn set_rpc_reachable(&self, reachable: bool) {
+        self.rpc_reachable.store(reachable, Ordering::Relaxed);
+    }
+
+    pub fn set_swap_router_reachable(&self, reachable: bool) {
+        self.swap_router_reachable.store(reachable, Ordering::Relaxed);
+    }
+
+    /// Reports the health as of `now`: the keeper is healthy if `on_block`
+    /// completed within the last `max_block_age_secs` seconds
+    pub fn report(&self, now: u64, max_block_age_secs: u64) -> HealthReport {
+        let last_block_at = match self.last_block_at.load(Ordering::Relaxed) {
+            // give the keeper some time to process its first block
+            0 => self.started_at,
+            x => x,
+        };
+        let last_block_age_secs = now.saturating_sub(last_block_at);
+        let rpc_reachable = self.rpc_reachable.load(Ordering::Relaxed);
+        let swap_router_reachable = self.swap_router_reachable.load(Ordering::Relaxed);
+        let healthy = last_block_age_secs <= max_block_age_secs;
+        HealthReport {
+            last_block: self.last_block.load(Ordering::Relaxed),
+            last_block_age_secs,
+            err_count: self.err_count.load(Ordering::Relaxed),
+            rpc_reachable,
+            swap_router_reachable,
+            healthy,
+            ready: healthy && rpc_reachable && swap_router_reachable,
+        }
+    }
+}
+
+/// Current unix timestamp, in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_keeper_is_unhealthy() {
+        let health = Health::new(1000);
+
+        // grace period after startup
+        assert!(health.report(1050, 60).healthy);
+        assert!(!health.report(1061, 60).healthy);
+
+        health.last_block_at.store(2000, Ordering::Relaxed);
+        let report = health.report(2030, 60);
+        assert!(report.healthy);
+        assert!(report.ready);
+        assert_eq!(report.last_block_age_secs, 30);
+        assert!(!health.report(2061, 60).healthy);
+    }
+
+    #[test]
+    fn unreachable_dependencies_are_not_ready() {
+        let health = Health::new(1000);
+        health.set_swap_router_reachable(false);
+        let report = health.report(1000, 60);
+        assert!(report.healthy);
+        assert!(!report.ready);
+
+        health.set_swap_router_reachable(true);
+        health.set_err_count(3);
+        let report = health.report(1000, 60);
+        assert_eq!(report.err_count, 3);
+        assert!(!report.ready);
+    }
+}
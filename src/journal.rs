@@ -0,0 +1,113 @@
+//! Dry-run journal
+//!
+//! In dry-run mode, transactions are simulated with `eth_call` instead of
+//! being sent. The journal records what we would have sent, one JSON object
+//! per line.
+use ethers::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// What the simulated transaction would have done
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "status", content = "reason")]
+pub enum SimulatedOutcome {
+    Success,
+    Reverted(String),
+}
+
+/// A transaction we would have sent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// When the transaction was simulated (unix timestamp, seconds)
+    pub timestamp: u64,
+    /// "liquidation" (starting an auction) or "auction" (a bid)
+    pub tx_type: String,
+    /// Hex encoded
+    pub vault_id: String,
+    pub to: Address,
+    pub calldata: Bytes,
+    /// `None` if the gas estimation failed
+    pub gas_estimate: Option<U256>,
+    pub gas_price: U256,
+    pub outcome: SimulatedOutcome,
+    /// Estimated profit after gas, in base units, as a decimal string (bids only)
+    pub profit: Option<String>,
+}
+
+/// An append-only JSON lines file of `JournalEntry`s
+#[derive(Clone, Debug)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// Constructor
+    pub fn new(path: PathBuf) -> Self {
+        Journal { path }
+    }
+
+    /// Appends `entry` to the journal
+    pub fn record(&self, entry: &JournalEntry) -> Result<(), JournalError> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("yield-liquidator-{}-journal.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journal = Journal::new(path.clone());
+
+        let entry = JournalEntry {
+            timestamp: 1000,
+            tx_type: "auction".to_string(),
+            vault_id: hex::encode([1u8; 12]),
+            to: Address::repeat_byte(2),
+            calldata: vec![1, 2, 3].into(),
+            gas_estimate: Some(U256::from(500_000)),
+            gas_price: U256::from(100),
+            outcome: SimulatedOutcome::Success,
+            profit: Some("42".to_string()),
+        };
+        journal.record(&entry).unwrap();
+        journal
+            .record(&JournalEntry {
+                outcome: SimulatedOutcome::Reverted("go away baka".to_string()),
+                gas_estimate: None,
+                ..entry
+            })
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["vault_id"], "010101010101010101010101");
+        assert_eq!(first["outcome"]["status"], "success");
+        let second: JournalEntry = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second.outcome, SimulatedOutcome::Reverted("go away baka".to_string()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,37 @@
 use crate::{
-    bindings::{Witch, BaseIdType},
+    backfill::Backfill,
+    bindings::{Witch, BaseIdType, IlkIdType},
     borrowers::{Borrowers, VaultMap},
+    breaker::{BreakerLimits, CircuitBreaker},
     cache::ImmutableCache,
-    escalator::GeometricGasPrice,
-    liquidations::{AuctionMap, Liquidator},
+    cancel::CancelPolicy,
+    fees::FeeStrategy,
+    health::{unix_now, HEALTH},
+    journal::Journal,
+    ledger::Ledger,
+    liquidations::{AuctionMap, BundleMap, CancellationMap, Liquidator, PendingTransactionMap},
+    metrics,
+    outcomes::OutcomeTracker,
+    relay::BundleRelay,
+    reorg::{BlockHistory, DEFAULT_REORG_DEPTH},
+    routes::FlashRouteConfig,
+    store::StateStore,
+    strategy::Strategy,
     Result, swap_router::SwapRouter,
 };
 
 use ethers::prelude::*;
+use futures_util::stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::{
-    collections::HashMap, io::Write, path::PathBuf, sync::Arc, time::SystemTime, time::UNIX_EPOCH,
+    collections::HashMap, sync::Arc, time::SystemTime, time::UNIX_EPOCH,
 };
-use tokio::time::{sleep, Duration};
-use tracing::{debug_span, info, instrument, trace};
+use tokio::{
+    sync::watch,
+    time::{sleep, Duration, Instant},
+};
+use tracing::{debug, debug_span, error, info, instrument, trace, warn};
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Default)]
@@ -22,12 +39,72 @@ use tracing::{debug_span, info, instrument, trace};
 pub struct State {
     /// The auctions being monitored
     #[serde_as(as = "Vec<(_, _)>")]
-    auctions: AuctionMap,
+    pub auctions: AuctionMap,
     /// The borrowers being monitored
     #[serde_as(as = "Vec<(_, _)>")]
-    vaults: VaultMap,
+    pub vaults: VaultMap,
     /// The last observed block
-    last_block: u64,
+    pub last_block: u64,
+    /// Our auction-starting transactions which were not mined yet
+    #[serde_as(as = "Vec<(_, _)>")]
+    #[serde(default)]
+    pub pending_liquidations: PendingTransactionMap,
+    /// Our bids which were not mined yet
+    #[serde_as(as = "Vec<(_, _)>")]
+    #[serde(default)]
+    pub pending_auctions: PendingTransactionMap,
+    /// Self-transfers replacing our stuck transactions, by nonce
+    #[serde_as(as = "Vec<(_, _)>")]
+    #[serde(default)]
+    pub pending_cancellations: CancellationMap,
+    /// Our bids sent to the relay which were not mined yet
+    #[serde_as(as = "Vec<(_, _)>")]
+    #[serde(default)]
+    pub pending_bundles: BundleMap,
+    /// Who started and bought the auctions, and the per-ilk win rates
+    #[serde(default)]
+    pub outcomes: OutcomeTracker,
+    /// (number, hash) of the most recently processed blocks, used to detect reorgs
+    #[serde(default)]
+    pub recent_blocks: Vec<(u64, H256)>,
+    /// Our recent spending and failures, and whether the circuit breaker is tripped
+    #[serde(default)]
+    pub breaker: CircuitBreaker,
+}
+
+impl State {
+    /// The hashes of all our pending transactions, sorted. They change whenever
+    /// a transaction is submitted, bumped or forgotten
+    pub fn pending_hashes(&self) -> Vec<TxHash> {
+        let mut hashes: Vec<TxHash> = self
+            .pending_liquidations
+            .values()
+            .chain(self.pending_auctions.values())
+            .chain(self.pending_cancellations.values())
+            .map(|x| x.hash)
+            .chain(self.pending_bundles.values().map(|x| x.hash))
+            .collect();
+        hashes.sort();
+        hashes
+    }
+}
+
+/// Tells the keeper when to stop, and how
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    /// Becomes `true` when the keeper has to stop
+    pub signal: watch::Receiver<bool>,
+    /// How long to wait for our pending transactions to be mined before exiting
+    pub settle_timeout: Duration,
+}
+
+/// How the keeper stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownOutcome {
+    /// None of our transactions are pending
+    Settled,
+    /// Some of our transactions were still pending when we gave up waiting
+    Unsettled,
 }
 
 /// The keeper monitors the chain for both liquidation opportunities and for
@@ -35,10 +112,17 @@ pub struct State {
 pub struct Keeper<M> {
     client: Arc<M>,
     last_block: U64,
+    block_history: BlockHistory,
 
     cache: ImmutableCache<M>,
     borrowers: Borrowers<M>,
     liquidator: Liquidator<M>,
+    /// Prices the transactions of each block
+    fee_strategy: FeeStrategy,
+    /// Max number of blocks to query logs for at once when catching up
+    backfill_chunk_size: u64,
+    /// The first block whose auction outcomes we failed to follow, if any
+    outcomes_from: Option<U64>,
     instance_name: String,
 }
 
@@ -49,22 +133,28 @@ impl<M: Middleware> Keeper<M> {
         client: Arc<M>,
         liquidations: Address,
         flashloan: Address,
+        ilk_flash_liquidators: HashMap<IlkIdType, FlashRouteConfig>,
         multicall2: Address,
         multicall_batch_size: usize,
-        min_ratio: u16,
-        gas_boost: u16,
-        gas_escalator: GeometricGasPrice,
+        strategy: Strategy,
+        fee_strategy: FeeStrategy,
         bump_gas_delay: u64,
-        target_collateral_offer: u16,
+        cancel_policy: CancelPolicy,
+        breaker_limits: BreakerLimits,
         base_to_debt_threshold: HashMap<BaseIdType, u128>,
+        base_to_min_profit: HashMap<BaseIdType, u128>,
+        prepare_ahead: u64,
         state: Option<State>,
         swap_router: SwapRouter,
+        backfill_chunk_size: u64,
+        journal: Option<Journal>,
+        relay: Option<BundleRelay>,
+        ledger: Option<Ledger>,
         instance_name: String,
     ) -> Result<Keeper<M>, M> {
-        let (vaults, auctions, last_block) = match state {
-            Some(state) => (state.vaults, state.auctions, state.last_block.into()),
-            None => (HashMap::new(), HashMap::new(), 0.into()),
-        };
+        let state = state.unwrap_or_default();
+        let last_block = state.last_block.into();
+        let block_history = BlockHistory::new(state.recent_blocks, DEFAULT_REORG_DEPTH);
         let witch = Witch::new(liquidations, client.clone());
         let controller = witch.cauldron().call().await?;
         let borrowers = Borrowers::new(
@@ -73,26 +163,39 @@ impl<M: Middleware> Keeper<M> {
             multicall2,
             multicall_batch_size,
             client.clone(),
-            vaults,
+            state.vaults,
             instance_name.clone(),
         )
         .await;
-        let liquidator = Liquidator::new(
+        let mut liquidator = Liquidator::new(
             swap_router,
             controller,
             liquidations,
             flashloan,
-            Some(multicall2),
-            min_ratio,
-            gas_boost,
-            target_collateral_offer,
+            ilk_flash_liquidators,
+            multicall2,
+            strategy,
+            base_to_min_profit,
+            prepare_ahead,
             client.clone(),
-            auctions,
-            gas_escalator,
+            state.auctions,
+            state.pending_liquidations,
+            state.pending_auctions,
+            state.pending_cancellations,
+            fee_strategy.clone(),
             bump_gas_delay,
+            cancel_policy,
+            state.breaker.with_limits(breaker_limits),
+            journal,
+            relay,
+            state.pending_bundles,
+            state.outcomes,
+            ledger,
             instance_name.clone(),
         )
         .await;
+        // make sure we know what happened to our transactions before sending new ones
+        liquidator.reconcile_pending().await?;
 
         let cache = ImmutableCache::new(
             client.clone(), 
@@ -107,16 +210,32 @@ impl<M: Middleware> Keeper<M> {
             cache,
             borrowers,
             liquidator,
+            fee_strategy,
             last_block,
+            block_history,
+            backfill_chunk_size,
+            outcomes_from: None,
             instance_name: instance_name.clone(),
         })
     }
 
-    pub async fn run(&mut self, fname: PathBuf, start_block: Option<u64>) -> Result<(), M> {
+    /// Runs the keeper, polling for new blocks every `poll_interval`, until
+    /// it's told to shut down.
+    ///
+    /// Works with any provider; see `run_subscribed` for providers which
+    /// support subscriptions
+    pub async fn run(
+        &mut self,
+        store: &mut dyn StateStore,
+        start_block: Option<u64>,
+        poll_interval: Duration,
+        mut shutdown: Shutdown,
+    ) -> Result<ShutdownOutcome, M> {
         // Create the initial list of borrowers from the start_block, if provided
         if let Some(start_block) = start_block {
             self.last_block = start_block.into();
         }
+        self.backfill(store, &shutdown).await?;
 
         let watcher = self.client.clone();
         let mut filter_id = watcher
@@ -125,98 +244,49 @@ impl<M: Middleware> Keeper<M> {
             .map_err(ContractError::MiddlewareError)?;
 
         let mut err_count = 0;
-        let mut file: Option<std::fs::File> = None;
 
         let mut maybe_last_block_number: Option<u64> = None;
 
         let span = debug_span!("run", instance_name = self.instance_name.as_str());
         let _enter = span.enter();
         loop {
-            sleep(Duration::from_secs(30)).await; // don't spin
+            // don't spin
+            tokio::select! {
+                biased;
+                Ok(()) = shutdown.signal.changed() => break,
+                _ = sleep(poll_interval) => {}
+            }
             match watcher
                 .get_filter_changes::<_, ethers_core::types::H256>(filter_id)
                 .await
             {
                 Ok(_results) => {
                     err_count = 0;
+                    HEALTH.set_err_count(err_count);
                     let block_number = self
                         .client
                         .get_block_number()
                         .await
                         .map_err(ContractError::MiddlewareError)?;
 
-                    if let Some(last_block_number) = maybe_last_block_number {
-                        if last_block_number == block_number.as_u64() {
-                            trace!(last_block_number, "skipping previously seen block");
-                            continue;
-                        }
-                    }
-
-                    maybe_last_block_number = Some(block_number.as_u64());
-                    match self
+                    let block = self
                         .client
                         .get_block(block_number)
                         .await
-                        .map_err(ContractError::MiddlewareError)?
-                    {
-                        Some(block) => {
-                            let block_timestamp = block.timestamp.as_u64() as i64;
-                            match SystemTime::now().duration_since(UNIX_EPOCH) {
-                                Ok(current_time) => {
-                                    info!(
-                                        block_number = block_number.as_u64(),
-                                        timestamp = block_timestamp,
-                                        delay_seconds =
-                                            current_time.as_secs() as i64 - block_timestamp,
-                                        instance_name = self.instance_name.as_str(),
-                                        "New block"
-                                    );
-                                }
-                                Err(_) => {
-                                    info!(
-                                        block_number = block_number.as_u64(),
-                                        timestamp = block_timestamp,
-                                        instance_name = self.instance_name.as_str(),
-                                        "New block"
-                                    );
-                                }
-                            }
-                        }
-                        None => {
-                            info!(
-                                block_number = block_number.as_u64(),
-                                instance_name = self.instance_name.as_str(),
-                                "New block"
-                            );
-                        }
-                    }
+                        .map_err(ContractError::MiddlewareError)?;
 
-                    if block_number % 10 == 0.into() {
-                        // on each new block we open a new file handler to dump our state.
-                        // we should just have a database connection instead here...
-                        file = Some(
-                            std::fs::OpenOptions::new()
-                                .read(true)
-                                .write(true)
-                                .create(true)
-                                .open(&fname)
-                                .unwrap(),
-                        );
+                    // a block at the same height may be another one after a reorg
+                    if self.is_processed(block_number.as_u64(), block.as_ref(), maybe_last_block_number) {
+                        trace!(block_number = block_number.as_u64(), "skipping previously seen block");
+                        continue;
                     }
+                    maybe_last_block_number = Some(block_number.as_u64());
 
-                    // run the logic for this block
-                    self.on_block(block_number).await?;
-
-                    // update our last block
-                    self.last_block = block_number;
-
-                    // Log once every 10 blocks
-                    if let Some(file) = file.take() {
-                        self.log(file);
-                    }
+                    self.process_block(store, block_number, block).await?;
                 }
                 Err(_x) => {
                     err_count += 1;
+                    HEALTH.set_err_count(err_count);
                     if err_count == 10 {
                         return Err(ContractError::ProviderError(ProviderError::CustomError(
                             String::from("can't query filter"),
@@ -229,6 +299,278 @@ impl<M: Middleware> Keeper<M> {
                 }
             }
         }
+
+        self.shutdown(store, shutdown.settle_timeout).await
+    }
+
+    /// Runs the keeper, processing blocks as soon as the provider notifies us
+    /// about them, until it's told to shut down
+    pub async fn run_subscribed(
+        &mut self,
+        store: &mut dyn StateStore,
+        start_block: Option<u64>,
+        mut shutdown: Shutdown,
+    ) -> Result<ShutdownOutcome, M>
+    where
+        <M as Middleware>::Provider: PubsubClient,
+    {
+        // Create the initial list of borrowers from the start_block, if provided
+        if let Some(start_block) = start_block {
+            self.last_block = start_block.into();
+        }
+        self.backfill(store, &shutdown).await?;
+
+        let watcher = self.client.clone();
+        let mut blocks = watcher
+            .subscribe_blocks()
+            .await
+            .map_err(ContractError::MiddlewareError)?;
+
+        let mut maybe_last_block_number: Option<u64> = None;
+
+        let span = debug_span!("run", instance_name = self.instance_name.as_str());
+        let _enter = span.enter();
+        loop {
+            let block = tokio::select! {
+                biased;
+                Ok(()) = shutdown.signal.changed() => break,
+                x = blocks.next() => match x {
+                    Some(block) => block,
+                    None => {
+                        return Err(ContractError::ProviderError(ProviderError::CustomError(
+                            String::from("block subscription ended"),
+                        )));
+                    }
+                },
+            };
+            let block_number = match block.number {
+                Some(x) => x,
+                None => {
+                    trace!(block_hash = ?block.hash, "skipping pending block");
+                    continue;
+                }
+            };
+
+            // a block at or below the last height may be another one after a reorg
+            if self.is_processed(block_number.as_u64(), Some(&block), maybe_last_block_number) {
+                trace!(block_number = block_number.as_u64(), "skipping previously seen block");
+                continue;
+            }
+            maybe_last_block_number = Some(block_number.as_u64());
+
+            self.process_block(store, block_number, Some(block)).await?;
+        }
+
+        self.shutdown(store, shutdown.settle_timeout).await
+    }
+
+    /// Stops the keeper: persists the final state and waits up to
+    /// `settle_timeout` for our pending transactions to be mined
+    #[instrument(skip(self, store), fields(self.instance_name))]
+    async fn shutdown(&mut self, store: &mut dyn StateStore, settle_timeout: Duration) -> Result<ShutdownOutcome, M> {
+        info!(last_block = self.last_block.as_u64(), instance_name = self.instance_name.as_str(), "Shutting down");
+        if let Err(x) = store.flush(&self.state()) {
+            error!(err=?x, instance_name=self.instance_name.as_str(), "Failed to persist state");
+        }
+
+        if self.liquidator.is_dry_run() {
+            return Ok(ShutdownOutcome::Settled);
+        }
+
+        let deadline = Instant::now() + settle_timeout;
+        loop {
+            let bundles = self.liquidator.pending_bundles.len();
+            let pending = self.liquidator.pending_liquidations.len()
+                + self.liquidator.pending_auctions.len()
+                + self.liquidator.pending_cancellations.len()
+                + bundles;
+            if pending == 0 {
+                info!(instance_name = self.instance_name.as_str(), "All transactions settled");
+                return Ok(ShutdownOutcome::Settled);
+            }
+            if Instant::now() >= deadline {
+                // the relay forgets bundles after their target block: they're sent again on restart
+                for (vault_id, bundle) in self.liquidator.pending_bundles.iter() {
+                    warn!(tx_hash = ?bundle.hash, vault_id = ?hex::encode(vault_id), nonce = ?bundle.tx.nonce(),
+                        target_block = bundle.target_block.as_u64(), instance_name = self.instance_name.as_str(),
+                        "Bundle not mined");
+                }
+                warn!(pending, bundles, instance_name = self.instance_name.as_str(), "Giving up on pending transactions");
+                return Ok(ShutdownOutcome::Unsettled);
+            }
+            info!(pending, bundles, instance_name = self.instance_name.as_str(), "Waiting for pending transactions");
+            sleep(std::cmp::min(Duration::from_secs(5), deadline.saturating_duration_since(Instant::now()))).await;
+
+            let fees = self
+                .fee_strategy
+                .estimate(self.client.as_ref())
+                .await
+                .map_err(ContractError::MiddlewareError)?;
+            self.liquidator.remove_or_bump(&fees).await?;
+            // bundles only target the next block
+            let block_number = self.client.get_block_number().await.map_err(ContractError::MiddlewareError)?;
+            self.liquidator.retarget_bundles(block_number, &fees).await?;
+            self.liquidator.record_mined_bids(&mut self.cache).await?;
+            if let Err(x) = store.flush(&self.state()) {
+                error!(err=?x, instance_name=self.instance_name.as_str(), "Failed to persist state");
+            }
+        }
+    }
+
+    /// Catches up with the chain head: collects the vaults and auctions created
+    /// since `last_block`, querying the logs in chunks of at most
+    /// `backfill_chunk_size` blocks. `last_block` is checkpointed after each chunk
+    #[instrument(skip(self, store, shutdown), fields(self.instance_name))]
+    async fn backfill(&mut self, store: &mut dyn StateStore, shutdown: &Shutdown) -> Result<(), M> {
+        let head = self
+            .client
+            .get_block_number()
+            .await
+            .map_err(ContractError::MiddlewareError)?
+            .as_u64();
+        let from = self.last_block.as_u64();
+        if head <= from + self.backfill_chunk_size {
+            // small enough for the regular per-block logic
+            return Ok(());
+        }
+        info!(from, head, chunk_size = self.backfill_chunk_size,
+            instance_name = self.instance_name.as_str(), "Backfilling");
+
+        let mut backfill = Backfill::new(from, head, self.backfill_chunk_size);
+        while let Some((chunk_from, chunk_to)) = backfill.next_range() {
+            if *shutdown.signal.borrow() {
+                info!(last_block = self.last_block.as_u64(), "Backfill interrupted");
+                break;
+            }
+            let new_vaults = self.borrowers.new_vaults(chunk_from.into(), chunk_to.into()).await;
+            let new_auctions = self.liquidator.new_auctions(chunk_from.into(), chunk_to.into()).await;
+            match (new_vaults, new_auctions) {
+                (Ok(new_vaults), Ok(new_auctions)) => {
+                    debug!(chunk_from, chunk_to, vaults = new_vaults.len(), auctions = new_auctions.len(),
+                        "Backfilled chunk");
+                    self.borrowers.add_vaults(new_vaults);
+                    for vault_id in new_auctions {
+                        self.liquidator.auctions.insert(vault_id, true);
+                    }
+                    self.last_block = chunk_to.into();
+                    backfill.on_success();
+                    metrics::LAST_BLOCK.set(chunk_to as i64);
+                    HEALTH.block_processed(chunk_to);
+                    // the chunks don't end on checkpoint blocks
+                    if let Err(x) = store.flush(&self.state()) {
+                        error!(err=?x, instance_name=self.instance_name.as_str(), "Failed to persist state");
+                    }
+                    info!(
+                        last_block = chunk_to,
+                        head,
+                        progress_pct = backfill.progress_pct(from),
+                        instance_name = self.instance_name.as_str(),
+                        "Backfill progress"
+                    );
+                }
+                (Err(x), _) | (_, Err(x)) => {
+                    warn!(chunk_from, chunk_to, err = ?x, "Failed to query logs, shrinking the range");
+                    if !backfill.on_failure() {
+                        return Err(x);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether we ran the logic for this block already. Blocks are compared by
+    /// hash; by number only when the node didn't tell us the hash
+    fn is_processed(&self, number: u64, block: Option<&Block<TxHash>>, last_number: Option<u64>) -> bool {
+        match block.and_then(|x| x.hash) {
+            Some(hash) => self.block_history.contains(number, hash),
+            None => matches!(last_number, Some(x) if x >= number),
+        }
+    }
+
+    /// Runs the logic for a new block and persists the resulting state
+    async fn process_block(
+        &mut self,
+        store: &mut dyn StateStore,
+        block_number: U64,
+        block: Option<Block<TxHash>>,
+    ) -> Result<(), M> {
+        match &block {
+            Some(block) => {
+                let block_timestamp = block.timestamp.as_u64() as i64;
+                match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(current_time) => {
+                        metrics::BLOCK_LAG.set(current_time.as_secs() as i64 - block_timestamp);
+                        info!(
+                            block_number = block_number.as_u64(),
+                            timestamp = block_timestamp,
+                            delay_seconds = current_time.as_secs() as i64 - block_timestamp,
+                            instance_name = self.instance_name.as_str(),
+                            "New block"
+                        );
+                    }
+                    Err(_) => {
+                        info!(
+                            block_number = block_number.as_u64(),
+                            timestamp = block_timestamp,
+                            instance_name = self.instance_name.as_str(),
+                            "New block"
+                        );
+                    }
+                }
+            }
+            None => {
+                info!(
+                    block_number = block_number.as_u64(),
+                    instance_name = self.instance_name.as_str(),
+                    "New block"
+                );
+            }
+        }
+
+        if let Some(block) = &block {
+            if let Some(fork_block) = self
+                .block_history
+                .find_reorg(self.client.as_ref(), block_number.as_u64(), block.parent_hash)
+                .await
+                .map_err(ContractError::MiddlewareError)?
+            {
+                // ingest the events again, starting from the last block we know is canonical
+                warn!(
+                    block_number = block_number.as_u64(),
+                    fork_block,
+                    last_block = self.last_block.as_u64(),
+                    instance_name = self.instance_name.as_str(),
+                    "Rolling back after a reorg"
+                );
+                self.last_block = std::cmp::min(self.last_block, fork_block.into());
+                self.liquidator.outcomes.rollback(fork_block);
+                self.outcomes_from = self.outcomes_from.map(|x| std::cmp::min(x, self.last_block + 1));
+            }
+        }
+
+        // run the logic for this block
+        let timestamp = match &block {
+            Some(block) => block.timestamp.as_u64(),
+            None => unix_now(),
+        };
+        let timer = metrics::BLOCK_PROCESSING_TIME.start_timer();
+        self.on_block(block_number, timestamp).await?;
+        timer.observe_duration();
+
+        // update our last block
+        self.last_block = block_number;
+        metrics::LAST_BLOCK.set(block_number.as_u64() as i64);
+        HEALTH.block_processed(block_number.as_u64());
+        if let Some(hash) = block.and_then(|x| x.hash) {
+            self.block_history.push(block_number.as_u64(), hash);
+        }
+
+        // persist our state
+        if let Err(x) = store.save(&self.state()) {
+            error!(err=?x, instance_name=self.instance_name.as_str(), "Failed to persist state");
+        }
+        Ok(())
     }
 
     #[instrument(skip(self), fields(self.instance_name))]
@@ -238,21 +580,22 @@ impl<M: Middleware> Keeper<M> {
             .get_block_number()
             .await
             .map_err(ContractError::MiddlewareError)?;
-        return self.on_block(block_number).await;
+        return self.on_block(block_number, unix_now()).await;
     }
 
     /// Runs the liquidation business logic for the specified block
     #[instrument(skip(self), fields(self.instance_name))]
-    async fn on_block(&mut self, block_number: U64) -> Result<(), M> {
-        // Get the gas price - TODO: Replace with gas price oracle
-        let gas_price = self
-            .client
-            .get_gas_price()
+    async fn on_block(&mut self, block_number: U64, timestamp: u64) -> Result<(), M> {
+        // Get the base fee and the tips paid in the recent blocks
+        let fees = self
+            .fee_strategy
+            .estimate(self.client.as_ref())
             .await
             .map_err(ContractError::MiddlewareError)?;
 
-        // 1. Check if our transactions have been mined
-        self.liquidator.remove_or_bump().await?;
+        // 1. Check if our transactions have been mined, and whether we may send new ones
+        self.liquidator.remove_or_bump(&fees).await?;
+        self.liquidator.sync_breaker();
 
         // 2. update our dataset with the new block's data
         self.borrowers
@@ -261,25 +604,44 @@ impl<M: Middleware> Keeper<M> {
 
         // 3. trigger the auction for any undercollateralized borrowers
         self.liquidator
-            .start_auctions(self.borrowers.vaults.iter(), gas_price)
+            .start_auctions(self.borrowers.vaults.iter(), &fees)
             .await?;
 
         // 4. try buying the ones which are worth buying
         self.liquidator
-            .buy_opportunities(self.last_block, block_number, gas_price, &mut self.cache)
+            .buy_opportunities(self.last_block, block_number, &fees, timestamp, &mut self.cache)
             .await?;
+
+        // 5. follow the auctions' outcomes, since the last block we processed. They are
+        // only bookkeeping: on failure, the range is retried on the next block
+        let from_block = self.outcomes_from.unwrap_or(self.last_block + 1);
+        match self.liquidator.track_outcomes(from_block, block_number).await {
+            Ok(()) => self.outcomes_from = None,
+            Err(x) => {
+                error!(err = ?x, from_block = from_block.as_u64(), to_block = block_number.as_u64(),
+                    instance_name = self.instance_name.as_str(), "Failed to track the auctions' outcomes");
+                self.outcomes_from = Some(from_block);
+            }
+        }
+
+        // 6. record what our mined bids earned
+        self.liquidator.record_mined_bids(&mut self.cache).await?;
         Ok(())
     }
 
-    fn log<W: Write>(&self, w: W) {
-        serde_json::to_writer(
-            w,
-            &State {
-                auctions: self.liquidator.auctions.clone(),
-                vaults: self.borrowers.vaults.clone(),
-                last_block: self.last_block.as_u64(),
-            },
-        )
-        .unwrap();
+    /// The state to be persisted
+    pub fn state(&self) -> State {
+        State {
+            auctions: self.liquidator.auctions.clone(),
+            vaults: self.borrowers.vaults.clone(),
+            last_block: self.last_block.as_u64(),
+            pending_liquidations: self.liquidator.pending_liquidations.clone(),
+            pending_auctions: self.liquidator.pending_auctions.clone(),
+            pending_cancellations: self.liquidator.pending_cancellations.clone(),
+            pending_bundles: self.liquidator.pending_bundles.clone(),
+            outcomes: self.liquidator.outcomes.clone(),
+            recent_blocks: self.block_history.blocks(),
+            breaker: self.liquidator.breaker.clone(),
+        }
     }
 }
@@ -0,0 +1,355 @@
+//! Profit and loss ledger
+//!
+//! Each of our bids which gets mined is decoded from its receipt: the Witch's
+//! `Bought` event tells how much debt was repaid, and the flash liquidator's
+//! `Transfer` to us how much we earned. Rows are appended to a JSON lines
+//! file, which `ledger` (the binary) turns into per-day or per-ilk CSVs.
+use crate::bindings::VaultIdType;
+
+use ethers::{prelude::*, utils::keccak256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LedgerError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unknown grouping: {0} (expected 'day' or 'ilk')")]
+    UnknownGrouping(String),
+}
+
+/// A mined liquidation of ours
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Timestamp of the block (unix timestamp, seconds)
+    pub timestamp: u64,
+    pub block_number: u64,
+    pub tx_hash: TxHash,
+    /// Hex encoded
+    pub vault_id: String,
+    /// Hex encoded
+    pub ilk_id: String,
+    /// Hex encoded
+    pub base_id: String,
+    /// What we're paid in: the collateral, or the base for flash liquidators
+    /// which sell all of it (hex encoded asset id)
+    pub proceeds_asset: String,
+    /// Proceeds transferred to us (`proceeds_asset` units)
+    pub collateral_received: U256,
+    /// Debt repaid (base units)
+    pub debt_repaid: U256,
+    pub gas_used: U256,
+    pub effective_gas_price: U256,
+    /// `gas_used * effective_gas_price` (wei)
+    pub gas_cost: U256,
+    /// `collateral_received` valued in the base, if the oracle could tell
+    pub proceeds_value: Option<U256>,
+    /// `gas_cost` valued in the base, if the oracle could tell
+    pub gas_cost_value: Option<U256>,
+}
+
+impl LedgerEntry {
+    /// Profit in base units, if both sides could be valued
+    pub fn pnl(&self) -> Option<I256> {
+        Some(I256::from_raw(self.proceeds_value?) - I256::from_raw(self.gas_cost_value?))
+    }
+}
+
+/// What a liquidation receipt tells us
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DecodedLiquidation {
+    /// Sum of the `token` transfers to `recipient`
+    pub collateral_received: U256,
+    /// Sum of the `art` of the Witch's `Bought` events for the vault
+    pub debt_repaid: U256,
+}
+
+/// Decodes the `Bought` events of `witch` for `vault_id`, and the `Transfer`s
+/// of `token` to `recipient`
+pub fn decode_liquidation(
+    logs: &[Log],
+    witch: Address,
+    vault_id: VaultIdType,
+    token: Address,
+    recipient: Address,
+) -> DecodedLiquidation {
+    let transfer = H256::from(keccak256("Transfer(address,address,uint256)"));
+    let bought = H256::from(keccak256("Bought(bytes12,address,uint256,uint256)"));
+    let mut ret = DecodedLiquidation::default();
+    for log in logs {
+        match log.topics.as_slice() {
+            // Transfer(address indexed from, address indexed to, uint256 value)
+            [topic, _, to] if *topic == transfer && log.address == token && log.data.len() == 32 => {
+                if Address::from(*to) == recipient {
+                    ret.collateral_received += U256::from_big_endian(&log.data);
+                }
+            }
+            // Bought(bytes12 indexed vaultId, address indexed buyer, uint256 ink, uint256 art)
+            [topic, vault, _] if *topic == bought && log.address == witch && log.data.len() == 64 => {
+                if vault.as_bytes()[..12] == vault_id {
+                    ret.debt_repaid += U256::from_big_endian(&log.data[32..]);
+                }
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+/// An append-only JSON lines file of `LedgerEntry`s
+#[derive(Clone, Debug)]
+pub struct Ledger {
+    path: PathBuf,
+}
+
+impl Ledger {
+    /// Constructor
+    pub fn new(path: PathBuf) -> Self {
+        Ledger { path }
+    }
+
+    /// Appends `entry` to the ledger
+    pub fn record(&self, entry: &LedgerEntry) -> Result<(), LedgerError> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Reads all of the entries
+    pub fn read(&self) -> Result<Vec<LedgerEntry>, LedgerError> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(x) => x,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(x) => return Err(x.into()),
+        };
+        let mut ret = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                ret.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// How to group the ledger in the PnL report
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Grouping {
+    /// By UTC day
+    Day,
+    Ilk,
+}
+
+impl FromStr for Grouping {
+    type Err = LedgerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Grouping::Day),
+            "ilk" => Ok(Grouping::Ilk),
+            x => Err(LedgerError::UnknownGrouping(x.to_string())),
+        }
+    }
+}
+
+struct PnlRow {
+    liquidations: u64,
+    debt_repaid: U256,
+    gas_cost: U256,
+    pnl: I256,
+    /// Liquidations left out of `pnl` because they couldn't be valued
+    unvalued: u64,
+}
+
+impl Default for PnlRow {
+    fn default() -> Self {
+        PnlRow {
+            liquidations: 0,
+            debt_repaid: U256::zero(),
+            gas_cost: U256::zero(),
+            pnl: I256::zero(),
+            unvalued: 0,
+        }
+    }
+}
+
+/// Writes the PnL of `entries` as CSV, one row per group and base (amounts
+/// in different bases can't be added up)
+pub fn write_pnl_csv<W: Write>(entries: &[LedgerEntry], grouping: Grouping, out: &mut W) -> std::io::Result<()> {
+    let mut rows: BTreeMap<(String, String), PnlRow> = BTreeMap::new();
+    for entry in entries {
+        let key = match grouping {
+            Grouping::Day => utc_date(entry.timestamp),
+            Grouping::Ilk => entry.ilk_id.clone(),
+        };
+        let row = rows.entry((key, entry.base_id.clone())).or_default();
+        row.liquidations += 1;
+        row.debt_repaid += entry.debt_repaid;
+        row.gas_cost += entry.gas_cost;
+        match entry.pnl() {
+            Some(x) => row.pnl = row.pnl + x,
+            None => row.unvalued += 1,
+        }
+    }
+
+    let column = match grouping {
+        Grouping::Day => "day",
+        Grouping::Ilk => "ilk",
+    };
+    writeln!(out, "{},base,liquidations,debt_repaid,gas_cost_wei,pnl,unvalued", column)?;
+    for ((key, base), row) in rows {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            key, base, row.liquidations, row.debt_repaid, row.gas_cost, row.pnl, row.unvalued
+        )?;
+    }
+    Ok(())
+}
+
+/// `YYYY-MM-DD` of a unix timestamp, in UTC
+fn utc_date(timestamp: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (timestamp / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(address: Address, topics: Vec<H256>, data: Vec<u8>) -> Log {
+        Log {
+            address,
+            topics,
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    fn word(x: u64) -> Vec<u8> {
+        let mut ret = [0u8; 32];
+        U256::from(x).to_big_endian(&mut ret);
+        ret.to_vec()
+    }
+
+    #[test]
+    fn decodes_receipts() {
+        let (witch, token, us, them) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+            Address::repeat_byte(4),
+        );
+        let vault_id = [5u8; 12];
+        let mut vault_topic = [0u8; 32];
+        vault_topic[..12].copy_from_slice(&vault_id);
+        let transfer = H256::from(keccak256("Transfer(address,address,uint256)"));
+        let bought = H256::from(keccak256("Bought(bytes12,address,uint256,uint256)"));
+
+        let logs = vec![
+            log(witch, vec![bought, vault_topic.into(), them.into()], [word(7), word(1000)].concat()),
+            // the collateral going to the flash liquidator
+            log(token, vec![transfer, witch.into(), them.into()], word(7)),
+            // what's left of it, to us
+            log(token, vec![transfer, them.into(), us.into()], word(2)),
+            // some other token
+            log(Address::repeat_byte(9), vec![transfer, them.into(), us.into()], word(100)),
+        ];
+        assert_eq!(
+            decode_liquidation(&logs, witch, vault_id, token, us),
+            DecodedLiquidation {
+                collateral_received: 2.into(),
+                debt_repaid: 1000.into(),
+            }
+        );
+        assert_eq!(decode_liquidation(&logs, witch, [6u8; 12], token, us).debt_repaid, U256::zero());
+    }
+
+    fn entry(timestamp: u64, ilk_id: &str, proceeds_value: Option<u64>, gas_cost_value: u64) -> LedgerEntry {
+        LedgerEntry {
+            timestamp,
+            block_number: 1,
+            tx_hash: TxHash::zero(),
+            vault_id: "00".repeat(12),
+            ilk_id: ilk_id.to_string(),
+            base_id: "303100000000".to_string(),
+            proceeds_asset: ilk_id.to_string(),
+            collateral_received: 1.into(),
+            debt_repaid: 100.into(),
+            gas_used: 10.into(),
+            effective_gas_price: 3.into(),
+            gas_cost: 30.into(),
+            proceeds_value: proceeds_value.map(U256::from),
+            gas_cost_value: Some(gas_cost_value.into()),
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_entries() {
+        let path = std::env::temp_dir().join(format!("yield-liquidator-{}-ledger.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ledger = Ledger::new(path.clone());
+        assert!(ledger.read().unwrap().is_empty());
+        let entries = vec![entry(0, "303000000000", Some(50), 20), entry(1, "303000000000", None, 20)];
+        for x in &entries {
+            ledger.record(x).unwrap();
+        }
+        assert_eq!(ledger.read().unwrap(), entries);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn exports_pnl() {
+        let entries = vec![
+            entry(1_633_046_400, "303000000000", Some(50), 20), // 2021-10-01
+            entry(1_633_132_799, "303200000000", Some(10), 20), // 2021-10-01, a loss
+            entry(1_633_132_800, "303000000000", None, 20),     // 2021-10-02, unvalued
+        ];
+
+        let mut out = vec![];
+        write_pnl_csv(&entries, Grouping::Day, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "day,base,liquidations,debt_repaid,gas_cost_wei,pnl,unvalued\n\
+             2021-10-01,303100000000,2,200,60,20,0\n\
+             2021-10-02,303100000000,1,100,30,0,1\n"
+        );
+
+        let mut out = vec![];
+        write_pnl_csv(&entries, Grouping::Ilk, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ilk,base,liquidations,debt_repaid,gas_cost_wei,pnl,unvalued\n\
+             303000000000,303100000000,2,200,60,30,1\n\
+             303200000000,303100000000,1,100,30,-10,0\n"
+        );
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(utc_date(1_633_046_400), "2021-10-01");
+    }
+}
@@ -1,10 +1,31 @@
+pub mod aggregator;
+pub mod backfill;
 pub mod bindings;
 pub mod borrowers;
+pub mod breaker;
 pub mod cache;
+pub mod cancel;
 pub mod escalator;
+pub mod failover;
+pub mod fees;
+pub mod health;
+pub mod journal;
 pub mod keeper;
+pub mod ledger;
 pub mod liquidations;
+pub mod metrics;
+pub mod outcomes;
+pub mod profit;
+pub mod relay;
+pub mod reorg;
+pub mod router_daemon;
+pub mod routes;
+pub mod schedule;
+pub mod server;
+pub mod store;
+pub mod strategy;
 pub mod swap_router;
+pub mod uniswap;
 
 use ethers::prelude::*;
 use std::collections::HashMap;
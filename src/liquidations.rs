@@ -3,19 +3,34 @@
 //! This module is responsible for triggering and participating in a Auction's
 //! dutch auction
 use crate::{
-    bindings::{Cauldron, Witch, VaultIdType, FlashLiquidator, BaseIdType, IlkIdType},
+    bindings::{
+        Cauldron, Witch, VaultIdType, FlashLiquidator, BaseIdType, IlkIdType, AssetIdType,
+        IMulticall2, IMulticall2Call, IERC20, IOracle,
+    },
     borrowers::{Vault},
-    escalator::GeometricGasPrice,
-    merge, Result, cache::ImmutableCache, swap_router::SwapRouter,
+    breaker::{CircuitBreaker, Trip, BREAKER},
+    cancel::{cancellation, CancelPolicy, CancelReason},
+    fees::{is_replacement, FeeStrategy, Fees},
+    health::unix_now,
+    merge, metrics, Result, cache::ImmutableCache, swap_router::SwapRouter,
+    journal::{Journal, JournalEntry, SimulatedOutcome},
+    ledger::{decode_liquidation, Ledger, LedgerEntry},
+    outcomes::{AuctionOutcome, OutcomeTracker},
+    relay::{BundleRelay, PendingBundle},
+    strategy::Strategy,
+    routes::{CalldataBuilder, FlashRouteConfig},
+    schedule::{offer_for_profit, offer_reaches_at, ScheduledBuy},
+    profit::{asset_id_to_bytes32, decode_balance, ProfitEstimate, ETH_ID},
 };
 
 use ethers_core::types::transaction::eip2718::TypedTransaction;
 
 use ethers::{
+    abi::Detokenize,
     prelude::*,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt, ops::Mul, sync::Arc, time::{Instant, SystemTime}, convert::TryInto};
+use std::{collections::{HashMap, HashSet}, fmt, ops::Mul, sync::Arc, convert::TryInto};
 use tracing::{debug, debug_span, error, info, trace, warn, instrument};
 
 pub type AuctionMap = HashMap<VaultIdType, bool>;
@@ -25,9 +40,12 @@ use std::ops::Div;
 
 #[derive(Clone)]
 pub struct Liquidator<M> {
+    client: Arc<M>,
     cauldron: Cauldron<M>,
     liquidator: Witch<M>,
     flash_liquidator: FlashLiquidator<M>,
+    /// Flash liquidators for the ilks which can't be sold by `flash_liquidator`
+    ilk_flash_liquidators: HashMap<IlkIdType, (FlashLiquidator<M>, CalldataBuilder)>,
 
     /// The currently active auctions
     pub auctions: AuctionMap,
@@ -35,35 +53,104 @@ pub struct Liquidator<M> {
     /// We use multicall to batch together calls and have reduced stress on
     /// our RPC endpoint
     multicall: Multicall<M>,
+    /// Used to simulate liquidations: unlike `multicall`, it tolerates failing calls
+    multicall2: IMulticall2<M>,
 
     // uniswap swap router
     swap_router: SwapRouter,
 
-    /// The minimum ratio (collateral/debt) to trigger liquidation
-    min_ratio: u16,
+    /// When to buy and how much gas to use, per base and ilk
+    strategy: Strategy,
 
-    // extra gas to use for txs, as percent of estimated gas cost
-    gas_boost: u16,
+    /// Buys which are prepared ahead of time, waiting for the offer to be good enough
+    scheduled_buys: HashMap<VaultIdType, ScheduledBuy>,
+    /// How long before a buy is due to prepare it (seconds)
+    prepare_ahead: u64,
 
-    // buy an auction when this percentage of collateral is released
-    target_collateral_offer: u16,
+    /// Minimum profit (in base units, after gas) for a buy to go ahead.
+    /// Bases which are not listed only need to break even
+    base_to_min_profit: HashMap<BaseIdType, u128>,
 
-    pending_liquidations: HashMap<VaultIdType, PendingTransaction>,
-    pending_auctions: HashMap<VaultIdType, PendingTransaction>,
-    gas_escalator: GeometricGasPrice,
+    /// Transactions which were broadcast but not mined yet
+    pub pending_liquidations: PendingTransactionMap,
+    pub pending_auctions: PendingTransactionMap,
+    /// Self-transfers replacing stuck transactions, by nonce
+    pub pending_cancellations: CancellationMap,
+    fee_strategy: FeeStrategy,
     bump_gas_delay: u64,
+    cancel_policy: CancelPolicy,
+    /// Stops new transactions from being sent when we spend or fail too much
+    pub breaker: CircuitBreaker,
+
+    /// If set, bids are sent to this relay as bundles instead of going through the mempool
+    relay: Option<BundleRelay>,
+    /// Bids sent to the relay which weren't mined yet. The node doesn't know
+    /// about them: their nonces are reserved by `next_nonce`
+    pub pending_bundles: BundleMap,
+    /// The nonce of our next transaction, read from the node once and then
+    /// counted here: the node may not have seen our last transactions yet,
+    /// or not even have received them with several endpoints
+    nonce: Option<U256>,
+
+    /// Dry-run mode: transactions are simulated and journaled instead of being sent
+    journal: Option<Journal>,
+    /// Dry-run mode: the auctions we would have started and bought, so that
+    /// they aren't reconsidered every block
+    virtual_liquidations: HashSet<VaultIdType>,
+    virtually_bought: HashSet<VaultIdType>,
+
+    /// Who started and bought the auctions, and at what offer
+    pub outcomes: OutcomeTracker,
+
+    /// Where our mined bids are recorded, with what they earned
+    ledger: Option<Ledger>,
+    /// Our mined bids, waiting to be recorded in the ledger
+    mined_bids: Vec<(VaultIdType, TransactionReceipt)>,
 
     instance_name: String
 }
 
-/// Tx / Hash/ Submitted at time
-type PendingTransaction = (TypedTransaction, TxHash, Instant);
+/// A transaction which was broadcast, but not mined yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingTransaction {
+    pub tx: TypedTransaction,
+    /// The hash of the latest broadcast version of the transaction
+    pub hash: TxHash,
+    /// When the transaction was first submitted (unix timestamp, seconds)
+    pub submitted_at: u64,
+    pub vault_id: VaultIdType,
+    /// How many times its fees were bumped
+    #[serde(default)]
+    pub bumps: u32,
+    /// When its fees were last bumped (unix timestamp, seconds)
+    #[serde(default)]
+    pub last_bumped_at: Option<u64>,
+}
+
+impl PendingTransaction {
+    /// When the current version of the transaction was broadcast: bumps are
+    /// `bump_gas_delay` apart, while cancellation deadlines count from `submitted_at`
+    pub fn last_sent_at(&self) -> u64 {
+        self.last_bumped_at.unwrap_or(self.submitted_at)
+    }
+}
+
+pub type PendingTransactionMap = HashMap<VaultIdType, PendingTransaction>;
+pub type CancellationMap = HashMap<U256, PendingTransaction>;
+pub type BundleMap = HashMap<VaultIdType, PendingBundle>;
+
 
 /// An initiated auction
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Auction {
     /// The start time of the auction
     started: u32,
+    /// How long it takes for the offer to reach 100%, seconds
+    duration: u32,
+    /// Percentage of the collateral offered when the auction starts
+    initial_offer_pct: u16,
+    /// The collateral in the vault
+    ink: u128,
     under_auction: bool,
     /// The debt which can be repaid
     debt: u128,
@@ -73,7 +160,8 @@ pub struct Auction {
     ilk_id: IlkIdType,
 
     ratio_pct: u16,
-    collateral_offer_is_good_enough: bool,
+    /// Percentage of the collateral currently offered
+    current_offer: u16,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -99,75 +187,291 @@ impl<M: Middleware> Liquidator<M> {
         cauldron: Address,
         liquidator: Address,
         flashloan: Address,
-        multicall: Option<Address>,
-        min_ratio: u16,
-        gas_boost: u16,
-        target_collateral_offer: u16,
+        ilk_flash_liquidators: HashMap<IlkIdType, FlashRouteConfig>,
+        multicall: Address,
+        strategy: Strategy,
+        base_to_min_profit: HashMap<BaseIdType, u128>,
+        prepare_ahead: u64,
         client: Arc<M>,
         auctions: AuctionMap,
-        gas_escalator: GeometricGasPrice,
+        pending_liquidations: PendingTransactionMap,
+        pending_auctions: PendingTransactionMap,
+        pending_cancellations: CancellationMap,
+        fee_strategy: FeeStrategy,
         bump_gas_delay: u64,
+        cancel_policy: CancelPolicy,
+        breaker: CircuitBreaker,
+        journal: Option<Journal>,
+        relay: Option<BundleRelay>,
+        pending_bundles: BundleMap,
+        outcomes: OutcomeTracker,
+        ledger: Option<Ledger>,
         instance_name: String
     ) -> Self {
-        let multicall = Multicall::new(client.clone(), multicall)
+        let multicall2 = IMulticall2::new(multicall, client.clone());
+        let multicall = Multicall::new(client.clone(), Some(multicall))
             .await
             .expect("could not initialize multicall");
 
         Self {
+            client: client.clone(),
             cauldron: Cauldron::new(cauldron, client.clone()),
             liquidator: Witch::new(liquidator, client.clone()),
             flash_liquidator: FlashLiquidator::new(flashloan, client.clone()),
+            ilk_flash_liquidators: ilk_flash_liquidators
+                .into_iter()
+                .map(|(ilk_id, x)| (ilk_id, (FlashLiquidator::new(x.flash_liquidator, client.clone()), x.calldata)))
+                .collect(),
             multicall,
+            multicall2,
             swap_router,
-            min_ratio,
-            gas_boost,
-            target_collateral_offer,
+            strategy,
+            base_to_min_profit,
+            scheduled_buys: HashMap::new(),
+            prepare_ahead,
             auctions,
 
-            pending_liquidations: HashMap::new(),
-            pending_auctions: HashMap::new(),
-            gas_escalator,
+            pending_liquidations,
+            pending_auctions,
+            pending_cancellations,
+            fee_strategy,
             bump_gas_delay,
+            cancel_policy,
+            breaker,
+            relay,
+            pending_bundles,
+            nonce: None,
+            journal,
+            virtual_liquidations: HashSet::new(),
+            virtually_bought: HashSet::new(),
+            outcomes,
+            ledger,
+            mined_bids: vec![],
             instance_name
         }
     }
 
+    /// Reconciles the transactions which were pending when we last stopped
+    /// with the chain: forgets the ones which were mined (or whose nonce was
+    /// used by another transaction) and rebroadcasts the others.
+    ///
+    /// Must be called before sending any new transaction. Does nothing in
+    /// dry-run mode
+    #[instrument(skip(self), fields(self.instance_name))]
+    pub async fn reconcile_pending(&mut self) -> Result<(), M> {
+        if self.is_dry_run() {
+            return Ok(());
+        }
+        let client = self.liquidator.client();
+        let sender = client.default_sender().expect("client must have a sender");
+        let nonce = client
+            .get_transaction_count(sender, Some(BlockNumber::Latest.into()))
+            .await
+            .map_err(ContractError::MiddlewareError)?;
+        info!(
+            nonce = %nonce,
+            liquidations = self.pending_liquidations.len(),
+            auctions = self.pending_auctions.len(),
+            cancellations = self.pending_cancellations.len(),
+            instance_name = self.instance_name.as_str(),
+            "Reconciling pending transactions"
+        );
+
+        Liquidator::reconcile_pending_inner(client, nonce, &mut self.pending_liquidations,
+            "liquidations", self.instance_name.as_ref()).await?;
+        let bought = Liquidator::reconcile_pending_inner(client, nonce, &mut self.pending_auctions,
+            "auctions", self.instance_name.as_ref()).await?;
+        Liquidator::reconcile_pending_inner(client, nonce, &mut self.pending_cancellations,
+            "cancellations", self.instance_name.as_ref()).await?;
+        // our bids still go to the ledger
+        self.mined_bids.extend(bought);
+        Ok(())
+    }
+
+    async fn reconcile_pending_inner<K: Clone + Eq + ::std::hash::Hash>(
+        client: &M,
+        nonce: U256,
+        pending_txs: &mut HashMap<K, PendingTransaction>,
+        tx_type: &str,
+        instance_name: &str,
+        ) -> Result<Vec<(K, TransactionReceipt)>, M> {
+        let mut mined = vec![];
+        for (key, pending_tx) in pending_txs.clone().into_iter() {
+            let vault_id = pending_tx.vault_id;
+            let receipt = client
+                .get_transaction_receipt(pending_tx.hash)
+                .await
+                .map_err(ContractError::MiddlewareError)?;
+            if let Some(receipt) = receipt {
+                let status = if receipt.status == Some(1.into()) {
+                    "success"
+                } else {
+                    "fail"
+                };
+                info!(tx_hash = ?pending_tx.hash, vault_id = ?hex::encode(vault_id), status, tx_type,
+                    instance_name, "confirmed while we were away");
+                pending_txs.remove(&key);
+                mined.push((key, receipt));
+                continue;
+            }
+            let mut tx = pending_tx.tx.clone();
+            let tx_nonce = match Liquidator::known_nonce(client, &mut tx, pending_tx.hash).await? {
+                Some(x) => x,
+                None => {
+                    warn!(tx_hash = ?pending_tx.hash, vault_id = ?hex::encode(vault_id), tx_type, instance_name,
+                        "unknown nonce and transaction not found: forgetting it");
+                    pending_txs.remove(&key);
+                    continue;
+                }
+            };
+            if tx_nonce < nonce {
+                // a different version of this transaction (or something else entirely) was mined
+                info!(tx_hash = ?pending_tx.hash, vault_id = ?hex::encode(vault_id), tx_nonce = %tx_nonce,
+                    tx_type, instance_name, "nonce already used: forgetting transaction");
+                pending_txs.remove(&key);
+                continue;
+            }
+            if let Some(x) = pending_txs.get_mut(&key) {
+                x.tx = tx.clone();
+            }
+            // still pending: make sure the node knows about it. It will be bumped as usual
+            match client.send_transaction(tx, None).await {
+                Ok(tx) => {
+                    info!(tx_hash = ?*tx, vault_id = ?hex::encode(vault_id), tx_type, instance_name,
+                        "rebroadcast pending transaction");
+                    if let Some(x) = pending_txs.get_mut(&key) {
+                        x.hash = *tx;
+                    }
+                }
+                Err(x) => {
+                    // most likely the node still has it in its mempool
+                    warn!(tx_hash = ?pending_tx.hash, vault_id = ?hex::encode(vault_id), err = ?x,
+                        tx_type, instance_name, "failed to rebroadcast pending transaction");
+                }
+            }
+        }
+        Ok(mined)
+    }
+
+    /// The nonce of `tx`, looked up from the node (and filled in) if it wasn't
+    /// recorded. `None` if the node doesn't know the transaction: it must not
+    /// be sent again then, as the nonce manager would give it a fresh nonce and
+    /// the bid could be mined twice
+    async fn known_nonce(client: &M, tx: &mut TypedTransaction, hash: TxHash) -> Result<Option<U256>, M> {
+        if let Some(x) = tx.nonce() {
+            return Ok(Some(*x));
+        }
+        let sent = client.get_transaction(hash).await.map_err(ContractError::MiddlewareError)?;
+        Ok(sent.map(|sent| {
+            tx.set_nonce(sent.nonce);
+            sent.nonce
+        }))
+    }
+
     /// Checks if any transactions which have been submitted are mined, removes
-    /// them if they were successful, otherwise bumps their gas price
+    /// them if they were successful, otherwise bumps their fees. Transactions
+    /// which can't be bumped anymore are cancelled. Our mined bids are queued
+    /// for the ledger, and all our mined transactions are fed to the breaker
     #[instrument(skip(self), fields(self.instance_name))]
-    pub async fn remove_or_bump(&mut self) -> Result<(), M> {
-        let now = Instant::now();
+    pub async fn remove_or_bump(&mut self, fees: &Fees) -> Result<(), M> {
+        // whatever is pending was sent by a real run
+        if self.is_dry_run() {
+            return Ok(());
+        }
+        let now = unix_now();
 
         let liquidator_client = self.liquidator.client();
         // Check all the pending liquidations
-        Liquidator::remove_or_bump_inner(now, liquidator_client, &self.gas_escalator,
+        let started = Liquidator::remove_or_bump_inner(now, liquidator_client, &self.fee_strategy, fees,
+            &self.cancel_policy, &mut self.pending_cancellations,
             &mut self.pending_liquidations, "liquidations",
             self.instance_name.as_ref(),
             self.bump_gas_delay).await?;
-        Liquidator::remove_or_bump_inner(now, liquidator_client, &self.gas_escalator,
+        let bought = Liquidator::remove_or_bump_inner(now, liquidator_client, &self.fee_strategy, fees,
+            &self.cancel_policy, &mut self.pending_cancellations,
             &mut self.pending_auctions, "auctions",
             self.instance_name.as_ref(),
             self.bump_gas_delay).await?;
+        let cancelled = Liquidator::track_cancellations(now, liquidator_client, &self.fee_strategy, fees,
+            &mut self.pending_cancellations,
+            self.instance_name.as_ref(),
+            self.bump_gas_delay).await?;
+
+        for (vault_id, receipt) in started.iter().chain(bought.iter()) {
+            self.on_mined(now, Some(*vault_id), receipt);
+        }
+        for receipt in cancelled.iter() {
+            self.on_mined(now, None, receipt);
+        }
+        // reverted bids cost gas too
+        self.mined_bids.extend(bought);
 
         Ok(())
     }
 
+    /// Whether transactions are simulated instead of being sent
+    pub fn is_dry_run(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Feeds one of our mined transactions to the breaker; `vault_id` is `None`
+    /// for cancellations
+    fn on_mined(&mut self, now: u64, vault_id: Option<VaultIdType>, receipt: &TransactionReceipt) {
+        let gas_cost = receipt.gas_used.unwrap_or_default() * receipt.effective_gas_price.unwrap_or_default();
+        let trip = match vault_id {
+            Some(vault_id) => self.breaker.record(now, vault_id, gas_cost, receipt.status == Some(1.into())),
+            None => self.breaker.spend(now, gas_cost),
+        };
+        if let Some(trip) = trip {
+            Self::alert(trip, receipt.transaction_hash, &self.instance_name);
+        }
+    }
+
+    /// Raises the alarm when the breaker trips
+    fn alert(trip: Trip, tx_hash: TxHash, instance_name: &str) {
+        let vault_id = match trip {
+            Trip::VaultReverts(vault_id) => Some(hex::encode(vault_id)),
+            _ => None,
+        };
+        error!(reason = %trip, ?vault_id, ?tx_hash, instance_name,
+            "Circuit breaker tripped: not sending new transactions until it's reset");
+        metrics::BREAKER_TRIPS.with_label_values(&[&trip.to_string()]).inc();
+        metrics::BREAKER_PAUSED.set(1);
+    }
+
+    /// Resumes the breaker if the operator asked to, and publishes its status
+    /// to the HTTP server. Called once per block
+    pub fn sync_breaker(&mut self) {
+        let now = unix_now();
+        if BREAKER.take_resume_request() {
+            info!(instance_name = self.instance_name.as_str(), "Circuit breaker reset by the operator");
+            self.breaker.resume();
+        }
+        let paused = self.breaker.is_paused(now);
+        metrics::BREAKER_PAUSED.set(paused as i64);
+        BREAKER.set_status(self.breaker.status(now));
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn remove_or_bump_inner<K: Clone + Eq + ::std::hash::Hash + std::fmt::Debug>(
-        now: Instant,
+        now: u64,
         client: &M,
-        gas_escalator: &GeometricGasPrice,
+        fee_strategy: &FeeStrategy,
+        fees: &Fees,
+        cancel_policy: &CancelPolicy,
+        cancellations: &mut CancellationMap,
         pending_txs: &mut HashMap<K, PendingTransaction>,
         tx_type: &str,
         instance_name: &str,
         bump_gas_delay: u64
-        ) -> Result<(), M> {
-        for (addr, (pending_tx_wrapper, tx_hash, instant)) in pending_txs.clone().into_iter() {
-            let pending_tx = match pending_tx_wrapper {
-                TypedTransaction::Eip1559(x) => x,
-                _ => panic!("Non-Eip1559 transactions are not supported yet")
-            };
-
-            // get the receipt and check inclusion, or bump its gas price
+        ) -> Result<Vec<(K, TransactionReceipt)>, M> {
+        let mut mined = vec![];
+        for (addr, pending) in pending_txs.clone().into_iter() {
+            let PendingTransaction { tx: pending_tx_wrapper, hash: tx_hash, submitted_at, bumps, .. } = pending.clone();
+            let last_sent_at = pending.last_sent_at();
+            let current_fees = Fees::of(&pending_tx_wrapper, fees.base_fee);
+
+            // get the receipt and check inclusion, or bump its fees
             let receipt = client
                 .get_transaction_receipt(tx_hash)
                 .await
@@ -175,95 +479,509 @@ impl<M: Middleware> Liquidator<M> {
             if let Some(receipt) = receipt {
                 pending_txs.remove(&addr);
                 let status = if receipt.status == Some(1.into()) {
+                    metrics::TRANSACTIONS.with_label_values(&[tx_type, "confirmed"]).inc();
                     "success"
                 } else {
+                    metrics::TRANSACTIONS.with_label_values(&[tx_type, "failed"]).inc();
                     "fail"
                 };
                 info!(tx_hash = ?tx_hash, gas_used = %receipt.gas_used.unwrap_or_default(), user = ?addr,
                     status = status, tx_type, instance_name, "confirmed");
+                mined.push((addr, receipt));
             } else {
-                let time_since = now.duration_since(instant).as_secs();
-                if time_since > bump_gas_delay {
+                let time_since = now.saturating_sub(submitted_at);
+                let time_since_sent = now.saturating_sub(last_sent_at);
+                if time_since_sent > bump_gas_delay {
                     info!(tx_hash = ?tx_hash, "Bumping gas");
-                    // Get the new gas price based on how much time passed since the
-                    // tx was last broadcast
-                    let new_gas_price = gas_escalator.get_gas_price(
-                        pending_tx.max_fee_per_gas.expect("max_fee_per_gas price must be set"),
-                        now.duration_since(instant).as_secs(),
-                    );
+                    // Raise the tip (or the legacy gas price), and follow the current
+                    // base fee with the max fee, both by at least the replacement minimum
+                    let new_fees = fee_strategy.bump(current_fees, fees.base_fee);
+
+                    let cancel_reason = cancel_policy.reason(bumps, time_since).or_else(|| {
+                        if is_replacement(&current_fees, &new_fees) {
+                            None
+                        } else {
+                            Some(CancelReason::MaxPrice)
+                        }
+                    });
+                    if let Some(reason) = cancel_reason {
+                        pending_txs.remove(&addr);
+                        Liquidator::cancel(client, fee_strategy, fees, pending, reason, cancellations,
+                            now, tx_type, instance_name).await;
+                        continue;
+                    }
 
                     let replacement_tx = pending_txs
                         .get_mut(&addr)
                         .expect("tx will always be found since we're iterating over the map");
-
-                    // bump the gas price
-                    if let TypedTransaction::Eip1559(x) = &mut replacement_tx.0 {
-                        // it should be reversed:
-                        // - max_fee_per_gas has to be constant
-                        // - max_priority_fee_per_gas needs to be bumped
-                        x.max_fee_per_gas = Some(new_gas_price);
-                        x.max_priority_fee_per_gas = Some(U256::from(2000000000)); // 2 gwei
-                    } else {
-                        panic!("Non-Eip1559 transactions are not supported yet");
+                    if Liquidator::known_nonce(client, &mut replacement_tx.tx, tx_hash).await?.is_none() {
+                        warn!(tx_hash = ?tx_hash, user = ?addr, tx_type, instance_name,
+                            "Unknown nonce: not bumping the transaction");
+                        continue;
                     }
+                    new_fees.apply(&mut replacement_tx.tx);
 
                     // rebroadcast
                     match client
-                        .send_transaction(replacement_tx.0.clone(), None)
+                        .send_transaction(replacement_tx.tx.clone(), None)
                         .await {
                             Ok(tx) => {
-                                replacement_tx.1 = *tx;
+                                replacement_tx.hash = *tx;
+                                replacement_tx.bumps += 1;
+                                replacement_tx.last_bumped_at = Some(now);
+                                metrics::GAS_BUMPS.with_label_values(&[tx_type]).inc();
                             },
                             Err(x) => {
-                                error!(tx=?replacement_tx, err=?x, "Failed to replace transaction: dropping it");
+                                error!(tx=?replacement_tx, err=?x, "Failed to replace transaction: cancelling it");
                                 pending_txs.remove(&addr);
+                                Liquidator::cancel(client, fee_strategy, fees, pending, CancelReason::RebroadcastFailed,
+                                    cancellations, now, tx_type, instance_name).await;
+                                continue;
                             }
                         }
 
-                    info!(tx_hash = ?tx_hash, new_gas_price = %new_gas_price, user = ?addr,
+                    info!(tx_hash = ?tx_hash, max_fee_per_gas = %new_fees.max_fee_per_gas,
+                        max_priority_fee_per_gas = %new_fees.max_priority_fee_per_gas, user = ?addr,
                         tx_type, instance_name, "Bumping gas: done");
                     } else {
-                        info!(tx_hash = ?tx_hash, time_since, bump_gas_delay, instance_name, "Bumping gas: too early");
+                        info!(tx_hash = ?tx_hash, time_since_sent, bump_gas_delay, instance_name, "Bumping gas: too early");
                     }
             }
         }
 
+        Ok(mined)
+    }
+
+    /// Replaces `stuck` with a zero-value transfer to ourselves, so that its
+    /// nonce doesn't block our later transactions
+    #[allow(clippy::too_many_arguments)]
+    async fn cancel(
+        client: &M,
+        fee_strategy: &FeeStrategy,
+        fees: &Fees,
+        stuck: PendingTransaction,
+        reason: CancelReason,
+        cancellations: &mut CancellationMap,
+        now: u64,
+        tx_type: &str,
+        instance_name: &str,
+    ) {
+        let nonce = match stuck.tx.nonce() {
+            Some(x) => *x,
+            None => {
+                error!(tx_hash = ?stuck.hash, reason = %reason, tx_type, instance_name,
+                    "Unknown nonce: can't cancel the transaction, dropping it");
+                metrics::TRANSACTIONS.with_label_values(&[tx_type, "dropped"]).inc();
+                return;
+            }
+        };
+        let sender = client.default_sender().expect("client must have a sender");
+        let cancel_fees = fee_strategy.cancellation_fees(Fees::of(&stuck.tx, fees.base_fee), fees.base_fee);
+        let tx = cancellation(sender, nonce, &cancel_fees);
+        match client.send_transaction(tx.clone(), None).await {
+            Ok(hash) => {
+                info!(tx_hash = ?stuck.hash, cancellation_hash = ?*hash, nonce = %nonce, reason = %reason,
+                    max_fee_per_gas = %cancel_fees.max_fee_per_gas, tx_type, instance_name,
+                    "Cancelling stuck transaction");
+                metrics::TRANSACTIONS.with_label_values(&[tx_type, "cancelled"]).inc();
+                metrics::TRANSACTIONS.with_label_values(&["cancellations", "submitted"]).inc();
+                cancellations.insert(nonce, PendingTransaction {
+                    tx,
+                    hash: *hash,
+                    submitted_at: now,
+                    vault_id: stuck.vault_id,
+                    bumps: 0,
+                    last_bumped_at: None,
+                });
+            }
+            Err(x) => {
+                // most likely, the stuck transaction was mined in the meantime
+                error!(tx_hash = ?stuck.hash, nonce = %nonce, reason = %reason, err = ?x, tx_type, instance_name,
+                    "Failed to cancel transaction: dropping it");
+                metrics::TRANSACTIONS.with_label_values(&[tx_type, "dropped"]).inc();
+            }
+        }
+    }
+
+    /// Follows up on our cancellations until they're mined, or their nonce is
+    /// used by the transaction they were replacing. They're bumped like any
+    /// other transaction, but never cancelled, and may go over `max_price` up
+    /// to the strategy's cap for cancellations. Returns the receipts of the mined ones
+    async fn track_cancellations(
+        now: u64,
+        client: &M,
+        fee_strategy: &FeeStrategy,
+        fees: &Fees,
+        cancellations: &mut CancellationMap,
+        instance_name: &str,
+        bump_gas_delay: u64,
+    ) -> Result<Vec<TransactionReceipt>, M> {
+        let mut mined = vec![];
+        if cancellations.is_empty() {
+            return Ok(mined);
+        }
+        let sender = client.default_sender().expect("client must have a sender");
+        let mined_nonce = client
+            .get_transaction_count(sender, Some(BlockNumber::Latest.into()))
+            .await
+            .map_err(ContractError::MiddlewareError)?;
+        for (nonce, pending) in cancellations.clone().into_iter() {
+            let receipt = client
+                .get_transaction_receipt(pending.hash)
+                .await
+                .map_err(ContractError::MiddlewareError)?;
+            if let Some(receipt) = receipt {
+                info!(tx_hash = ?pending.hash, nonce = %nonce, instance_name, "Cancellation confirmed");
+                metrics::TRANSACTIONS.with_label_values(&["cancellations", "confirmed"]).inc();
+                cancellations.remove(&nonce);
+                mined.push(receipt);
+                continue;
+            }
+            if nonce < mined_nonce {
+                info!(tx_hash = ?pending.hash, nonce = %nonce, instance_name,
+                    "Nonce used by the cancelled transaction: forgetting the cancellation");
+                metrics::TRANSACTIONS.with_label_values(&["cancellations", "dropped"]).inc();
+                cancellations.remove(&nonce);
+                continue;
+            }
+            if now.saturating_sub(pending.last_sent_at()) <= bump_gas_delay {
+                continue;
+            }
+
+            let current_fees = Fees::of(&pending.tx, fees.base_fee);
+            let new_fees = fee_strategy.cancellation_fees(current_fees, fees.base_fee);
+            if !is_replacement(&current_fees, &new_fees) {
+                debug!(tx_hash = ?pending.hash, nonce = %nonce, max_fee_per_gas = %current_fees.max_fee_per_gas,
+                    instance_name, "Cancellation fees are capped: waiting");
+                continue;
+            }
+            let mut tx = pending.tx.clone();
+            new_fees.apply(&mut tx);
+            match client.send_transaction(tx.clone(), None).await {
+                Ok(hash) => {
+                    info!(tx_hash = ?*hash, nonce = %nonce, max_fee_per_gas = %new_fees.max_fee_per_gas,
+                        instance_name, "Bumped cancellation");
+                    metrics::GAS_BUMPS.with_label_values(&["cancellations"]).inc();
+                    cancellations.insert(nonce, PendingTransaction {
+                        tx,
+                        hash: *hash,
+                        bumps: pending.bumps + 1,
+                        last_bumped_at: Some(now),
+                        ..pending
+                    });
+                }
+                Err(x) => {
+                    // keep it: it will be forgotten once its nonce is used
+                    warn!(tx_hash = ?pending.hash, nonce = %nonce, err = ?x, instance_name,
+                        "Failed to bump cancellation");
+                }
+            }
+        }
+        Ok(mined)
+    }
+
+    /// The nonce of our next transaction, bundle or not: our own count, unless
+    /// our bundles (which the node doesn't know about) took it. The count is
+    /// read from the node again once none of our transactions is pending, so
+    /// that a transaction which was dropped doesn't leave a gap
+    async fn next_nonce(&mut self) -> Result<U256, M> {
+        let idle = self.pending_liquidations.is_empty()
+            && self.pending_auctions.is_empty()
+            && self.pending_cancellations.is_empty()
+            && self.pending_bundles.is_empty();
+        let counted = match self.nonce {
+            Some(x) if !idle => x,
+            _ => {
+                let sender = self.client.default_sender().expect("client must have a sender");
+                self.client
+                    .get_transaction_count(sender, Some(BlockNumber::Pending.into()))
+                    .await
+                    .map_err(ContractError::MiddlewareError)?
+            }
+        };
+        let reserved = self.pending_bundles
+            .values()
+            .filter_map(|x| x.tx.nonce())
+            .map(|x| *x + 1)
+            .max()
+            .unwrap_or_default();
+        let nonce = std::cmp::max(counted, reserved);
+        self.nonce = Some(nonce);
+        Ok(nonce)
+    }
+
+    /// Counts `nonce` as used, or not: after a failed send, the count is read
+    /// from the node again
+    fn on_sent(&mut self, nonce: U256, sent: bool) {
+        self.nonce = if sent { Some(nonce + 1) } else { None };
+    }
+
+    /// Builds the record of a transaction we just broadcast.
+    ///
+    /// Transactions sent without a nonce (i.e. assigned by the middleware) get
+    /// it looked up: we need it to replace the transaction when bumping its
+    /// gas price, and to reconcile it with the chain after a restart
+    async fn pending_transaction(&self, mut tx: TypedTransaction, hash: TxHash, now: u64,
+        vault_id: VaultIdType) -> PendingTransaction {
+        if tx.nonce().is_none() {
+            match self.liquidator.client().get_transaction(hash).await {
+                Ok(Some(sent)) => {
+                    tx.set_nonce(sent.nonce);
+                }
+                Ok(None) => {
+                    warn!(tx_hash = ?hash, "Sent transaction not found: can't record its nonce");
+                }
+                Err(x) => {
+                    warn!(tx_hash = ?hash, err = ?x, "Failed to fetch sent transaction: can't record its nonce");
+                }
+            }
+        }
+        PendingTransaction { tx, hash, submitted_at: now, vault_id, bumps: 0, last_bumped_at: None }
+    }
+
+    /// Returns the ids of the vaults whose auctions were started in the given block range
+    pub async fn new_auctions(&self, from_block: U64, to_block: U64) -> Result<Vec<VaultIdType>, M> {
+        Ok(self
+            .liquidator
+            .auctioned_filter()
+            .from_block(from_block)
+            .to_block(to_block)
+            .query()
+            .await?
+            .iter()
+            .map(|x| x.vault_id)
+            .collect::<Vec<_>>())
+    }
+
+    /// Follows the lifecycle of the auctions in the given block range (both
+    /// included): who started them, who bought them and at what offer, and
+    /// when they ended
+    #[instrument(skip(self), fields(self.instance_name))]
+    pub async fn track_outcomes(&mut self, from_block: U64, to_block: U64) -> Result<(), M> {
+        let us = self.client.default_sender();
+        let mut timestamps = HashMap::new();
+
+        let started = self
+            .liquidator
+            .auctioned_filter()
+            .from_block(from_block)
+            .to_block(to_block)
+            .query_with_meta()
+            .await?;
+        for (event, meta) in started {
+            let starter = self.sender_of(meta.transaction_hash).await?;
+            self.register_auction(event.vault_id, event.start.as_u64(), starter).await?;
+        }
+
+        let bought = self
+            .liquidator
+            .bought_filter()
+            .from_block(from_block)
+            .to_block(to_block)
+            .query_with_meta()
+            .await?;
+        for (event, meta) in bought {
+            let block_number = meta.block_number.as_u64();
+            if !self.outcomes.is_open(&event.vault_id) && self.auctions.contains_key(&event.vault_id) {
+                // started before we were watching: look the start up as of the block before.
+                // Nodes which don't keep the historical state can't tell, so the start stays unknown
+                let start = self
+                    .liquidator
+                    .auctions(event.vault_id)
+                    .block(BlockNumber::Number((block_number - 1).into()))
+                    .call()
+                    .await;
+                match start {
+                    Ok((_, start)) if start != 0 => {
+                        self.register_auction(event.vault_id, u64::from(start), None).await?;
+                    }
+                    Ok(_) => {}
+                    Err(x) => {
+                        warn!(vault_id = ?hex::encode(event.vault_id), block_number, err = ?x,
+                            instance_name = self.instance_name.as_str(), "Could not read when the auction started");
+                    }
+                }
+            }
+            let buyer = self.sender_of(meta.transaction_hash).await?.unwrap_or(event.buyer);
+            let timestamp = match timestamps.get(&block_number) {
+                Some(x) => *x,
+                None => {
+                    let timestamp = self
+                        .client
+                        .get_block(block_number)
+                        .await
+                        .map_err(ContractError::MiddlewareError)?
+                        .map(|x| x.timestamp.as_u64())
+                        .unwrap_or_default();
+                    timestamps.insert(block_number, timestamp);
+                    timestamp
+                }
+            };
+            let ours = Some(buyer) == us;
+            if let Some(purchase) = self.outcomes.on_bought(event.vault_id, buyer, event.ink, event.art,
+                block_number, timestamp, ours, meta.transaction_hash, meta.log_index) {
+                info!(vault_id = ?hex::encode(event.vault_id), buyer = ?buyer, ours, elapsed = purchase.elapsed,
+                    offer_pct = purchase.offer_pct, instance_name = self.instance_name.as_str(), "Auction bought");
+            }
+        }
+
+        // the Witch gives the vault back to its owner once all of the debt is repaid
+        let given = self
+            .cauldron
+            .vault_given_filter()
+            .from_block(from_block)
+            .to_block(to_block)
+            .query()
+            .await?;
+        let witch = self.liquidator.address();
+        for event in given.iter().filter(|x| x.receiver != witch) {
+            if let Some(outcome) = self.outcomes.on_ended(&event.vault_id) {
+                info!(vault_id = ?hex::encode(event.vault_id), ilk_id = ?hex::encode(outcome.ilk_id),
+                    won = outcome.won(), purchases = outcome.purchases.len(),
+                    instance_name = self.instance_name.as_str(), "Auction ended");
+                let stats = &self.outcomes.stats()[&outcome.ilk_id];
+                let ilk = hex::encode(outcome.ilk_id);
+                if let Some(x) = stats.win_rate() {
+                    metrics::AUCTION_WIN_RATE.with_label_values(&[&ilk]).set(x);
+                }
+                if let Some(x) = stats.avg_competitor_offer_pct() {
+                    metrics::COMPETITOR_OFFER.with_label_values(&[&ilk]).set(x);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes our mined bids from their receipts, and records them in the ledger.
+    /// A bid whose chain data can't be read stays queued for the next block
+    #[instrument(skip(self, cache), fields(self.instance_name))]
+    pub async fn record_mined_bids(&mut self, cache: &mut ImmutableCache<M>) -> Result<(), M> {
+        let ledger = match &self.ledger {
+            Some(x) => x.clone(),
+            None => {
+                self.mined_bids.clear();
+                return Ok(());
+            }
+        };
+        // one at a time: if we fail to read the chain, the rest waits for the next block
+        while let Some((vault_id, receipt)) = self.mined_bids.first().cloned() {
+            let entry = match self.ledger_entry(vault_id, &receipt, cache).await {
+                Ok(x) => x,
+                Err(x) => {
+                    warn!(tx_hash = ?receipt.transaction_hash, vault_id = ?hex::encode(vault_id), err = ?x,
+                        instance_name = self.instance_name.as_str(), "Failed to read a mined bid, retrying on the next block");
+                    break;
+                }
+            };
+            self.mined_bids.remove(0);
+            info!(tx_hash = ?entry.tx_hash, vault_id = %entry.vault_id, collateral_received = %entry.collateral_received,
+                debt_repaid = %entry.debt_repaid, gas_cost = %entry.gas_cost,
+                pnl = ?entry.pnl().map(|x| x.to_string()), reverted = receipt.status != Some(1.into()),
+                instance_name = self.instance_name.as_str(), "Liquidation recorded");
+            if let Err(x) = ledger.record(&entry) {
+                error!(err=?x, "Failed to write to the ledger");
+            }
+        }
         Ok(())
     }
 
-    /// Sends a bid for any of the liquidation auctions.
+    /// The ledger row of one of our mined bids. A reverted bid has no proceeds,
+    /// but its gas was spent all the same
+    async fn ledger_entry(&self, vault_id: VaultIdType, receipt: &TransactionReceipt,
+        cache: &mut ImmutableCache<M>) -> Result<LedgerEntry, M> {
+        let us = self.client.default_sender().expect("client must have a sender");
+        let (_, series_id, ilk_id) = self.cauldron.vaults(vault_id).call().await?;
+        let base_id = cache.get_or_fetch_base_id(series_id).await?;
+        let (_, builder) = self.flash_route(ilk_id);
+        let proceeds_asset = if builder.pays_profit_in_base() { base_id } else { ilk_id };
+        let token = cache.get_or_fetch_asset_address(proceeds_asset).await?;
+        // a reverted transaction has no logs
+        let decoded = decode_liquidation(&receipt.logs, self.liquidator.address(), vault_id, token, us);
+
+        let block_number = receipt.block_number.unwrap_or_default();
+        let timestamp = self
+            .client
+            .get_block(block_number)
+            .await
+            .map_err(ContractError::MiddlewareError)?
+            .map(|x| x.timestamp.as_u64())
+            .unwrap_or_default();
+        let gas_used = receipt.gas_used.unwrap_or_default();
+        let effective_gas_price = receipt.effective_gas_price.unwrap_or_default();
+        let gas_cost = gas_used * effective_gas_price;
+        Ok(LedgerEntry {
+            timestamp,
+            block_number: block_number.as_u64(),
+            tx_hash: receipt.transaction_hash,
+            vault_id: hex::encode(vault_id),
+            ilk_id: hex::encode(ilk_id),
+            base_id: hex::encode(base_id),
+            proceeds_asset: hex::encode(proceeds_asset),
+            collateral_received: decoded.collateral_received,
+            debt_repaid: decoded.debt_repaid,
+            gas_used,
+            effective_gas_price,
+            gas_cost,
+            proceeds_value: self.value_in_base(proceeds_asset, base_id, decoded.collateral_received).await.ok(),
+            gas_cost_value: self.value_in_base(ETH_ID, base_id, gas_cost).await.ok(),
+        })
+    }
+
+    /// Starts following the auction of `vault_id`
+    async fn register_auction(&mut self, vault_id: VaultIdType, started: u64, starter: Option<Address>) -> Result<(), M> {
+        let (_, _, ilk_id) = self.cauldron.vaults(vault_id).call().await?;
+        let (duration, initial_offer) = self.liquidator.ilks(ilk_id).call().await?;
+        self.outcomes.on_auctioned(vault_id, AuctionOutcome {
+            ilk_id,
+            started,
+            starter,
+            duration: u64::from(duration),
+            initial_offer_pct: initial_offer / 10u64.pow(16),
+            purchases: vec![],
+        });
+        debug!(vault_id = ?hex::encode(vault_id), starter = ?starter, "Following auction");
+        Ok(())
+    }
+
+    /// The account which sent the transaction `hash`
+    async fn sender_of(&self, hash: TxHash) -> Result<Option<Address>, M> {
+        Ok(self
+            .client
+            .get_transaction(hash)
+            .await
+            .map_err(ContractError::MiddlewareError)?
+            .map(|x| x.from))
+    }
+
+    /// Sends a bid for any of the liquidation auctions. `now` is the timestamp of
+    /// the latest block
     #[instrument(skip(self, from_block, to_block, cache), fields(self.instance_name))]
     pub async fn buy_opportunities(
         &mut self,
         from_block: U64,
         to_block: U64,
-        gas_price: U256,
+        fees: &Fees,
+        now: u64,
         cache: &mut ImmutableCache<M>
     ) -> Result<(), M> {
         let all_auctions = {
-            let liquidations = self
-                .liquidator
-                .auctioned_filter()
-                .from_block(from_block)
-                .to_block(to_block)
-                .query()
-                .await?;
-            let new_liquidations = liquidations
-                .iter()
-                .map(|x| x.vault_id).collect::<Vec<_>>();
+            let new_liquidations = self.new_auctions(from_block, to_block).await?;
             merge(new_liquidations, &self.auctions)
         };
+        self.retarget_bundles(to_block, fees).await?;
 
         info!(count=all_auctions.len(), instance_name=self.instance_name.as_str(), "Liquidations collected");
         for vault_id in all_auctions {
             self.auctions.insert(vault_id, true);
 
             trace!(vault_id=?hex::encode(vault_id), "Buying");
-            match self.buy(vault_id, Instant::now(), gas_price, cache).await {
+            match self.buy(vault_id, to_block, now, fees, cache).await {
                 Ok(is_still_valid) => {
                     if !is_still_valid {
                         info!(vault_id=?hex::encode(vault_id), instance_name=self.instance_name.as_str(), "Removing no longer valid auction");
                         self.auctions.remove(&vault_id);
+                        self.virtually_bought.remove(&vault_id);
+                        self.scheduled_buys.remove(&vault_id);
                     }        
                 }
                 Err(x) => {
@@ -272,6 +990,7 @@ impl<M: Middleware> Liquidator<M> {
                 }
             }
         }
+        metrics::ACTIVE_AUCTIONS.set(self.auctions.len() as i64);
 
         Ok(())
     }
@@ -279,20 +998,32 @@ impl<M: Middleware> Liquidator<M> {
     /// Tries to buy the collateral associated with a user's liquidation auction
     /// via a flashloan funded by Uniswap.
     ///
+    /// Buys are prepared up to `prepare_ahead` seconds before the auction becomes
+    /// worth buying, and sent as soon as `now` (the block timestamp) gets there.
+    ///
     /// Returns
     ///  - Result<false>: auction is no longer valid, we need to forget about it
     ///  - Result<true>: auction is still valid
     #[instrument(skip(self, cache), fields(self.instance_name))]
-    async fn buy(&mut self, vault_id: VaultIdType, now: Instant, gas_price: U256,
+    async fn buy(&mut self, vault_id: VaultIdType, block_number: U64, now: u64, fees: &Fees,
         cache: &mut ImmutableCache<M>) -> Result<bool, M> {
         // only iterate over users that do not have active auctions
         if let Some(pending_tx) = self.pending_auctions.get(&vault_id) {
-            trace!(tx_hash = ?pending_tx.1, vault_id=?vault_id, "bid not confirmed yet");
+            trace!(tx_hash = ?pending_tx.hash, vault_id=?vault_id, "bid not confirmed yet");
+            return Ok(true);
+        }
+        if let Some(bundle) = self.pending_bundles.get(&vault_id) {
+            trace!(tx_hash = ?bundle.hash, vault_id=?vault_id, "bundle not mined yet");
+            return Ok(true);
+        }
+        // `now` is the block's timestamp, the breaker works with ours
+        if self.breaker.is_paused(unix_now()) {
+            trace!(vault_id=?vault_id, "circuit breaker tripped, not buying");
             return Ok(true);
         }
 
         // Get the vault's info
-        let auction = match self.get_auction(vault_id, cache).await {
+        let auction = match self.get_auction(vault_id, now, cache).await {
             Ok(Some(x)) => x,
             Ok(None) => {
                 // auction is not valid
@@ -315,25 +1046,53 @@ impl<M: Middleware> Liquidator<M> {
             return Ok(true);
         }
 
-        let mut buy: bool = false;
-        if auction.ratio_pct <= self.min_ratio {
-            info!(vault_id=?hex::encode(vault_id), auction=?auction,
-                ratio=auction.ratio_pct, ratio_threshold=self.min_ratio,
-                instance_name=self.instance_name.as_str(),
-                "Ratio threshold is reached, buying");
-            buy = true;
+        if self.virtually_bought.contains(&vault_id) {
+            trace!(vault_id=?hex::encode(vault_id), "already bought (dry run)");
+            return Ok(true);
         }
-        if auction.collateral_offer_is_good_enough {
-            info!(vault_id=?hex::encode(vault_id), auction=?auction,
-                ratio=auction.ratio_pct, ratio_threshold=self.min_ratio,
-                instance_name=self.instance_name.as_str(),
-                "Collateral offer is good enough, buying");
-            buy = true;
+
+        let params = self.strategy.resolve(auction.base_id, auction.ilk_id);
+        // the auction is worth buying right away if the ratio is low enough,
+        // otherwise once the offer reaches the target
+        let trigger_at = if auction.ratio_pct <= params.min_ratio {
+            now
+        } else {
+            offer_reaches_at(
+                u64::from(auction.started),
+                u64::from(auction.duration),
+                u64::from(auction.initial_offer_pct),
+                params.target_collateral_offer,
+            )
+        };
+        if let Some(scheduled) = self.scheduled_buys.get(&vault_id) {
+            if scheduled.debt != auction.debt || now.saturating_sub(scheduled.prepared_at) > self.prepare_ahead {
+                debug!(vault_id=?hex::encode(vault_id), scheduled=?scheduled, "Prepared buy is stale");
+                self.scheduled_buys.remove(&vault_id);
+            }
         }
-        if !buy {
-            debug!(vault_id=?hex::encode(vault_id), auction=?auction, "Not time to buy yet");
+        // an earlier simulation may have told us to wait for a bigger offer to make a profit
+        let due = std::cmp::max(
+            trigger_at,
+            self.scheduled_buys.get(&vault_id).map(|x| x.due).unwrap_or_default(),
+        );
+
+        if now < due {
+            if due - now <= self.prepare_ahead && !self.scheduled_buys.contains_key(&vault_id) {
+                if let Some(scheduled) = self.prepare_buy(vault_id, &auction, due, now, cache).await? {
+                    info!(vault_id=?hex::encode(vault_id), due, gas=?scheduled.gas_estimation,
+                        instance_name=self.instance_name.as_str(),
+                        "Buy scheduled");
+                    self.scheduled_buys.insert(vault_id, scheduled);
+                }
+            }
+            debug!(vault_id=?hex::encode(vault_id), auction=?auction, params=?params, due, "Not time to buy yet");
             return Ok(true);
         }
+        info!(vault_id=?hex::encode(vault_id), auction=?auction,
+            ratio=auction.ratio_pct, ratio_threshold=params.min_ratio,
+            target_collateral_offer=params.target_collateral_offer, due,
+            instance_name=self.instance_name.as_str(),
+            "Auction is worth buying");
 
         if self.auctions.insert(vault_id, true).is_none() {
             debug!(vault_id=?vault_id, auction=?auction, "new auction");
@@ -341,28 +1100,104 @@ impl<M: Middleware> Liquidator<M> {
         let span = debug_span!("buying", vault_id=?vault_id, auction=?auction);
         let _enter = span.enter();
 
-        let maybe_calldata = self.swap_router.build_swap_exact_out(
-            cache.get_or_fetch_asset_address(auction.ilk_id).await?, // in: collateral
-            cache.get_or_fetch_asset_address(auction.base_id).await?, // out:debt
-            U256::from(auction.debt)
-        ).await;
-        if let Err(x) = maybe_calldata {
-            warn!(vault_id=?hex::encode(vault_id), err=?x, "failed to generate swap calldata - will try later");
-            return Ok(true);
-        }
-        let swap_calldata = maybe_calldata.unwrap().calldata;
-
-        let raw_call = self.flash_liquidator.liquidate(vault_id, swap_calldata)
+        let scheduled = match self.scheduled_buys.remove(&vault_id) {
+            Some(x) => x,
+            None => match self.prepare_buy(vault_id, &auction, due, now, cache).await? {
+                Some(x) => x,
+                None => return Ok(true),
+            },
+        };
+        let (_, builder) = self.flash_route(auction.ilk_id);
+        let raw_call = FlashLiquidator::new(scheduled.flash_liquidator, self.client.clone())
+            .liquidate(vault_id, scheduled.swap_calldata.clone())
             // explicitly set 'from' field because we're about to call `estimate_gas`
             // If there's no `from` set, the estimated transaction is sent from 0x0 and reverts (tokens can't be transferred there)
             //
             // Also, it's safe to unwrap() client().default_sender(): if it's not set, we're in trouble anyways
-            .from(self.flash_liquidator.client().default_sender().unwrap());
-        let gas_estimation = raw_call.estimate_gas().await?;
-        let gas = gas_estimation.mul(U256::from(self.gas_boost + 100)).div(100);
-        let call = raw_call
-            .gas_price(gas_price)
-            .gas(gas);
+            .from(self.client.default_sender().unwrap());
+        let gas_estimation = match scheduled.gas_estimation {
+            Some(x) => x,
+            None => raw_call.estimate_gas().await?,
+        };
+        let gas = gas_estimation.mul(U256::from(params.gas_boost + 100)).div(100);
+
+        let proceeds_asset = if builder.pays_profit_in_base() { auction.base_id } else { auction.ilk_id };
+        let estimate = match self.simulate_profit(vault_id, &auction, &raw_call, proceeds_asset, gas_estimation * fees.expected_gas_price(), cache).await {
+            Ok(x) => x,
+            Err(x) => {
+                warn!(vault_id=?hex::encode(vault_id), err=?x, "failed to simulate the liquidation - will try later");
+                return Ok(true);
+            }
+        };
+        let min_profit = self.base_to_min_profit.get(&auction.base_id).copied().unwrap_or_default();
+        if !estimate.is_profitable(min_profit) {
+            // the profit grows with the offer: wait until it's big enough
+            let shortfall = (I256::from_raw(U256::from(min_profit)) - estimate.profit()).into_raw();
+            let collateral_value = self.value_in_base(auction.ilk_id, auction.base_id, U256::from(auction.ink)).await;
+            let profit_due = match collateral_value.ok()
+                .and_then(|x| offer_for_profit(auction.current_offer, shortfall, x)) {
+                Some(offer) if offer > auction.current_offer => offer_reaches_at(
+                    u64::from(auction.started),
+                    u64::from(auction.duration),
+                    u64::from(auction.initial_offer_pct),
+                    offer,
+                ),
+                // can't tell, check again on the next block
+                _ => now,
+            };
+            info!(vault_id=?hex::encode(vault_id), estimate=?estimate, profit=%estimate.profit(), min_profit,
+                profit_due, instance_name=self.instance_name.as_str(),
+                "Not profitable enough, waiting");
+            self.scheduled_buys.insert(vault_id, ScheduledBuy { due: profit_due, gas_estimation: Some(gas_estimation), ..scheduled });
+            return Ok(true);
+        }
+        info!(vault_id=?hex::encode(vault_id), estimate=?estimate, profit=%estimate.profit(), min_profit,
+            instance_name=self.instance_name.as_str(),
+            "Liquidation is profitable");
+
+        let mut call = raw_call.gas(gas);
+        fees.apply(&mut call.tx);
+
+        if let Some(journal) = &self.journal {
+            let profit = estimate.profit();
+            if self.dry_run(journal, TxType::Auction, vault_id, &call, fees.expected_gas_price(), Some(profit)).await {
+                self.virtually_bought.insert(vault_id);
+            }
+            return Ok(true);
+        }
+
+        // bundles and public transactions mustn't get the same nonce
+        let nonce = self.next_nonce().await?;
+        call.tx.set_nonce(nonce);
+
+        if let Some(relay) = &self.relay {
+            let bundle = match relay.sign(call.tx.clone(), block_number + 1).await {
+                Ok(x) => x,
+                Err(x) => {
+                    error!(vault_id = ?hex::encode(vault_id), err=?x, "Failed to sign the bundle");
+                    return Ok(true);
+                }
+            };
+            match relay.send_bundle(&bundle).await {
+                Ok(()) => {
+                    info!(tx_hash = ?bundle.hash,
+                        vault_id = ?hex::encode(vault_id),
+                        target_block = bundle.target_block.as_u64(),
+                        instance_name=self.instance_name.as_str(),
+                        gas=?gas,
+                        "Submitted buy order to the relay");
+                    metrics::TRANSACTIONS.with_label_values(&["auctions", "submitted"]).inc();
+                    self.pending_bundles.insert(vault_id, bundle);
+                    // `relay` borrows `self`: see `on_sent`
+                    self.nonce = Some(nonce + 1);
+                }
+                Err(x) => {
+                    error!(vault_id = ?hex::encode(vault_id), err=?x, "Relay error");
+                    self.nonce = None;
+                }
+            }
+            return Ok(true);
+        }
 
         let tx = call.tx.clone();
 
@@ -374,11 +1209,15 @@ impl<M: Middleware> Liquidator<M> {
                     instance_name=self.instance_name.as_str(),
                     gas=?gas,
                     "Submitted buy order");
+                metrics::TRANSACTIONS.with_label_values(&["auctions", "submitted"]).inc();
+                self.on_sent(nonce, true);
+                let pending_tx = self.pending_transaction(tx, *hash, now, vault_id).await;
                 self.pending_auctions
                     .entry(vault_id)
-                    .or_insert((tx, *hash, now));
+                    .or_insert(pending_tx);
             }
             Err(err) => {
+                self.on_sent(nonce, false);
                 let err = err.to_string();
                 error!("Buy error: {}; data: {:?}", err, call.calldata());
             }
@@ -387,17 +1226,117 @@ impl<M: Middleware> Liquidator<M> {
         Ok(true)
     }
 
+    /// Follows up on the bundles sent to the relay: forgets the ones which were
+    /// mined, were replaced, or whose auction ended, and sends the others again
+    /// for the block after `block_number`.
+    ///
+    /// The nonce of a bundle whose auction ended is filled with a cancellation
+    /// if later transactions of ours are waiting for it
+    pub async fn retarget_bundles(&mut self, block_number: U64, fees: &Fees) -> Result<(), M> {
+        let relay = match &self.relay {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if self.pending_bundles.is_empty() {
+            return Ok(());
+        }
+        let sender = self.client.default_sender().unwrap();
+        let nonce = self.client
+            .get_transaction_count(sender, None)
+            .await
+            .map_err(ContractError::MiddlewareError)?;
+
+        let now = unix_now();
+        let mut done = vec![];
+        let mut mined = vec![];
+        let mut dropped = vec![];
+        for (vault_id, bundle) in self.pending_bundles.iter_mut() {
+            let receipt = self.client
+                .get_transaction_receipt(bundle.hash)
+                .await
+                .map_err(ContractError::MiddlewareError)?;
+            if let Some(receipt) = receipt {
+                let status = if receipt.status == Some(1u64.into()) { "confirmed" } else { "failed" };
+                info!(tx_hash = ?bundle.hash, vault_id = ?hex::encode(vault_id), status,
+                    instance_name=self.instance_name.as_str(), "Bundle mined");
+                metrics::TRANSACTIONS.with_label_values(&["auctions", status]).inc();
+                mined.push((*vault_id, receipt));
+                done.push(*vault_id);
+                continue;
+            }
+            if bundle.tx.nonce().map(|x| *x < nonce).unwrap_or(false) {
+                info!(tx_hash = ?bundle.hash, vault_id = ?hex::encode(vault_id),
+                    instance_name=self.instance_name.as_str(), "Bundle nonce was used by another transaction");
+                metrics::TRANSACTIONS.with_label_values(&["auctions", "dropped"]).inc();
+                done.push(*vault_id);
+                continue;
+            }
+            let (owner, _) = self.liquidator.auctions(*vault_id).call().await?;
+            if owner == Address::zero() {
+                info!(tx_hash = ?bundle.hash, vault_id = ?hex::encode(vault_id),
+                    instance_name=self.instance_name.as_str(), "Auction ended, dropping the bundle");
+                metrics::TRANSACTIONS.with_label_values(&["auctions", "dropped"]).inc();
+                done.push(*vault_id);
+                dropped.push((*vault_id, bundle.clone()));
+                continue;
+            }
+            if bundle.target_block <= block_number {
+                bundle.target_block = block_number + 1;
+                match relay.send_bundle(bundle).await {
+                    Ok(()) => debug!(tx_hash = ?bundle.hash, target_block = bundle.target_block.as_u64(), "Bundle re-targeted"),
+                    Err(x) => warn!(tx_hash = ?bundle.hash, err=?x, "Failed to re-target the bundle"),
+                }
+            }
+        }
+        for vault_id in done {
+            self.pending_bundles.remove(&vault_id);
+        }
+        for (vault_id, bundle) in dropped {
+            let nonce = match bundle.tx.nonce() {
+                Some(x) => *x,
+                None => continue,
+            };
+            let waiting = self.pending_bundles.values().map(|x| &x.tx)
+                .chain(self.pending_liquidations.values().map(|x| &x.tx))
+                .chain(self.pending_auctions.values().map(|x| &x.tx))
+                .any(|x| x.nonce().map(|x| *x > nonce).unwrap_or(false));
+            if !waiting {
+                // the next transaction takes the nonce
+                continue;
+            }
+            let stuck = PendingTransaction {
+                tx: bundle.tx,
+                hash: bundle.hash,
+                submitted_at: now,
+                vault_id,
+                bumps: 0,
+                last_bumped_at: None,
+            };
+            Liquidator::cancel(self.liquidator.client(), &self.fee_strategy, fees, stuck, CancelReason::BundleDropped,
+                &mut self.pending_cancellations, now, "auctions", self.instance_name.as_str()).await;
+        }
+        for (vault_id, receipt) in mined {
+            self.on_mined(now, Some(vault_id), &receipt);
+            self.mined_bids.push((vault_id, receipt));
+        }
+        Ok(())
+    }
+
     /// Triggers liquidations for any vulnerable positions which were fetched from the
     /// controller
     #[instrument(skip(self, vaults), fields(self.instance_name))]
     pub async fn start_auctions(
         &mut self,
         vaults: impl Iterator<Item = (&VaultIdType, &Vault)>,
-        gas_price: U256,
+        fees: &Fees,
     ) -> Result<(), M> {
         debug!("checking for undercollateralized positions...");
 
-        let now = Instant::now();
+        let now = unix_now();
+        if self.breaker.is_paused(now) {
+            debug!(instance_name=self.instance_name.as_str(), "circuit breaker tripped, not starting auctions");
+            return Ok(());
+        }
 
         for (vault_id, vault) in vaults {
             if !vault.is_initialized {
@@ -406,34 +1345,59 @@ impl<M: Middleware> Liquidator<M> {
             }
             // only iterate over vaults that do not have pending liquidations
             if let Some(pending_tx) = self.pending_liquidations.get(vault_id) {
-                trace!(tx_hash = ?pending_tx.1, vault_id = ?hex::encode(vault_id), "liquidation not confirmed yet");
+                trace!(tx_hash = ?pending_tx.hash, vault_id = ?hex::encode(vault_id), "liquidation not confirmed yet");
                 continue;
             }
 
+            if vault.is_collateralized || vault.under_auction {
+                self.virtual_liquidations.remove(vault_id);
+            }
+
             if !vault.is_collateralized {
                 if vault.under_auction {
                     debug!(vault_id = ?hex::encode(vault_id), details = ?vault, "found vault under auction, ignoring it");
                     continue;
                 }
                 info!(
-                    vault_id = ?hex::encode(vault_id), details = ?vault, gas_price=?gas_price,
+                    vault_id = ?hex::encode(vault_id), details = ?vault, max_fee_per_gas=?fees.max_fee_per_gas,
                     instance_name=self.instance_name.as_str(),
                     "found an undercollateralized vault. starting an auction",
                 );
 
+                if let Some(journal) = &self.journal {
+                    if self.virtual_liquidations.contains(vault_id) {
+                        trace!(vault_id = ?hex::encode(vault_id), "auction already started (dry run)");
+                        continue;
+                    }
+                    let mut call = self.liquidator.auction(*vault_id)
+                        .from(self.client.default_sender().unwrap());
+                    fees.apply(&mut call.tx);
+                    if self.dry_run(journal, TxType::Liquidation, *vault_id, &call, fees.expected_gas_price(), None).await {
+                        self.virtual_liquidations.insert(*vault_id);
+                    }
+                    continue;
+                }
+
                 // Send the tx and track it
-                let call = self.liquidator.auction(*vault_id).gas_price(gas_price);
+                let mut call = self.liquidator.auction(*vault_id);
+                fees.apply(&mut call.tx);
+                let nonce = self.next_nonce().await?;
+                call.tx.set_nonce(nonce);
                 let tx = call.tx.clone();
                 match call.send().await {
                     Ok(tx_hash) => {
                         info!(tx_hash = ?tx_hash,
                             vault_id = ?hex::encode(vault_id), 
                             instance_name=self.instance_name.as_str(), "Submitted liquidation");
+                        metrics::TRANSACTIONS.with_label_values(&["liquidations", "submitted"]).inc();
+                        self.on_sent(nonce, true);
+                        let pending_tx = self.pending_transaction(tx, *tx_hash, now, *vault_id).await;
                         self.pending_liquidations
                             .entry(*vault_id)
-                            .or_insert((tx, *tx_hash, now));
+                            .or_insert(pending_tx);
                     }
                     Err(x) => {
+                        self.on_sent(nonce, false);
                         warn!(
                             vault_id = ?hex::encode(vault_id), 
                             error=?x,
@@ -448,6 +1412,197 @@ impl<M: Middleware> Liquidator<M> {
         Ok(())
     }
 
+    /// Builds the swap calldata for buying `auction` at `due`, and estimates the gas
+    /// if the auction can already be bought.
+    ///
+    /// `None` if the swap calldata couldn't be built
+    async fn prepare_buy(
+        &self,
+        vault_id: VaultIdType,
+        auction: &Auction,
+        due: u64,
+        now: u64,
+        cache: &mut ImmutableCache<M>,
+    ) -> Result<Option<ScheduledBuy>, M> {
+        let (flash_liquidator, builder) = self.flash_route(auction.ilk_id);
+        let collateral = cache.get_or_fetch_asset_address(auction.ilk_id).await?;
+        let base = cache.get_or_fetch_asset_address(auction.base_id).await?;
+        let weth = match builder {
+            CalldataBuilder::Uniswap => Address::zero(), // not needed
+            CalldataBuilder::WstethCurve => flash_liquidator.weth().call().await?,
+        };
+        if !builder.supports_base(base, weth) {
+            warn!(vault_id=?hex::encode(vault_id), base=?base, builder=?builder,
+                "The flash liquidator can't buy auctions for this base");
+            return Ok(None);
+        }
+        let swap_calldata = match builder.uniswap_leg(collateral, base) {
+            Some((token_in, token_out)) => {
+                let maybe_calldata = self.swap_router
+                    .for_flash_liquidator(flash_liquidator.address())
+                    .build_swap_exact_out(token_in, token_out, U256::from(auction.debt))
+                    .await;
+                match maybe_calldata {
+                    Ok(x) => {
+                        debug!(vault_id=?hex::encode(vault_id), amount_in=%x.amount_in, "swap route selected");
+                        x.calldata
+                    }
+                    Err(x) => {
+                        warn!(vault_id=?hex::encode(vault_id), err=?x, "failed to generate swap calldata - will try later");
+                        return Ok(None);
+                    }
+                }
+            }
+            None => vec![],
+        };
+        debug!(vault_id=?hex::encode(vault_id), flash_liquidator=?flash_liquidator.address(), builder=?builder,
+            "Flash liquidator selected");
+
+        // fails if the offer isn't big enough yet to pay for the swap
+        let gas_estimation = flash_liquidator
+            .liquidate(vault_id, swap_calldata.clone())
+            .from(self.client.default_sender().unwrap())
+            .estimate_gas()
+            .await
+            .ok();
+
+        Ok(Some(ScheduledBuy {
+            due,
+            prepared_at: now,
+            debt: auction.debt,
+            flash_liquidator: flash_liquidator.address(),
+            swap_calldata,
+            gas_estimation,
+        }))
+    }
+
+    /// The flash liquidator to buy `ilk_id` auctions through, and how to build its calldata
+    fn flash_route(&self, ilk_id: IlkIdType) -> (FlashLiquidator<M>, CalldataBuilder) {
+        match self.ilk_flash_liquidators.get(&ilk_id) {
+            Some(x) => x.clone(),
+            None => (self.flash_liquidator.clone(), CalldataBuilder::Uniswap),
+        }
+    }
+
+    /// Dry-run replacement for `call.send()`: simulates `call` with an `eth_call`
+    /// and journals it. Returns whether the simulation succeeded
+    async fn dry_run<D: Detokenize>(
+        &self,
+        journal: &Journal,
+        tx_type: TxType,
+        vault_id: VaultIdType,
+        call: &ContractCall<M, D>,
+        gas_price: U256,
+        profit: Option<I256>,
+    ) -> bool {
+        let gas_estimate = call.estimate_gas().await.ok();
+        let outcome = match call.call().await {
+            Ok(_) => SimulatedOutcome::Success,
+            Err(x) => SimulatedOutcome::Reverted(x.to_string()),
+        };
+        info!(vault_id = ?hex::encode(vault_id), tx_type = %tx_type, outcome = ?outcome, gas = ?gas_estimate,
+            instance_name=self.instance_name.as_str(),
+            "Simulated transaction (dry run)");
+        let label = match tx_type {
+            TxType::Auction => "auctions",
+            TxType::Liquidation => "liquidations",
+        };
+        metrics::TRANSACTIONS.with_label_values(&[label, "simulated"]).inc();
+
+        let to = match call.tx.to() {
+            Some(NameOrAddress::Address(x)) => *x,
+            _ => Address::zero(),
+        };
+        let entry = JournalEntry {
+            timestamp: unix_now(),
+            tx_type: tx_type.to_string(),
+            vault_id: hex::encode(vault_id),
+            to,
+            calldata: call.calldata().unwrap_or_default(),
+            gas_estimate,
+            gas_price,
+            outcome,
+            profit: profit.map(|x| x.to_string()),
+        };
+        if let Err(x) = journal.record(&entry) {
+            error!(err=?x, "Failed to write to the journal");
+        }
+        entry.outcome == SimulatedOutcome::Success
+    }
+
+    /// Simulates `call` (a `FlashLiquidator.liquidate`) with a Multicall2
+    /// `eth_call`, measuring the collateral which would be left over, and values
+    /// it and `gas_cost` in the auction's base.
+    ///
+    /// `liquidate` sends the proceeds (`proceeds_asset`: the collateral, or the base
+    /// for some flash liquidators) to its caller, so the simulation reads the
+    /// multicall's own balance before and after it
+    async fn simulate_profit(
+        &self,
+        vault_id: VaultIdType,
+        auction: &Auction,
+        call: &ContractCall<M, ()>,
+        proceeds_asset: AssetIdType,
+        gas_cost: U256,
+        cache: &mut ImmutableCache<M>,
+    ) -> Result<ProfitEstimate, M> {
+        let collateral = IERC20::new(
+            cache.get_or_fetch_asset_address(proceeds_asset).await?,
+            self.client.clone(),
+        );
+        let balance_call = IMulticall2Call {
+            target: collateral.address(),
+            call_data: collateral.balance_of(self.multicall2.address()).calldata().unwrap().to_vec(),
+        };
+        let calls = vec![
+            balance_call.clone(),
+            IMulticall2Call {
+                target: match call.tx.to() {
+                    Some(NameOrAddress::Address(x)) => *x,
+                    _ => self.flash_liquidator.address(),
+                },
+                call_data: call.calldata().unwrap().to_vec(),
+            },
+            balance_call,
+        ];
+        let response = self.multicall2.try_aggregate(false, calls).call().await?;
+        let (balance_before, balance_after) = match response.as_slice() {
+            [(true, before), (true, _), (true, after)] => (decode_balance(before), decode_balance(after)),
+            _ => {
+                debug!(vault_id=?hex::encode(vault_id), response=?response, "Liquidation simulation failed");
+                return Err(ContractError::ConstructorError {});
+            }
+        };
+        let collateral_received = match (balance_before, balance_after) {
+            (Some(before), Some(after)) => after.saturating_sub(before),
+            _ => return Err(ContractError::ConstructorError {}),
+        };
+
+        Ok(ProfitEstimate {
+            collateral_received,
+            collateral_value: self.value_in_base(proceeds_asset, auction.base_id, collateral_received).await?,
+            gas_cost,
+            gas_cost_value: self.value_in_base(ETH_ID, auction.base_id, gas_cost).await?,
+        })
+    }
+
+    /// Values `amount` of `asset_id` in `base_id`, using the Cauldron's spot oracle
+    async fn value_in_base(&self, asset_id: AssetIdType, base_id: BaseIdType, amount: U256) -> Result<U256, M> {
+        if asset_id == base_id || amount.is_zero() {
+            return Ok(amount);
+        }
+        let (oracle, _) = self.cauldron.spot_oracles(base_id, asset_id).call().await?;
+        if oracle == Address::zero() {
+            warn!(asset_id=?hex::encode(asset_id), base_id=?hex::encode(base_id), "No spot oracle");
+            return Err(ContractError::ConstructorError {});
+        }
+        let (value, _) = IOracle::new(oracle, self.client.clone())
+            .peek(asset_id_to_bytes32(asset_id), asset_id_to_bytes32(base_id), amount)
+            .call()
+            .await?;
+        Ok(value)
+    }
+
     fn current_offer(&self, now: u64, auction_start: u64, duration: u64, initial_offer: u64) -> Result<u16, M> {
         if now < auction_start.into() {
             return Err(ContractError::ConstructorError{});
@@ -468,10 +1623,13 @@ impl<M: Middleware> Liquidator<M> {
         }
     }
 
-    async fn get_auction(&mut self, vault_id: VaultIdType, cache: &mut ImmutableCache<M>) -> Result<Option<Auction>, M> {
+    /// Fetches the auction of `vault_id`, with the offer as of `now`
+    async fn get_auction(&mut self, vault_id: VaultIdType, now: u64, cache: &mut ImmutableCache<M>) -> Result<Option<Auction>, M> {
         let (_, series_id, ilk_id) = self.cauldron.vaults(vault_id).call().await?;
         let balances_fn = self.cauldron.balances(vault_id);
         let auction_fn = self.liquidator.auctions(vault_id);
+        // the ratio is the one of the contract the ilk's bids go through
+        let (flash_liquidator, _) = self.flash_route(ilk_id);
 
         trace!(
             vault_id=?hex::encode(vault_id),
@@ -484,27 +1642,19 @@ impl<M: Middleware> Liquidator<M> {
             .add_call(balances_fn)
             .add_call(auction_fn)
             .add_call(self.liquidator.ilks(ilk_id))
-            .add_call(self.flash_liquidator.collateral_to_debt_ratio(vault_id))
+            .add_call(flash_liquidator.collateral_to_debt_ratio(vault_id))
             ;
 
-        let ((art, _), (auction_owner, auction_start), (duration, initial_offer), ratio_u256):
+        let ((art, ink), (auction_owner, auction_start), (duration, initial_offer), ratio_u256):
             ((u128, u128), (Address, u32), (u32, u64), U256) = multicall.call().await?;
 
         if cache.is_vault_ignored(series_id, ilk_id, art).await? {
             info!(vault_id=?hex::encode(vault_id), "vault is trivial or ignored - not auctioning");
             return Ok(None);
         }
-        let current_offer: u16 = 
-            match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                    Ok(x) => self.current_offer(x.as_secs(), 
-                    u64::from(auction_start), 
-                    u64::from(duration), initial_offer)
-                        .unwrap_or(0),
-                    Err(x) => {
-                        error!("Failed to get system time: {}", x);
-                        0u16
-                    }
-                };
+        let current_offer: u16 = self
+            .current_offer(now, u64::from(auction_start), u64::from(duration), initial_offer)
+            .unwrap_or(0);
 
         trace!(
             vault_id=?hex::encode(vault_id),
@@ -527,12 +1677,102 @@ impl<M: Middleware> Liquidator<M> {
         Ok(Some(Auction {
             under_auction: (auction_owner != Address::zero()),
             started: auction_start,
+            duration,
+            initial_offer_pct: (initial_offer / 10u64.pow(16)) as u16,
+            ink,
             debt: art,
             ratio_pct: ratio_pct,
             base_id: cache.get_or_fetch_base_id(series_id).await?,
             ilk_id: ilk_id,
-            collateral_offer_is_good_enough: current_offer >= self.target_collateral_offer,
+            current_offer,
         }))
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{escalator::GeometricGasPrice, strategy::StrategyParams};
+    use ethers::abi::{self, Token};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn keeps_mined_bids_which_cannot_be_read() {
+        let (provider, mock) = Provider::mocked();
+        let client = Arc::new(provider.with_sender(Address::repeat_byte(9)));
+        let (cauldron, witch) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let (vault_id, series_id, ilk_id, base_id) = ([1u8; 12], [2u8; 6], [3u8; 6], [4u8; 6]);
+        let path = std::env::temp_dir().join(format!("yield-liquidator-{}-mined-bids.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut liquidator = Liquidator::new(
+            SwapRouter::new(vec![], Duration::from_secs(1), Address::zero(), "test".to_owned()),
+            cauldron,
+            witch,
+            Address::zero(),
+            HashMap::new(),
+            Address::zero(),
+            Strategy::new(StrategyParams { min_ratio: 110, target_collateral_offer: 90, gas_boost: 0 }, vec![]).unwrap(),
+            HashMap::new(),
+            0,
+            client.clone(),
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            FeeStrategy {
+                base_fee_multiplier: 2,
+                tip_percentile: 50.0,
+                history_blocks: 10,
+                min_tip: U256::from(2),
+                tx_format: None,
+                escalator: GeometricGasPrice::new(),
+                max_cancel_price_multiplier: 2,
+            },
+            0,
+            CancelPolicy { max_bumps: 3, deadline: 600 },
+            CircuitBreaker::default(),
+            None,
+            None,
+            HashMap::new(),
+            OutcomeTracker::default(),
+            Some(Ledger::new(path.clone())),
+            "test".to_owned(),
+        )
+        .await;
+        let series_to_base = vec![(series_id, base_id)].into_iter().collect();
+        let mut cache = ImmutableCache::new(client.clone(), cauldron, series_to_base, HashMap::new(), "test".to_owned()).await;
+        let receipt = TransactionReceipt {
+            transaction_hash: H256::repeat_byte(5),
+            block_number: Some(10.into()),
+            status: Some(1.into()),
+            ..Default::default()
+        };
+        liquidator.mined_bids.push((vault_id, receipt));
+        let vault = Bytes::from(abi::encode(&[
+            Token::Address(Address::repeat_byte(7)),
+            Token::FixedBytes(series_id.to_vec()),
+            Token::FixedBytes(ilk_id.to_vec()),
+        ]));
+
+        // the mock answers last in, first out, and fails once it runs out of
+        // answers: the vault and its ilk are read, but not the bid's block
+        mock.push(Bytes::from(abi::encode(&[Token::Address(Address::repeat_byte(8))]))).unwrap();
+        mock.push(vault.clone()).unwrap();
+        liquidator.record_mined_bids(&mut cache).await.unwrap();
+        assert_eq!(liquidator.mined_bids.len(), 1);
+        assert!(Ledger::new(path.clone()).read().unwrap().is_empty());
+
+        // the bid is recorded once the chain answers
+        mock.push(Block::<TxHash> { timestamp: 1000.into(), ..Default::default() }).unwrap();
+        mock.push(vault).unwrap();
+        liquidator.record_mined_bids(&mut cache).await.unwrap();
+        assert!(liquidator.mined_bids.is_empty());
+        let entries = Ledger::new(path.clone()).read().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].timestamp, 1000);
+        assert_eq!(entries[0].vault_id, hex::encode(vault_id));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
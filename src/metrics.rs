@@ -0,0 +1,133 @@
+//! Prometheus metrics
+//!
+//! The metrics are registered in the default registry and exported by the
+//! HTTP server in `server`.
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, GaugeVec, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    /// Number of vaults being monitored
+    pub static ref VAULTS: IntGauge =
+        register_int_gauge!("liquidator_vaults", "Number of vaults being monitored").unwrap();
+
+    /// Number of monitored vaults which are undercollateralized
+    pub static ref UNDERCOLLATERALIZED_VAULTS: IntGauge = register_int_gauge!(
+        "liquidator_undercollateralized_vaults",
+        "Number of monitored vaults which are undercollateralized"
+    )
+    .unwrap();
+
+    /// Number of auctions being monitored
+    pub static ref ACTIVE_AUCTIONS: IntGauge =
+        register_int_gauge!("liquidator_active_auctions", "Number of auctions being monitored").unwrap();
+
+    /// Our transactions, by type ("liquidations": starting auctions, "auctions": bids,
+    /// "cancellations": self-transfers replacing stuck transactions) and status ("submitted",
+    /// "confirmed", "failed", "dropped", "cancelled", or "simulated" in dry-run mode)
+    pub static ref TRANSACTIONS: IntCounterVec = register_int_counter_vec!(
+        "liquidator_transactions_total",
+        "Transactions sent by the liquidator",
+        &["tx_type", "status"]
+    )
+    .unwrap();
+
+    /// Gas price bumps of pending transactions, by transaction type
+    pub static ref GAS_BUMPS: IntCounterVec = register_int_counter_vec!(
+        "liquidator_gas_bumps_total",
+        "Gas price bumps of pending transactions",
+        &["tx_type"]
+    )
+    .unwrap();
+
+    /// Circuit breaker trips, by reason
+    pub static ref BREAKER_TRIPS: IntCounterVec = register_int_counter_vec!(
+        "liquidator_breaker_trips_total",
+        "Circuit breaker trips",
+        &["reason"]
+    )
+    .unwrap();
+
+    /// 1 while the circuit breaker stops new transactions from being sent
+    pub static ref BREAKER_PAUSED: IntGauge = register_int_gauge!(
+        "liquidator_breaker_paused",
+        "1 while the circuit breaker stops new transactions from being sent"
+    )
+    .unwrap();
+
+    /// Share of the ended auctions we bought, by ilk
+    pub static ref AUCTION_WIN_RATE: GaugeVec = register_gauge_vec!(
+        "liquidator_auction_win_rate",
+        "Share of the ended auctions we bought",
+        &["ilk"]
+    )
+    .unwrap();
+
+    /// Average offer (percent of the collateral) other buyers accepted, by ilk
+    pub static ref COMPETITOR_OFFER: GaugeVec = register_gauge_vec!(
+        "liquidator_competitor_offer_pct",
+        "Average offer (percent of the collateral) other buyers accepted",
+        &["ilk"]
+    )
+    .unwrap();
+
+    /// Failures to build swap calldata
+    pub static ref SWAP_ROUTER_FAILURES: IntCounter = register_int_counter!(
+        "liquidator_swap_router_failures_total",
+        "Failures to build swap calldata"
+    )
+    .unwrap();
+
+    /// Restarts of the swap router daemon, after it crashed or hung
+    pub static ref SWAP_ROUTER_RESTARTS: IntCounter = register_int_counter!(
+        "liquidator_swap_router_restarts_total",
+        "Restarts of the swap router daemon, after it crashed or hung"
+    )
+    .unwrap();
+
+    /// Swaps the router was run once for, because its daemon was starting or busy
+    pub static ref SWAP_ROUTER_FALLBACKS: IntCounter = register_int_counter!(
+        "liquidator_swap_router_fallbacks_total",
+        "Swaps the router was run once for, because its daemon was starting or busy"
+    )
+    .unwrap();
+
+    /// Time it takes to build swap calldata
+    pub static ref SWAP_ROUTER_LATENCY: Histogram = register_histogram!(
+        "liquidator_swap_router_latency_seconds",
+        "Time it takes to build swap calldata",
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap();
+
+    /// Time it takes to process a block
+    pub static ref BLOCK_PROCESSING_TIME: Histogram = register_histogram!(
+        "liquidator_block_processing_seconds",
+        "Time it takes to process a block",
+        vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0]
+    )
+    .unwrap();
+
+    /// The last processed block
+    pub static ref LAST_BLOCK: IntGauge =
+        register_int_gauge!("liquidator_last_block", "The last processed block").unwrap();
+
+    /// Difference between the wall clock and the last block's timestamp
+    pub static ref BLOCK_LAG: IntGauge = register_int_gauge!(
+        "liquidator_block_lag_seconds",
+        "Difference between the wall clock and the last block's timestamp"
+    )
+    .unwrap();
+}
+
+/// Renders all the registered metrics in the Prometheus text format
+pub fn render() -> (String, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics can always be encoded");
+    (encoder.format_type().to_string(), buffer)
+}
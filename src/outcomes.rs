@@ -0,0 +1,268 @@
+//! Auction outcomes
+//!
+//! Our own receipts only tell us about the auctions we bought. To tune
+//! `target_collateral_offer`, we follow every auction's lifecycle from the
+//! Witch's events: who started it, who bought it, and at what offer. Ended
+//! auctions are rolled up into per-ilk statistics.
+use crate::bindings::{IlkIdType, VaultIdType};
+
+use ethers::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::HashMap;
+
+/// A purchase of (part of) an auction's collateral
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Purchase {
+    /// The account which sent the transaction
+    pub buyer: Address,
+    /// Collateral bought
+    pub ink: U256,
+    /// Debt repaid
+    pub art: U256,
+    pub block_number: u64,
+    /// Seconds since the auction started
+    pub elapsed: u64,
+    /// Percentage of the collateral offered at the time
+    pub offer_pct: u16,
+    /// Whether we're the buyer
+    pub ours: bool,
+    /// The `Bought` event, so that it isn't recorded twice
+    #[serde(default)]
+    pub tx_hash: TxHash,
+    #[serde(default)]
+    pub log_index: U256,
+}
+
+/// An auction we've seen start, and what happened to it so far
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuctionOutcome {
+    pub ilk_id: IlkIdType,
+    /// The start of the auction (unix timestamp, seconds)
+    pub started: u64,
+    /// The account which started the auction, if known
+    pub starter: Option<Address>,
+    /// How long it takes for the offer to reach 100%, seconds
+    pub duration: u64,
+    /// Percentage of the collateral offered when the auction starts
+    pub initial_offer_pct: u64,
+    pub purchases: Vec<Purchase>,
+}
+
+impl AuctionOutcome {
+    /// Whether we bought (part of) the collateral
+    pub fn won(&self) -> bool {
+        self.purchases.iter().any(|x| x.ours)
+    }
+}
+
+/// What happened to the ended auctions of an ilk
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct IlkStats {
+    /// Ended auctions which were bought
+    pub auctions: u64,
+    /// ... by us
+    pub won: u64,
+    /// Purchases made by others
+    pub competitor_purchases: u64,
+    /// Sum of the offers (percent) of the purchases made by others
+    pub competitor_offer_pct_sum: u64,
+}
+
+impl IlkStats {
+    /// Share of the bought auctions which we bought
+    pub fn win_rate(&self) -> Option<f64> {
+        if self.auctions == 0 {
+            return None;
+        }
+        Some(self.won as f64 / self.auctions as f64)
+    }
+
+    /// The average offer (percent) others bought at
+    pub fn avg_competitor_offer_pct(&self) -> Option<f64> {
+        if self.competitor_purchases == 0 {
+            return None;
+        }
+        Some(self.competitor_offer_pct_sum as f64 / self.competitor_purchases as f64)
+    }
+}
+
+/// Follows the auctions, and keeps the statistics of the ended ones
+#[serde_as]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutcomeTracker {
+    /// Auctions which didn't end yet
+    #[serde_as(as = "Vec<(_, _)>")]
+    open: HashMap<VaultIdType, AuctionOutcome>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    stats: HashMap<IlkIdType, IlkStats>,
+}
+
+impl OutcomeTracker {
+    /// Records the start of the auction of `vault_id`
+    pub fn on_auctioned(&mut self, vault_id: VaultIdType, outcome: AuctionOutcome) {
+        self.open.insert(vault_id, outcome);
+    }
+
+    /// Whether we know when the auction of `vault_id` started
+    pub fn is_open(&self, vault_id: &VaultIdType) -> bool {
+        self.open.contains_key(vault_id)
+    }
+
+    /// Records a purchase made at `timestamp` in an auction we know about,
+    /// from the `Bought` event at `log_index` in `tx_hash`. Returns it, if the
+    /// auction was known and the event wasn't recorded already
+    #[allow(clippy::too_many_arguments)]
+    pub fn on_bought(
+        &mut self,
+        vault_id: VaultIdType,
+        buyer: Address,
+        ink: U256,
+        art: U256,
+        block_number: u64,
+        timestamp: u64,
+        ours: bool,
+        tx_hash: TxHash,
+        log_index: U256,
+    ) -> Option<&Purchase> {
+        let outcome = self.open.get_mut(&vault_id)?;
+        if outcome.purchases.iter().any(|x| x.tx_hash == tx_hash && x.log_index == log_index) {
+            return None;
+        }
+        let elapsed = timestamp.saturating_sub(outcome.started);
+        outcome.purchases.push(Purchase {
+            buyer,
+            ink,
+            art,
+            block_number,
+            elapsed,
+            offer_pct: offer_pct(elapsed, outcome.duration, outcome.initial_offer_pct),
+            ours,
+            tx_hash,
+            log_index,
+        });
+        outcome.purchases.last()
+    }
+
+    /// Forgets the purchases made after `block_number`, whose blocks were
+    /// orphaned by a reorg: they're recorded again if they're mined again
+    pub fn rollback(&mut self, block_number: u64) {
+        for outcome in self.open.values_mut() {
+            outcome.purchases.retain(|x| x.block_number <= block_number);
+        }
+    }
+
+    /// Records the end of the auction of `vault_id`, and adds it to the stats
+    /// of its ilk. Returns it, if it was known
+    pub fn on_ended(&mut self, vault_id: &VaultIdType) -> Option<AuctionOutcome> {
+        let outcome = self.open.remove(vault_id)?;
+        if !outcome.purchases.is_empty() {
+            let stats = self.stats.entry(outcome.ilk_id).or_default();
+            stats.auctions += 1;
+            if outcome.won() {
+                stats.won += 1;
+            }
+            for purchase in outcome.purchases.iter().filter(|x| !x.ours) {
+                stats.competitor_purchases += 1;
+                stats.competitor_offer_pct_sum += u64::from(purchase.offer_pct);
+            }
+        }
+        Some(outcome)
+    }
+
+    /// The statistics of the ended auctions, per ilk
+    pub fn stats(&self) -> &HashMap<IlkIdType, IlkStats> {
+        &self.stats
+    }
+}
+
+/// The Witch's offer (percent of the collateral), `elapsed` seconds into an auction
+pub fn offer_pct(elapsed: u64, duration: u64, initial_offer_pct: u64) -> u16 {
+    if elapsed >= duration || initial_offer_pct >= 100 {
+        return 100;
+    }
+    (elapsed * (100 - initial_offer_pct) / duration + initial_offer_pct) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(ilk_id: IlkIdType, started: u64) -> AuctionOutcome {
+        AuctionOutcome {
+            ilk_id,
+            started,
+            starter: Some(Address::repeat_byte(9)),
+            duration: 100,
+            initial_offer_pct: 50,
+            purchases: vec![],
+        }
+    }
+
+    #[test]
+    fn computes_offers() {
+        assert_eq!(offer_pct(0, 100, 50), 50);
+        assert_eq!(offer_pct(50, 100, 50), 75);
+        assert_eq!(offer_pct(100, 100, 50), 100);
+        assert_eq!(offer_pct(1000, 100, 50), 100);
+    }
+
+    #[test]
+    fn rolls_ended_auctions_into_stats() {
+        let ilk = [1u8; 6];
+        let (us, them) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let mut tracker = OutcomeTracker::default();
+
+        // we buy half of the first auction, they buy the rest
+        tracker.on_auctioned([1u8; 12], outcome(ilk, 1000));
+        let purchase = tracker
+            .on_bought([1u8; 12], us, 5.into(), 10.into(), 1, 1020, true, TxHash::from_low_u64_be(1), 0.into())
+            .unwrap();
+        assert_eq!(purchase.elapsed, 20);
+        assert_eq!(purchase.offer_pct, 60);
+        tracker.on_bought([1u8; 12], them, 5.into(), 10.into(), 2, 1040, false, TxHash::from_low_u64_be(2), 0.into());
+        assert!(tracker.on_ended(&[1u8; 12]).unwrap().won());
+
+        // they buy the second one
+        tracker.on_auctioned([2u8; 12], outcome(ilk, 2000));
+        tracker.on_bought([2u8; 12], them, 5.into(), 10.into(), 3, 2080, false, TxHash::from_low_u64_be(3), 0.into());
+        tracker.on_ended(&[2u8; 12]);
+
+        // nobody buys the third one: it doesn't count
+        tracker.on_auctioned([3u8; 12], outcome(ilk, 3000));
+        tracker.on_ended(&[3u8; 12]);
+
+        // unknown auctions are ignored
+        assert!(tracker.on_bought([4u8; 12], them, 5.into(), 10.into(), 4, 4000, false, TxHash::from_low_u64_be(4), 0.into()).is_none());
+        assert!(tracker.on_ended(&[4u8; 12]).is_none());
+
+        let stats = &tracker.stats()[&ilk];
+        assert_eq!(stats.auctions, 2);
+        assert_eq!(stats.won, 1);
+        assert_eq!(stats.win_rate(), Some(0.5));
+        // they bought at 70% and 90%
+        assert_eq!(stats.avg_competitor_offer_pct(), Some(80.0));
+        assert_eq!(IlkStats::default().win_rate(), None);
+    }
+
+    #[test]
+    fn records_purchases_once() {
+        let them = Address::repeat_byte(2);
+        let mut tracker = OutcomeTracker::default();
+        tracker.on_auctioned([1u8; 12], outcome([1u8; 6], 1000));
+
+        let tx_hash = TxHash::repeat_byte(7);
+        assert!(tracker.on_bought([1u8; 12], them, 5.into(), 10.into(), 5, 1020, false, tx_hash, 3.into()).is_some());
+        // the same event, e.g. from overlapping block ranges
+        assert!(tracker.on_bought([1u8; 12], them, 5.into(), 10.into(), 5, 1020, false, tx_hash, 3.into()).is_none());
+        assert!(tracker.on_bought([1u8; 12], them, 5.into(), 10.into(), 5, 1020, false, tx_hash, 4.into()).is_some());
+
+        // the block was orphaned, and the purchase mined again later
+        tracker.rollback(4);
+        assert!(tracker.on_bought([1u8; 12], them, 5.into(), 10.into(), 6, 1030, false, tx_hash, 0.into()).is_some());
+
+        let outcome = tracker.on_ended(&[1u8; 12]).unwrap();
+        assert_eq!(outcome.purchases.len(), 1);
+        assert_eq!(tracker.stats()[&[1u8; 6]].competitor_purchases, 1);
+    }
+}
@@ -0,0 +1,91 @@
+//! Profit estimation
+//!
+//! Before bidding, the liquidation is simulated: the collateral left over
+//! after repaying the flash loan is what we earn, the gas is what we pay.
+//! Both are valued in the auction's base asset.
+use crate::bindings::AssetIdType;
+
+use ethers::prelude::*;
+
+/// Yield's asset id for ETH, used to value gas costs
+pub const ETH_ID: AssetIdType = [0x30, 0x30, 0, 0, 0, 0];
+
+/// The outcome of a simulated liquidation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProfitEstimate {
+    /// Collateral which would reach us (collateral units). Flash liquidators
+    /// which sell all the collateral pay us in the base instead
+    pub collateral_received: U256,
+    /// `collateral_received`, valued in the base asset
+    pub collateral_value: U256,
+    /// Gas cost of the liquidation (wei)
+    pub gas_cost: U256,
+    /// `gas_cost`, valued in the base asset
+    pub gas_cost_value: U256,
+}
+
+impl ProfitEstimate {
+    /// Net profit in the base asset; negative if the gas costs more than the
+    /// collateral we get
+    pub fn profit(&self) -> I256 {
+        I256::from_raw(self.collateral_value).saturating_sub(I256::from_raw(self.gas_cost_value))
+    }
+
+    /// Whether the liquidation makes at least `min_profit` (base units)
+    pub fn is_profitable(&self, min_profit: u128) -> bool {
+        self.profit() >= I256::from_raw(U256::from(min_profit))
+    }
+}
+
+/// Pads an asset id to `bytes32`, the way Solidity converts `bytes6` when
+/// calling the oracles
+pub fn asset_id_to_bytes32(asset_id: AssetIdType) -> [u8; 32] {
+    let mut ret = [0u8; 32];
+    ret[..asset_id.len()].copy_from_slice(&asset_id);
+    ret
+}
+
+/// Decodes the return data of `IERC20.balanceOf`
+pub fn decode_balance(data: &[u8]) -> Option<U256> {
+    if data.len() != 32 {
+        return None;
+    }
+    Some(U256::from_big_endian(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profit_is_collateral_value_minus_gas() {
+        let estimate = ProfitEstimate {
+            collateral_received: U256::from(10),
+            collateral_value: U256::from(1000),
+            gas_cost: U256::from(5),
+            gas_cost_value: U256::from(300),
+        };
+        assert_eq!(estimate.profit(), I256::from(700));
+        assert!(estimate.is_profitable(700));
+        assert!(!estimate.is_profitable(701));
+
+        let losing = ProfitEstimate {
+            gas_cost_value: U256::from(1200),
+            ..estimate
+        };
+        assert_eq!(losing.profit(), I256::from(-200));
+        assert!(!losing.is_profitable(0));
+    }
+
+    #[test]
+    fn decodes_balances() {
+        let mut data = [0u8; 32];
+        data[31] = 42;
+        assert_eq!(decode_balance(&data), Some(U256::from(42)));
+        assert_eq!(decode_balance(&data[1..]), None);
+        assert_eq!(
+            asset_id_to_bytes32(ETH_ID)[..8],
+            [0x30, 0x30, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Private transaction relay
+//!
+//! Bids sent to the public mempool get copied by generalized front-runners.
+//! Instead, they can be signed and sent to a bundle relay (`eth_sendBundle`),
+//! targeting a specific block. A bundle which didn't make it into its block is
+//! sent again for the next one.
+use ethers::{prelude::*, utils::keccak256};
+use ethers_core::types::transaction::eip2718::TypedTransaction;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::{debug, instrument};
+
+#[derive(Error, Debug)]
+pub enum RelayError {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("signing error: {0}")]
+    Signing(#[from] WalletError),
+    #[error("relay error: {0}")]
+    Relay(String),
+}
+
+/// A bundle sent to the relay, which wasn't mined yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingBundle {
+    /// The transaction, as signed
+    pub tx: TypedTransaction,
+    pub raw_tx: Bytes,
+    pub hash: TxHash,
+    /// The block the bundle was last sent for
+    pub target_block: U64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BundleRelay {
+    url: String,
+    http: reqwest::Client,
+    /// Signs the transactions
+    signer: LocalWallet,
+    /// Signs the requests to the relay, identifying us to it
+    auth_signer: LocalWallet,
+}
+
+impl BundleRelay {
+    /// Constructor
+    pub fn new(url: String, signer: LocalWallet, auth_signer: LocalWallet) -> Self {
+        BundleRelay {
+            url,
+            http: reqwest::Client::new(),
+            signer,
+            auth_signer,
+        }
+    }
+
+    /// Signs `tx`, which must be complete (nonce, gas and gas price set)
+    pub async fn sign(&self, tx: TypedTransaction, target_block: U64) -> Result<PendingBundle, RelayError> {
+        let signature = self.signer.sign_transaction(&tx).await?;
+        let raw_tx = tx.rlp_signed(&signature);
+        Ok(PendingBundle {
+            hash: H256::from(keccak256(&raw_tx)),
+            tx,
+            raw_tx,
+            target_block,
+        })
+    }
+
+    /// Sends `bundle` for inclusion in its `target_block`
+    #[instrument(skip(self, bundle), fields(tx_hash = ?bundle.hash))]
+    pub async fn send_bundle(&self, bundle: &PendingBundle) -> Result<(), RelayError> {
+        let body = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendBundle",
+            "params": [{
+                "txs": [bundle.raw_tx],
+                "blockNumber": bundle.target_block,
+            }],
+        }))?;
+        let response: Value = self
+            .http
+            .post(self.url.as_str())
+            .header("Content-Type", "application/json")
+            .header("X-Flashbots-Signature", self.auth_header(&body).await?)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if let Some(error) = response.get("error") {
+            return Err(RelayError::Relay(error.to_string()));
+        }
+        debug!(target_block = bundle.target_block.as_u64(), response = ?response, "Bundle sent");
+        Ok(())
+    }
+
+    /// `<address>:<signature of the hex encoded keccak256 of the body>`
+    async fn auth_header(&self, body: &[u8]) -> Result<String, RelayError> {
+        let message = format!("0x{}", hex::encode(keccak256(body)));
+        let signature = self.auth_signer.sign_message(message).await?;
+        Ok(format!("{:?}:0x{}", self.auth_signer.address(), signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use std::{convert::Infallible, str::FromStr, sync::Arc};
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn sends_signed_bundles() {
+        // a relay which records what it gets
+        let received: Arc<Mutex<Vec<(String, Vec<u8>)>>> = Arc::new(Mutex::new(vec![]));
+        let received_by_server = received.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let received = received_by_server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let received = received.clone();
+                    async move {
+                        let signature = req.headers()["X-Flashbots-Signature"].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        received.lock().await.push((signature, body.to_vec()));
+                        Ok::<_, Infallible>(Response::new(Body::from(
+                            r#"{"jsonrpc":"2.0","id":1,"result":{"bundleHash":"0x01"}}"#,
+                        )))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let signer: LocalWallet = "0101010101010101010101010101010101010101010101010101010101010101".parse().unwrap();
+        let auth_signer: LocalWallet = "0202020202020202020202020202020202020202020202020202020202020202".parse().unwrap();
+        let relay = BundleRelay::new(url, signer.clone(), auth_signer.clone());
+
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::repeat_byte(1))
+            .from(signer.address())
+            .nonce(3)
+            .gas(100_000)
+            .gas_price(1_000_000_000u64)
+            .into();
+        let bundle = relay.sign(tx, 1000.into()).await.unwrap();
+        relay.send_bundle(&bundle).await.unwrap();
+
+        let received = received.lock().await;
+        assert_eq!(received.len(), 1);
+        let (signature, body) = &received[0];
+        let request: Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(request["method"], "eth_sendBundle");
+        assert_eq!(request["params"][0]["blockNumber"], "0x3e8");
+        assert_eq!(
+            request["params"][0]["txs"][0],
+            serde_json::to_value(&bundle.raw_tx).unwrap()
+        );
+
+        // the header is signed by the auth key
+        let (address, signature) = signature.split_once(':').unwrap();
+        assert_eq!(Address::from_str(address).unwrap(), auth_signer.address());
+        let signature = Signature::from_str(signature.trim_start_matches("0x")).unwrap();
+        signature
+            .verify(format!("0x{}", hex::encode(keccak256(body))), auth_signer.address())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn surfaces_relay_errors() {
+        let make_service = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+                Ok::<_, Infallible>(Response::new(Body::from(
+                    r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"bundle too old"}}"#,
+                )))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let wallet: LocalWallet = "0101010101010101010101010101010101010101010101010101010101010101".parse().unwrap();
+        let relay = BundleRelay::new(url, wallet.clone(), wallet);
+        let bundle = relay
+            .sign(TransactionRequest::new().nonce(0).gas(21_000).gas_price(1).into(), 1.into())
+            .await
+            .unwrap();
+        assert!(matches!(relay.send_bundle(&bundle).await, Err(RelayError::Relay(_))));
+    }
+}
@@ -0,0 +1,203 @@
+//! Reorg detection
+//!
+//! This module keeps track of the hashes of the most recently processed blocks,
+//! so that we can tell when the chain we ingested events from is no longer
+//! the canonical one.
+use ethers::prelude::*;
+use std::collections::VecDeque;
+use tracing::{error, warn};
+
+/// How many blocks to remember by default
+pub const DEFAULT_REORG_DEPTH: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct BlockHistory {
+    /// (number, hash) of the recently processed blocks, oldest first
+    blocks: VecDeque<(u64, H256)>,
+    /// How many blocks to remember
+    depth: usize,
+}
+
+impl BlockHistory {
+    /// Constructor
+    pub fn new(blocks: Vec<(u64, H256)>, depth: usize) -> Self {
+        let mut history = BlockHistory {
+            blocks: VecDeque::new(),
+            depth,
+        };
+        for (number, hash) in blocks {
+            history.push(number, hash);
+        }
+        history
+    }
+
+    /// The remembered blocks, oldest first
+    pub fn blocks(&self) -> Vec<(u64, H256)> {
+        self.blocks.iter().cloned().collect()
+    }
+
+    /// Whether this exact block was processed already
+    pub fn contains(&self, number: u64, hash: H256) -> bool {
+        self.blocks.iter().any(|x| *x == (number, hash))
+    }
+
+    /// Records a processed block
+    pub fn push(&mut self, number: u64, hash: H256) {
+        // anything at or above this height was replaced
+        while matches!(self.blocks.back(), Some((n, _)) if *n >= number) {
+            self.blocks.pop_back();
+        }
+        self.blocks.push_back((number, hash));
+        while self.blocks.len() > self.depth {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Checks whether a new block with the given number and parent hash
+    /// extends the blocks we've processed so far.
+    ///
+    /// Returns `None` if it does. Otherwise, forgets the orphaned blocks and
+    /// returns the most recent processed block which is still canonical: all
+    /// the events after it have to be ingested again
+    pub async fn find_reorg<M: Middleware>(
+        &mut self,
+        client: &M,
+        number: u64,
+        parent_hash: H256,
+    ) -> std::result::Result<Option<u64>, M::Error> {
+        let (last_number, last_hash) = match self.blocks.back() {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+        if number == last_number + 1 && parent_hash == last_hash {
+            return Ok(None);
+        }
+        if number > last_number + 1 {
+            // we haven't seen the blocks in between: check that our latest one is still canonical
+            let canonical = client.get_block(U64::from(last_number)).await?;
+            if canonical.and_then(|x| x.hash) == Some(last_hash) {
+                return Ok(None);
+            }
+        }
+
+        warn!(number, parent_hash = ?parent_hash, last_number, last_hash = ?last_hash, "Chain reorganization detected");
+        let oldest = self.blocks.front().map(|x| x.0).unwrap_or(last_number);
+        while let Some((n, h)) = self.blocks.back().cloned() {
+            if n < number {
+                let canonical = client.get_block(U64::from(n)).await?;
+                if canonical.and_then(|x| x.hash) == Some(h) {
+                    return Ok(Some(n));
+                }
+            }
+            self.blocks.pop_back();
+        }
+
+        error!(oldest, depth = self.depth, "Reorg is deeper than the block history");
+        Ok(Some(oldest.saturating_sub(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(number: u64) -> H256 {
+        H256::from_low_u64_be(number)
+    }
+
+    /// Blocks 0..=9, with hashes 0..=9
+    fn history(depth: usize) -> BlockHistory {
+        BlockHistory::new((0..10u64).map(|n| (n, hash(n))).collect(), depth)
+    }
+
+    /// The node's answers to `get_block`, in the order they're asked for
+    fn mock_blocks(blocks: Vec<(u64, H256)>) -> Provider<MockProvider> {
+        let (provider, mock) = Provider::mocked();
+        // the mock answers last in, first out
+        for (number, hash) in blocks.into_iter().rev() {
+            let block = Block::<TxHash> {
+                number: Some(number.into()),
+                hash: Some(hash),
+                ..Default::default()
+            };
+            mock.push(block).unwrap();
+        }
+        provider
+    }
+
+    #[tokio::test]
+    async fn extending_blocks_are_not_reorgs() {
+        let mut history = history(DEFAULT_REORG_DEPTH);
+        let provider = mock_blocks(vec![]);
+        assert_eq!(history.find_reorg(&provider, 10, hash(9)).await.unwrap(), None);
+
+        // a gap only needs our latest block to still be canonical
+        let provider = mock_blocks(vec![(9, hash(9))]);
+        assert_eq!(history.find_reorg(&provider, 12, hash(11)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn one_block_reorg() {
+        let mut history = history(DEFAULT_REORG_DEPTH);
+        // block 9 was replaced
+        let provider = mock_blocks(vec![(9, hash(99)), (8, hash(8))]);
+        assert_eq!(history.find_reorg(&provider, 10, hash(99)).await.unwrap(), Some(8));
+        assert_eq!(history.blocks().last(), Some(&(8, hash(8))));
+    }
+
+    #[tokio::test]
+    async fn deep_reorg() {
+        let mut history = history(DEFAULT_REORG_DEPTH);
+        // blocks 5 to 9 were replaced, and we're told about 11
+        let provider = mock_blocks(vec![
+            (9, hash(99)),
+            (9, hash(99)),
+            (8, hash(88)),
+            (7, hash(77)),
+            (6, hash(66)),
+            (5, hash(55)),
+            (4, hash(4)),
+        ]);
+        assert_eq!(history.find_reorg(&provider, 11, hash(1010)).await.unwrap(), Some(4));
+        assert_eq!(history.blocks().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn reorg_beyond_the_history() {
+        let mut history = history(3);
+        // blocks 7 to 9 are all we remember, and none of them is canonical
+        let provider = mock_blocks(vec![(9, hash(99)), (8, hash(88)), (7, hash(77))]);
+        assert_eq!(history.find_reorg(&provider, 10, hash(99)).await.unwrap(), Some(6));
+        assert!(history.blocks().is_empty());
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = BlockHistory::new(vec![], 3);
+        for n in 0..10u64 {
+            history.push(n, H256::from_low_u64_be(n));
+        }
+        assert_eq!(
+            history.blocks().iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec![7, 8, 9]
+        );
+    }
+
+    #[test]
+    fn replaced_blocks_are_forgotten() {
+        let mut history = BlockHistory::new(
+            (0..5u64).map(|n| (n, H256::from_low_u64_be(n))).collect(),
+            DEFAULT_REORG_DEPTH,
+        );
+        history.push(3, H256::from_low_u64_be(33));
+        assert_eq!(
+            history.blocks(),
+            vec![
+                (0, H256::from_low_u64_be(0)),
+                (1, H256::from_low_u64_be(1)),
+                (2, H256::from_low_u64_be(2)),
+                (3, H256::from_low_u64_be(33)),
+            ]
+        );
+    }
+}
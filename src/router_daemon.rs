@@ -0,0 +1,413 @@
+//! Swap router daemon
+//!
+//! Spawning the Node router for every swap costs seconds, and a router which
+//! hangs would block the keeper. Instead, the router can run once, with
+//! `--daemon`, and answer newline-delimited JSON-RPC requests on its stdin and
+//! stdout. A supervisor task owns the child: it sends it one request at a
+//! time, kills it when it doesn't answer in time, and starts it again when it
+//! crashed or was killed. Requests wait in a bounded queue.
+//!
+//! When the queue is full, or while the daemon is (re)starting, the router is
+//! run once for the request instead, as `BinaryRouteProvider` does. The
+//! daemon is (re)starting until it answers its first request; meanwhile it
+//! still gets the requests, to warm up.
+use crate::{
+    metrics,
+    swap_router::{BinaryRouteProvider, RouterResult, SwapCalldata, SwapRouteProvider, SwapRouterError},
+};
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Debug)]
+pub struct DaemonConfig {
+    pub router_binary_path: String,
+    pub rpc_url: String,
+    pub chain_id: u64,
+    /// How long the daemon has to answer a request before it's restarted.
+    /// Shorter than the swap router's timeout, which includes the queue
+    pub request_timeout: Duration,
+    /// How many requests may wait for the daemon
+    pub queue_size: usize,
+}
+
+/// The params of a `route` request
+#[derive(Clone, Debug, Serialize)]
+struct RouteParams {
+    from_address: String,
+    token_in: String,
+    token_out: String,
+    amount_out: String,
+}
+
+#[derive(Serialize)]
+struct RpcRequest<'a> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'static str,
+    params: &'a RouteParams,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    id: Option<u64>,
+    result: Option<RouterResult>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    message: String,
+}
+
+struct DaemonRequest {
+    params: RouteParams,
+    /// `None` to warm the daemon up: nobody waits for the response
+    response: Option<oneshot::Sender<Result<SwapCalldata, SwapRouterError>>>,
+}
+
+/// The router binary, running as a daemon
+#[derive(Debug)]
+pub struct DaemonRouteProvider {
+    requests: mpsc::Sender<DaemonRequest>,
+    /// Whether the daemon answered since it was (re)started
+    ready: Arc<AtomicBool>,
+    /// Runs the router once, when the daemon can't answer
+    fallback: BinaryRouteProvider,
+}
+
+impl DaemonRouteProvider {
+    /// Starts the daemon and its supervisor. Must be called within a tokio
+    /// runtime; the daemon is stopped when the provider is dropped
+    pub fn new(config: DaemonConfig) -> Self {
+        let (requests, receiver) = mpsc::channel(config.queue_size);
+        let ready = Arc::new(AtomicBool::new(false));
+        let fallback = BinaryRouteProvider::new(
+            config.rpc_url.clone(),
+            config.chain_id,
+            config.router_binary_path.clone(),
+        );
+        tokio::spawn(Supervisor::new(config, ready.clone()).run(receiver));
+        DaemonRouteProvider {
+            requests,
+            ready,
+            fallback,
+        }
+    }
+
+    /// Runs the router once for this request
+    async fn one_shot(
+        &self,
+        reason: &str,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<SwapCalldata, SwapRouterError> {
+        debug!(reason, "Running the router once");
+        metrics::SWAP_ROUTER_FALLBACKS.inc();
+        self.fallback
+            .build_swap_exact_out(recipient, token_in, token_out, amount_out)
+            .await
+    }
+}
+
+#[async_trait]
+impl SwapRouteProvider for DaemonRouteProvider {
+    fn name(&self) -> &str {
+        "daemon"
+    }
+
+    async fn build_swap_exact_out(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<SwapCalldata, SwapRouterError> {
+        let params = RouteParams {
+            from_address: format!("{:?}", recipient),
+            token_in: format!("{:?}", token_in),
+            token_out: format!("{:?}", token_out),
+            amount_out: amount_out.to_string(),
+        };
+        if !self.ready.load(Ordering::Relaxed) {
+            // if the queue is full, the daemon has enough to warm up on
+            let _ = self.requests.try_send(DaemonRequest { params, response: None });
+            return self.one_shot("router daemon starting", recipient, token_in, token_out, amount_out).await;
+        }
+        let (response, receiver) = oneshot::channel();
+        match self.requests.try_send(DaemonRequest { params, response: Some(response) }) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                return self.one_shot("router daemon queue is full", recipient, token_in, token_out, amount_out).await;
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(SwapRouterError::Unreachable("router daemon stopped".into()));
+            }
+        }
+        receiver
+            .await
+            .map_err(|_| SwapRouterError::Unreachable("router daemon stopped".into()))?
+    }
+}
+
+/// The running daemon
+struct RouterProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl RouterProcess {
+    /// Sends a request, and waits for the response with the same id.
+    /// I/O errors mean the daemon is gone
+    async fn request(&mut self, id: u64, params: &RouteParams) -> std::io::Result<Result<SwapCalldata, SwapRouterError>> {
+        let mut line = serde_json::to_vec(&RpcRequest { jsonrpc: "2.0", id, method: "route", params })?;
+        line.push(b'\n');
+        self.stdin.write_all(&line).await?;
+        self.stdin.flush().await?;
+
+        while let Some(line) = self.stdout.next_line().await? {
+            let response: RpcResponse = match serde_json::from_str(&line) {
+                Ok(x) => x,
+                Err(x) => {
+                    warn!(line = line.as_str(), err = ?x, "Unexpected output from the router daemon");
+                    continue;
+                }
+            };
+            if response.id != Some(id) {
+                debug!(id, response_id = ?response.id, "Stale response from the router daemon");
+                continue;
+            }
+            return Ok(match (response.result, response.error) {
+                (Some(result), _) => result.into_swap(),
+                (None, Some(error)) => Err(SwapRouterError::RouterError(error.message)),
+                (None, None) => Err(SwapRouterError::RouterError("empty response".into())),
+            });
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "router daemon exited"))
+    }
+}
+
+/// Owns the daemon, and feeds it the requests one at a time
+struct Supervisor {
+    config: DaemonConfig,
+    process: Option<RouterProcess>,
+    next_id: u64,
+    /// Shared with the provider
+    ready: Arc<AtomicBool>,
+}
+
+impl Supervisor {
+    fn new(config: DaemonConfig, ready: Arc<AtomicBool>) -> Self {
+        Supervisor {
+            config,
+            process: None,
+            next_id: 0,
+            ready,
+        }
+    }
+
+    async fn run(mut self, mut requests: mpsc::Receiver<DaemonRequest>) {
+        // start it right away, so that it's warm by the first request
+        if let Err(x) = self.ensure_running() {
+            warn!(err = ?x, "Failed to start the router daemon");
+        }
+        while let Some(request) = requests.recv().await {
+            match &request.response {
+                // the caller gave up while the request was queued
+                Some(x) if x.is_closed() => continue,
+                // warmed up already
+                None if self.ready.load(Ordering::Relaxed) => continue,
+                _ => {}
+            }
+            let result = self.handle(&request.params).await;
+            if let Some(response) = request.response {
+                let _ = response.send(result);
+            }
+        }
+        debug!("Router daemon no longer needed, stopping it");
+    }
+
+    async fn handle(&mut self, params: &RouteParams) -> Result<SwapCalldata, SwapRouterError> {
+        self.ensure_running()
+            .map_err(|x| SwapRouterError::Unreachable(format!("failed to start the router daemon: {}", x)))?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let process = self.process.as_mut().expect("the daemon was just started");
+        match tokio::time::timeout(self.config.request_timeout, process.request(id, params)).await {
+            Ok(Ok(result)) => {
+                // even without a route, it answered
+                self.ready.store(true, Ordering::Relaxed);
+                result
+            }
+            Ok(Err(x)) => {
+                self.stop(&format!("failed: {}", x));
+                Err(SwapRouterError::Unreachable(format!("router daemon failed: {}", x)))
+            }
+            Err(_) => {
+                self.stop("timed out");
+                Err(SwapRouterError::RouterError("router daemon timed out".into()))
+            }
+        }
+    }
+
+    /// (Re)starts the daemon if it's not running
+    fn ensure_running(&mut self) -> std::io::Result<()> {
+        if let Some(process) = &mut self.process {
+            match process.child.try_wait() {
+                Ok(None) => return Ok(()),
+                Ok(Some(status)) => self.stop(&format!("exited with {}", status)),
+                Err(x) => self.stop(&format!("unknown status: {}", x)),
+            }
+        }
+        let mut child = Command::new(&self.config.router_binary_path)
+            .arg(format!("--rpc_url={}", self.config.rpc_url))
+            .arg(format!("--chain_id={}", self.config.chain_id))
+            .arg("--daemon")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+        info!(pid = ?child.id(), "Router daemon started");
+        self.process = Some(RouterProcess { child, stdin, stdout });
+        Ok(())
+    }
+
+    /// Kills the daemon; it's started again on the next request
+    fn stop(&mut self, reason: &str) {
+        self.ready.store(false, Ordering::Relaxed);
+        if let Some(mut process) = self.process.take() {
+            warn!(pid = ?process.child.id(), reason, "Restarting the router daemon");
+            metrics::SWAP_ROUTER_RESTARTS.inc();
+            let _ = process.child.start_kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    /// As a daemon, answers `amount_out` 1, hangs on 2, crashes on 3, and
+    /// fails otherwise. Run once, answers `amount_out` 1 with another quote
+    const FAKE_ROUTER: &str = r#"#!/bin/sh
+case "$*" in
+  *--daemon*) ;;
+  *--amount_out=1\ *) echo '{"data": "0x0304", "amount_in": "43"}'; exit 0 ;;
+  *) exit 1 ;;
+esac
+while read -r line; do
+  id=$(echo "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+  case "$line" in
+    *'"amount_out":"1"'*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"data\":\"0x0102\",\"amount_in\":\"42\"}}" ;;
+    *'"amount_out":"2"'*) sleep 5 ;;
+    *'"amount_out":"3"'*) exit 1 ;;
+    *) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":-32000,\"message\":\"no route\"}}" ;;
+  esac
+done
+"#;
+
+    fn fake_router(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fake-router-{}-{}", name, std::process::id()));
+        fs::write(&path, FAKE_ROUTER).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn daemon(path: &PathBuf, queue_size: usize) -> DaemonRouteProvider {
+        DaemonRouteProvider::new(DaemonConfig {
+            router_binary_path: path.to_string_lossy().to_string(),
+            rpc_url: "http://127.0.0.1:8545".to_string(),
+            chain_id: 1,
+            request_timeout: Duration::from_millis(500),
+            queue_size,
+        })
+    }
+
+    async fn route(daemon: &DaemonRouteProvider, amount_out: u64) -> Result<SwapCalldata, SwapRouterError> {
+        daemon
+            .build_swap_exact_out(Address::zero(), Address::zero(), Address::zero(), amount_out.into())
+            .await
+    }
+
+    /// Until the daemon answered the requests it got while starting
+    async fn warm_up(daemon: &DaemonRouteProvider) {
+        while !daemon.ready.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_after_hangs_and_crashes() {
+        let path = fake_router("restarts");
+        let daemon = daemon(&path, 4);
+
+        // run once while the daemon starts
+        assert_eq!(route(&daemon, 1).await.unwrap().amount_in, 43.into());
+        warm_up(&daemon).await;
+        let swap = route(&daemon, 1).await.unwrap();
+        assert_eq!(swap.calldata, vec![1, 2]);
+        assert_eq!(swap.amount_in, 42.into());
+        assert!(route(&daemon, 4).await.unwrap_err().to_string().contains("router error"));
+
+        let err = route(&daemon, 2).await.unwrap_err();
+        assert!(matches!(err, SwapRouterError::RouterError(x) if x.contains("timed out")));
+        assert_eq!(route(&daemon, 1).await.unwrap().amount_in, 43.into());
+        warm_up(&daemon).await;
+        assert_eq!(route(&daemon, 1).await.unwrap().amount_in, 42.into());
+
+        let err = route(&daemon, 3).await.unwrap_err();
+        assert!(matches!(err, SwapRouterError::Unreachable(x) if x.contains("failed")));
+        assert_eq!(route(&daemon, 1).await.unwrap().amount_in, 43.into());
+        warm_up(&daemon).await;
+        assert_eq!(route(&daemon, 1).await.unwrap().amount_in, 42.into());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_once_when_the_queue_is_full() {
+        let path = fake_router("queue");
+        let daemon = daemon(&path, 1);
+        route(&daemon, 1).await.unwrap();
+        warm_up(&daemon).await;
+
+        // the first one keeps the daemon busy, the second one waits in the queue
+        let (first, second, third) = tokio::join!(
+            route(&daemon, 2),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                route(&daemon, 2).await
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                route(&daemon, 1).await
+            }
+        );
+        assert!(first.is_err());
+        assert!(second.is_err());
+        assert_eq!(third.unwrap().amount_in, 43.into());
+
+        fs::remove_file(path).unwrap();
+    }
+}
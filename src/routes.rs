@@ -0,0 +1,99 @@
+//! Flash liquidator routing
+//!
+//! Most collateral is sold on Uniswap by `FlashLiquidator`, but some needs a
+//! dedicated contract (e.g. wstETH, which `WstethFlashLiquidator` unwraps and
+//! sells on Curve). Each contract expects its own swap calldata.
+use ethers::prelude::*;
+use serde::Deserialize;
+
+/// How to build the swap calldata a flash liquidator contract expects
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum CalldataBuilder {
+    /// `FlashLiquidator`: the collateral is swapped for the base on Uniswap.
+    /// What's left of the collateral is our profit
+    Uniswap,
+    /// `WstethFlashLiquidator`: the wstETH is unwrapped and swapped for ETH on
+    /// Curve. The profit is paid in the base.
+    ///
+    /// Only for WETH bases: the contract would swap the WETH for any other
+    /// base on Uniswap, but it only accounts for the debt it recovered when
+    /// the base is WETH, so the profit of those bids would be left behind
+    WstethCurve,
+}
+
+impl Default for CalldataBuilder {
+    fn default() -> Self {
+        CalldataBuilder::Uniswap
+    }
+}
+
+impl CalldataBuilder {
+    /// Whether the contract can buy auctions for this base
+    pub fn supports_base(&self, base: Address, weth: Address) -> bool {
+        match self {
+            CalldataBuilder::Uniswap => true,
+            CalldataBuilder::WstethCurve => base == weth,
+        }
+    }
+
+    /// The Uniswap swap the contract makes, as (token in, token out), or
+    /// `None` if it doesn't swap on Uniswap at all
+    pub fn uniswap_leg(&self, collateral: Address, base: Address) -> Option<(Address, Address)> {
+        match self {
+            CalldataBuilder::Uniswap => Some((collateral, base)),
+            CalldataBuilder::WstethCurve => None,
+        }
+    }
+
+    /// Whether the profits are paid in the base (rather than in the collateral)
+    pub fn pays_profit_in_base(&self) -> bool {
+        match self {
+            CalldataBuilder::Uniswap => false,
+            CalldataBuilder::WstethCurve => true,
+        }
+    }
+}
+
+/// An entry of the config's `IlkFlashLiquidators` map
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FlashRouteConfig {
+    /// The flash liquidator contract
+    #[serde(rename = "Flash")]
+    pub flash_liquidator: Address,
+    #[serde(rename = "Calldata", default)]
+    pub calldata: CalldataBuilder,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_config() {
+        let routes: HashMap<String, FlashRouteConfig> = serde_json::from_str(
+            r#"{
+                "303400000000": {"Flash": "0x0000000000000000000000000000000000000001", "Calldata": "WstethCurve"},
+                "303500000000": {"Flash": "0x0000000000000000000000000000000000000002"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(routes["303400000000"].calldata, CalldataBuilder::WstethCurve);
+        assert_eq!(routes["303500000000"].calldata, CalldataBuilder::Uniswap);
+        assert_eq!(routes["303500000000"].flash_liquidator, Address::from_low_u64_be(2));
+    }
+
+    #[test]
+    fn wsteth_only_for_weth() {
+        let (collateral, base, weth) = (
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(3),
+        );
+        assert_eq!(CalldataBuilder::Uniswap.uniswap_leg(collateral, base), Some((collateral, base)));
+        assert!(CalldataBuilder::Uniswap.supports_base(base, weth));
+        assert_eq!(CalldataBuilder::WstethCurve.uniswap_leg(collateral, weth), None);
+        assert!(CalldataBuilder::WstethCurve.supports_base(weth, weth));
+        assert!(!CalldataBuilder::WstethCurve.supports_base(base, weth));
+    }
+}
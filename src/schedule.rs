@@ -0,0 +1,96 @@
+//! Buy scheduling
+//!
+//! The Witch's offer grows linearly from `initial_offer` at `auction_start` to
+//! 100% at `auction_start + duration`, so we know in advance when an auction
+//! becomes worth buying. Buys are prepared shortly before that, and sent in the
+//! first block whose timestamp qualifies.
+use ethers::prelude::*;
+
+/// A buy waiting for its time to come
+#[derive(Clone, Debug)]
+pub struct ScheduledBuy {
+    /// When the auction becomes worth buying (unix timestamp, seconds)
+    pub due: u64,
+    /// When the calldata was built (unix timestamp, seconds)
+    pub prepared_at: u64,
+    /// The debt the swap calldata was built for
+    pub debt: u128,
+    pub flash_liquidator: Address,
+    pub swap_calldata: Vec<u8>,
+    /// `None` if the gas couldn't be estimated ahead of time
+    pub gas_estimation: Option<U256>,
+}
+
+/// When the offer of an auction started at `auction_start` reaches
+/// `target_pct` percent of the collateral (unix timestamp, seconds)
+pub fn offer_reaches_at(auction_start: u64, duration: u64, initial_offer_pct: u64, target_pct: u16) -> u64 {
+    let target_pct = u64::from(target_pct);
+    if target_pct <= initial_offer_pct {
+        return auction_start;
+    }
+    if target_pct >= 100 || initial_offer_pct >= 100 {
+        return auction_start + duration;
+    }
+    // offer = elapsed * (100 - initial_offer) / duration + initial_offer, rounded down
+    let remaining_pct = 100 - initial_offer_pct;
+    auction_start + ((target_pct - initial_offer_pct) * duration + remaining_pct - 1) / remaining_pct
+}
+
+/// The offer (percent of the collateral) at which a liquidation which falls
+/// `profit_shortfall` short of our minimum profit at `current_offer_pct`
+/// becomes good enough, given that all of the collateral is worth
+/// `collateral_value` (both in base units). Every extra percent of offered
+/// collateral ends up in our pocket.
+///
+/// `None` if even the whole collateral isn't enough
+pub fn offer_for_profit(current_offer_pct: u16, profit_shortfall: U256, collateral_value: U256) -> Option<u16> {
+    if profit_shortfall.is_zero() {
+        return Some(current_offer_pct);
+    }
+    if collateral_value.is_zero() {
+        return None;
+    }
+    let extra_pct = (profit_shortfall * 100 + collateral_value - 1) / collateral_value;
+    if extra_pct > U256::from(100 - std::cmp::min(current_offer_pct, 100)) {
+        return None;
+    }
+    Some(current_offer_pct + extra_pct.as_u64() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer_at(now: u64, auction_start: u64, duration: u64, initial_offer_pct: u64) -> u64 {
+        // same as Liquidator::current_offer
+        std::cmp::min(100, (now - auction_start) * (100 - initial_offer_pct) / duration + initial_offer_pct)
+    }
+
+    #[test]
+    fn predicts_when_the_offer_reaches_the_target() {
+        let (start, duration, initial) = (1000, 3600, 50);
+        assert_eq!(offer_reaches_at(start, duration, initial, 40), start);
+        assert_eq!(offer_reaches_at(start, duration, initial, 100), start + duration);
+
+        for target in 51..100 {
+            let at = offer_reaches_at(start, duration, initial, target);
+            assert!(offer_at(at, start, duration, initial) >= target.into(), "target {}", target);
+            assert!(offer_at(at - 1, start, duration, initial) < target.into(), "target {}", target);
+        }
+
+        // uneven division
+        let at = offer_reaches_at(0, 7, 0, 50);
+        assert_eq!(at, 4);
+        assert_eq!(offer_at(at, 0, 7, 0), 57);
+    }
+
+    #[test]
+    fn computes_offer_for_profit() {
+        assert_eq!(offer_for_profit(60, U256::zero(), U256::from(1000)), Some(60));
+        // 1% of the collateral is worth 10
+        assert_eq!(offer_for_profit(60, U256::from(25), U256::from(1000)), Some(63));
+        assert_eq!(offer_for_profit(60, U256::from(400), U256::from(1000)), Some(100));
+        assert_eq!(offer_for_profit(60, U256::from(401), U256::from(1000)), None);
+        assert_eq!(offer_for_profit(60, U256::from(1), U256::zero()), None);
+    }
+}
@@ -0,0 +1,99 @@
+//! HTTP server
+//!
+//! Exposes the keeper's metrics and health to monitoring systems and
+//! orchestrators. The admin server, which lets the operator reset the
+//! circuit breaker, listens on its own address, which must be a loopback one.
+use crate::{
+    breaker::BREAKER,
+    health::{unix_now, HEALTH},
+    metrics,
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr};
+use tracing::info;
+
+/// Serves the HTTP endpoints on `addr` until an error occurs.
+///
+/// `/healthz` and `/readyz` fail when no block was processed in the last
+/// `max_block_age_secs` seconds
+pub async fn serve(addr: SocketAddr, max_block_age_secs: u64) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |req| handle(req, max_block_age_secs)))
+    });
+
+    info!(%addr, "Serving metrics and health");
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle(req: Request<Body>, max_block_age_secs: u64) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let (content_type, body) = metrics::render();
+            Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+        }
+        (&Method::GET, "/healthz") => {
+            let report = HEALTH.report(unix_now(), max_block_age_secs);
+            json_response(report.healthy, &report)
+        }
+        (&Method::GET, "/readyz") => {
+            let report = HEALTH.report(unix_now(), max_block_age_secs);
+            json_response(report.ready, &report)
+        }
+        (&Method::GET, "/breaker") => match BREAKER.status() {
+            Some(status) => json_response(true, &status),
+            None => json_response(false, &"no block processed yet"),
+        },
+        _ => not_found(),
+    };
+    Ok(response.expect("response is always valid"))
+}
+
+/// Serves the admin endpoints on `addr` until an error occurs. Anyone who
+/// can reach them can resume the keeper, hence the loopback address
+pub async fn serve_admin(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(handle_admin))
+    });
+
+    info!(%addr, "Serving admin endpoints");
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle_admin(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/breaker/resume") => {
+            info!("Circuit breaker reset requested");
+            BREAKER.request_resume();
+            json_response(true, &"resuming on the next block")
+        }
+        _ => not_found(),
+    };
+    Ok(response.expect("response is always valid"))
+}
+
+fn not_found() -> hyper::http::Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("not found"))
+}
+
+fn json_response<T: serde::Serialize>(ok: bool, body: &T) -> hyper::http::Result<Response<Body>> {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(body).expect("responses can always be serialized"),
+        ))
+}
@@ -0,0 +1,630 @@
+//! State persistence
+//!
+//! This module is responsible for persisting the keeper's state between runs.
+//! The state can either be dumped as a single JSON document or written row by
+//! row into an embedded SQLite database.
+use crate::{
+    bindings::{IlkIdType, SeriesIdType, VaultIdType},
+    borrowers::{Vault, VaultMap},
+    keeper::State,
+    liquidations::{AuctionMap, PendingTransaction},
+    relay::PendingBundle,
+};
+
+use ethers::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, io::Write, path::PathBuf};
+use thiserror::Error;
+use tracing::{debug, instrument};
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("corrupted state: {0}")]
+    Corrupted(String),
+}
+
+/// A backend the keeper's state can be saved to and restored from
+pub trait StateStore {
+    /// Loads the state persisted by a previous run, if there is one
+    fn load(&mut self) -> Result<Option<State>, StoreError>;
+
+    /// Persists the state. Called after every processed block
+    fn save(&mut self, state: &State) -> Result<(), StoreError>;
+
+    /// Persists the state unconditionally, e.g. before shutting down
+    fn flush(&mut self, state: &State) -> Result<(), StoreError> {
+        self.save(state)
+    }
+}
+
+/// Version of the JSON snapshot format written by `JsonFileStore`.
+///
+/// Bump it whenever `State` changes in a non backwards-compatible way and add
+/// the corresponding step to `migrate_snapshot`
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u64,
+    state: &'a State,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    version: u64,
+    state: serde_json::Value,
+}
+
+/// Dumps the whole state as a versioned JSON snapshot.
+///
+/// Snapshots are written to a temporary file which is then renamed over the
+/// previous one, so a crash mid-write never leaves a partial snapshot behind
+pub struct JsonFileStore {
+    path: PathBuf,
+    /// Only write the file once every `every_blocks` blocks (at least 1),
+    /// unless our pending transactions changed
+    every_blocks: u64,
+    /// The pending transactions in the last snapshot written
+    saved_pending: Vec<TxHash>,
+}
+
+impl JsonFileStore {
+    /// Constructor. `every_blocks` 0 saves every block, like 1
+    pub fn new(path: PathBuf, every_blocks: u64) -> Self {
+        JsonFileStore {
+            path,
+            every_blocks: every_blocks.max(1),
+            saved_pending: vec![],
+        }
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tmp.into()
+    }
+
+    fn write_snapshot(&mut self, state: &State) -> Result<(), StoreError> {
+        let tmp_path = self.tmp_path();
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            serde_json::to_writer(
+                &mut file,
+                &SnapshotRef {
+                    version: SNAPSHOT_VERSION,
+                    state,
+                },
+            )?;
+            file.flush()?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.saved_pending = state.pending_hashes();
+        Ok(())
+    }
+}
+
+impl StateStore for JsonFileStore {
+    fn load(&mut self) -> Result<Option<State>, StoreError> {
+        let data = match std::fs::read(&self.path) {
+            Ok(x) => x,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(x) => return Err(x.into()),
+        };
+        if data.iter().all(|x| x.is_ascii_whitespace()) {
+            return Ok(None);
+        }
+        let value: serde_json::Value = serde_json::from_slice(&data).map_err(|e| {
+            StoreError::Corrupted(format!("{:?} is not a valid snapshot: {}", self.path, e))
+        })?;
+        let state: State = serde_json::from_value(migrate_snapshot(value)?)
+            .map_err(|e| StoreError::Corrupted(format!("{:?} is not a valid snapshot: {}", self.path, e)))?;
+        self.saved_pending = state.pending_hashes();
+        Ok(Some(state))
+    }
+
+    fn save(&mut self, state: &State) -> Result<(), StoreError> {
+        // a transaction we'd forget about could be sent twice after a restart
+        if state.last_block % self.every_blocks != 0 && state.pending_hashes() == self.saved_pending {
+            return Ok(());
+        }
+        self.write_snapshot(state)
+    }
+
+    fn flush(&mut self, state: &State) -> Result<(), StoreError> {
+        self.write_snapshot(state)
+    }
+}
+
+/// Brings a snapshot of any known version up to `SNAPSHOT_VERSION` and
+/// returns the (still serialized) state it contains
+fn migrate_snapshot(value: serde_json::Value) -> Result<serde_json::Value, StoreError> {
+    // Version 0: the state itself, without any header
+    let Snapshot { mut version, mut state } = if value.get("version").is_some() {
+        serde_json::from_value(value)
+            .map_err(|e| StoreError::Corrupted(format!("bad snapshot header: {}", e)))?
+    } else {
+        Snapshot {
+            version: 0,
+            state: value,
+        }
+    };
+    if version > SNAPSHOT_VERSION {
+        return Err(StoreError::Corrupted(format!(
+            "snapshot version {} is newer than the supported version {}",
+            version, SNAPSHOT_VERSION
+        )));
+    }
+    while version < SNAPSHOT_VERSION {
+        state = match version {
+            // v0 -> v1: only the header was added
+            0 => state,
+            _ => unreachable!("no migration from snapshot version {}", version),
+        };
+        debug!(from = version, to = version + 1, "Migrated snapshot");
+        version += 1;
+    }
+    Ok(state)
+}
+
+/// Stores vaults and auctions as individual rows of an SQLite database.
+///
+/// Only the rows which changed since the previous save are written, so the
+/// store can be updated after every block and queried by other tools
+pub struct SqliteStore {
+    conn: Connection,
+    /// What we wrote last time: used to only write the rows that changed
+    saved_vaults: VaultMap,
+    saved_auctions: AuctionMap,
+}
+
+/// Version of the SQLite schema, stored in the database's `user_version`
+const SQLITE_SCHEMA_VERSION: u32 = 1;
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS vaults (
+        vault_id TEXT PRIMARY KEY,
+        is_initialized INTEGER NOT NULL,
+        is_collateralized INTEGER NOT NULL,
+        under_auction INTEGER NOT NULL,
+        level TEXT NOT NULL,
+        debt TEXT NOT NULL,
+        ilk_id TEXT NOT NULL,
+        series_id TEXT NOT NULL,
+        updated_block INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS auctions (
+        vault_id TEXT PRIMARY KEY,
+        active INTEGER NOT NULL,
+        updated_block INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+impl SqliteStore {
+    /// Opens (and creates, if needed) the database at `path`
+    pub fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SQLITE_SCHEMA_VERSION {
+            return Err(StoreError::Corrupted(format!(
+                "database schema version {} is newer than the supported version {}",
+                version, SQLITE_SCHEMA_VERSION
+            )));
+        }
+        conn.execute_batch(SQLITE_SCHEMA)?;
+        conn.pragma_update(None, "user_version", &SQLITE_SCHEMA_VERSION)?;
+        Ok(SqliteStore {
+            conn,
+            saved_vaults: VaultMap::new(),
+            saved_auctions: AuctionMap::new(),
+        })
+    }
+
+    fn get_meta(&self, key: &str) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM meta WHERE key = ?1", params![key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn load_vaults(&self) -> Result<VaultMap, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT vault_id, is_initialized, is_collateralized, under_auction, level, debt, ilk_id, series_id
+             FROM vaults",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, bool>(1)?,
+                row.get::<_, bool>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+                row.get::<_, String>(7)?,
+            ))
+        })?;
+
+        let mut vaults = VaultMap::new();
+        for row in rows {
+            let (vault_id, is_initialized, is_collateralized, under_auction, level, debt, ilk_id, series_id) =
+                row?;
+            let vault_id: VaultIdType = decode_id(&vault_id)?;
+            vaults.insert(
+                vault_id,
+                Vault {
+                    vault_id,
+                    is_initialized,
+                    is_collateralized,
+                    under_auction,
+                    level: I256::from_dec_str(&level)
+                        .map_err(|e| StoreError::Corrupted(format!("bad level {}: {:?}", level, e)))?,
+                    debt: debt
+                        .parse()
+                        .map_err(|e| StoreError::Corrupted(format!("bad debt {}: {:?}", debt, e)))?,
+                    ilk_id: decode_id::<IlkIdType>(&ilk_id)?,
+                    series_id: decode_id::<SeriesIdType>(&series_id)?,
+                },
+            );
+        }
+        Ok(vaults)
+    }
+
+    fn load_auctions(&self) -> Result<AuctionMap, StoreError> {
+        let mut stmt = self.conn.prepare("SELECT vault_id, active FROM auctions")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)))?;
+
+        let mut auctions = AuctionMap::new();
+        for row in rows {
+            let (vault_id, active) = row?;
+            auctions.insert(decode_id(&vault_id)?, active);
+        }
+        Ok(auctions)
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load(&mut self) -> Result<Option<State>, StoreError> {
+        let last_block = match self.get_meta("last_block")? {
+            Some(x) => x
+                .parse()
+                .map_err(|e| StoreError::Corrupted(format!("bad last_block {}: {:?}", x, e)))?,
+            // nothing was ever saved
+            None => return Ok(None),
+        };
+        let pending_liquidations = match self.get_meta("pending_liquidations")? {
+            Some(x) => serde_json::from_str::<Vec<PendingTransaction>>(&x)?,
+            None => vec![],
+        };
+        let pending_auctions = match self.get_meta("pending_auctions")? {
+            Some(x) => serde_json::from_str::<Vec<PendingTransaction>>(&x)?,
+            None => vec![],
+        };
+        let pending_cancellations = match self.get_meta("pending_cancellations")? {
+            Some(x) => serde_json::from_str::<Vec<PendingTransaction>>(&x)?,
+            None => vec![],
+        };
+        let pending_bundles = match self.get_meta("pending_bundles")? {
+            Some(x) => serde_json::from_str::<Vec<(VaultIdType, PendingBundle)>>(&x)?,
+            None => vec![],
+        };
+        let outcomes = match self.get_meta("outcomes")? {
+            Some(x) => serde_json::from_str(&x)?,
+            None => Default::default(),
+        };
+        let recent_blocks = match self.get_meta("recent_blocks")? {
+            Some(x) => serde_json::from_str(&x)?,
+            None => vec![],
+        };
+        let breaker = match self.get_meta("breaker")? {
+            Some(x) => serde_json::from_str(&x)?,
+            None => Default::default(),
+        };
+        let state = State {
+            vaults: self.load_vaults()?,
+            auctions: self.load_auctions()?,
+            last_block,
+            pending_liquidations: pending_liquidations.into_iter().map(|x| (x.vault_id, x)).collect(),
+            pending_auctions: pending_auctions.into_iter().map(|x| (x.vault_id, x)).collect(),
+            pending_cancellations: pending_cancellations
+                .into_iter()
+                .map(|x| (x.tx.nonce().copied().unwrap_or_default(), x))
+                .collect(),
+            pending_bundles: pending_bundles.into_iter().collect(),
+            outcomes,
+            recent_blocks,
+            breaker,
+        };
+        self.saved_vaults = state.vaults.clone();
+        self.saved_auctions = state.auctions.clone();
+        Ok(Some(state))
+    }
+
+    #[instrument(skip(self, state), fields(last_block = state.last_block))]
+    fn save(&mut self, state: &State) -> Result<(), StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut vaults_written = 0;
+        let mut auctions_written = 0;
+        {
+            let mut upsert_vault = tx.prepare_cached(
+                "INSERT OR REPLACE INTO vaults
+                 (vault_id, is_initialized, is_collateralized, under_auction, level, debt, ilk_id, series_id, updated_block)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for (vault_id, vault) in state.vaults.iter() {
+                if self.saved_vaults.get(vault_id) == Some(vault) {
+                    continue;
+                }
+                upsert_vault.execute(params![
+                    hex::encode(vault_id),
+                    vault.is_initialized,
+                    vault.is_collateralized,
+                    vault.under_auction,
+                    vault.level.to_string(),
+                    vault.debt.to_string(),
+                    hex::encode(vault.ilk_id),
+                    hex::encode(vault.series_id),
+                    state.last_block as i64,
+                ])?;
+                vaults_written += 1;
+            }
+
+            let mut upsert_auction = tx.prepare_cached(
+                "INSERT OR REPLACE INTO auctions (vault_id, active, updated_block) VALUES (?1, ?2, ?3)",
+            )?;
+            for (vault_id, active) in state.auctions.iter() {
+                if self.saved_auctions.get(vault_id) == Some(active) {
+                    continue;
+                }
+                upsert_auction.execute(params![hex::encode(vault_id), active, state.last_block as i64])?;
+                auctions_written += 1;
+            }
+
+            // auctions we've stopped tracking
+            let mut delete_auction = tx.prepare_cached("DELETE FROM auctions WHERE vault_id = ?1")?;
+            for vault_id in self.saved_auctions.keys() {
+                if !state.auctions.contains_key(vault_id) {
+                    delete_auction.execute(params![hex::encode(vault_id)])?;
+                    auctions_written += 1;
+                }
+            }
+
+            // in-flight transactions and recent block hashes are few: store them as JSON
+            let mut set_meta = tx.prepare_cached("INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)")?;
+            set_meta.execute(params![
+                "pending_liquidations",
+                serde_json::to_string(&state.pending_liquidations.values().collect::<Vec<_>>())?
+            ])?;
+            set_meta.execute(params![
+                "pending_auctions",
+                serde_json::to_string(&state.pending_auctions.values().collect::<Vec<_>>())?
+            ])?;
+            set_meta.execute(params![
+                "pending_cancellations",
+                serde_json::to_string(&state.pending_cancellations.values().collect::<Vec<_>>())?
+            ])?;
+            set_meta.execute(params![
+                "pending_bundles",
+                serde_json::to_string(&state.pending_bundles.iter().collect::<Vec<_>>())?
+            ])?;
+            set_meta.execute(params!["outcomes", serde_json::to_string(&state.outcomes)?])?;
+            set_meta.execute(params!["recent_blocks", serde_json::to_string(&state.recent_blocks)?])?;
+            set_meta.execute(params!["breaker", serde_json::to_string(&state.breaker)?])?;
+            set_meta.execute(params!["last_block", state.last_block.to_string()])?;
+        }
+        tx.commit()?;
+
+        self.saved_vaults = state.vaults.clone();
+        self.saved_auctions = state.auctions.clone();
+        debug!(vaults_written, auctions_written, "State saved");
+        Ok(())
+    }
+}
+
+fn decode_id<T: for<'a> std::convert::TryFrom<&'a [u8]>>(id: &str) -> Result<T, StoreError> {
+    let bytes = hex::decode(id).map_err(|e| StoreError::Corrupted(format!("bad id {}: {:?}", id, e)))?;
+    bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| StoreError::Corrupted(format!("bad id length: {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidations::{BundleMap, CancellationMap, PendingTransactionMap};
+    use crate::{breaker::CircuitBreaker, outcomes::OutcomeTracker};
+
+    fn sample_state(last_block: u64) -> State {
+        let mut vaults = VaultMap::new();
+        let vault_id = [1u8; 12];
+        vaults.insert(
+            vault_id,
+            Vault {
+                vault_id,
+                is_initialized: true,
+                is_collateralized: false,
+                under_auction: true,
+                level: I256::from(-42),
+                debt: u128::MAX,
+                ilk_id: [2u8; 6],
+                series_id: [3u8; 6],
+            },
+        );
+        let mut auctions = AuctionMap::new();
+        auctions.insert(vault_id, true);
+        let mut pending_auctions = PendingTransactionMap::new();
+        pending_auctions.insert(
+            vault_id,
+            PendingTransaction {
+                tx: TransactionRequest::new().nonce(7).into(),
+                hash: TxHash::repeat_byte(4),
+                submitted_at: 1234,
+                vault_id,
+                bumps: 2,
+                last_bumped_at: Some(1300),
+            },
+        );
+        let mut pending_bundles = BundleMap::new();
+        pending_bundles.insert(
+            [9u8; 12],
+            PendingBundle {
+                tx: TransactionRequest::new().nonce(9).into(),
+                raw_tx: vec![1, 2, 3].into(),
+                hash: TxHash::repeat_byte(8),
+                target_block: 101.into(),
+            },
+        );
+        let mut pending_cancellations = CancellationMap::new();
+        pending_cancellations.insert(
+            8.into(),
+            PendingTransaction {
+                tx: TransactionRequest::new().nonce(8).into(),
+                hash: TxHash::repeat_byte(6),
+                submitted_at: 1240,
+                vault_id,
+                bumps: 0,
+                last_bumped_at: None,
+            },
+        );
+        State {
+            auctions,
+            vaults,
+            last_block,
+            pending_liquidations: PendingTransactionMap::new(),
+            pending_auctions,
+            pending_cancellations,
+            pending_bundles,
+            outcomes: OutcomeTracker::default(),
+            recent_blocks: vec![(last_block, H256::repeat_byte(5))],
+            breaker: CircuitBreaker::default(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("yield-liquidator-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn json_snapshot_roundtrip() {
+        let path = temp_path("roundtrip.json");
+        let mut store = JsonFileStore::new(path.clone(), 1);
+        assert!(store.load().unwrap().is_none());
+
+        // a shorter snapshot should fully replace a longer one
+        store.save(&sample_state(100)).unwrap();
+        let mut state = sample_state(101);
+        state.vaults.clear();
+        store.save(&state).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.last_block, 101);
+        assert!(loaded.vaults.is_empty());
+        let raw: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(raw["version"], SNAPSHOT_VERSION);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_snapshot_every_zero_blocks_saves_every_block() {
+        let path = temp_path("every-zero.json");
+        let mut store = JsonFileStore::new(path.clone(), 0);
+        store.save(&sample_state(20)).unwrap();
+        store.save(&sample_state(21)).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().last_block, 21);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_snapshot_saves_new_pending_transactions() {
+        let path = temp_path("pending.json");
+        let mut store = JsonFileStore::new(path.clone(), 10);
+
+        let mut state = sample_state(20);
+        store.save(&state).unwrap();
+        state.last_block = 21;
+        store.save(&state).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().last_block, 20);
+
+        // a bump changes the hash
+        state.last_block = 22;
+        state.pending_cancellations.get_mut(&8.into()).unwrap().hash = TxHash::repeat_byte(7);
+        store.save(&state).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.last_block, 22);
+        assert_eq!(loaded.pending_hashes(), state.pending_hashes());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_snapshot_migrates_unversioned() {
+        let path = temp_path("legacy.json");
+        let state = sample_state(42);
+        std::fs::write(&path, serde_json::to_vec(&state).unwrap()).unwrap();
+
+        let loaded = JsonFileStore::new(path.clone(), 1).load().unwrap().unwrap();
+        assert_eq!(loaded.last_block, 42);
+        assert_eq!(loaded.vaults, state.vaults);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_snapshot_fails_loudly() {
+        let path = temp_path("garbage.json");
+        let mut store = JsonFileStore::new(path.clone(), 1);
+
+        std::fs::write(&path, b"{\"version\":1,\"state\":{}}trailing").unwrap();
+        assert!(matches!(store.load(), Err(StoreError::Corrupted(_))));
+
+        std::fs::write(&path, b"{\"version\":999,\"state\":{}}").unwrap();
+        assert!(matches!(store.load(), Err(StoreError::Corrupted(_))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sqlite_roundtrip() {
+        let path = temp_path("roundtrip.db");
+        let mut store = SqliteStore::open(path.clone()).unwrap();
+        assert!(store.load().unwrap().is_none());
+
+        let mut state = sample_state(100);
+        store.save(&state).unwrap();
+
+        // forget an auction: the row should go away
+        state.auctions.clear();
+        state.last_block = 101;
+        store.save(&state).unwrap();
+
+        let loaded = SqliteStore::open(path.clone()).unwrap().load().unwrap().unwrap();
+        assert_eq!(loaded.last_block, 101);
+        assert_eq!(loaded.vaults, state.vaults);
+        assert!(loaded.auctions.is_empty());
+        assert_eq!(loaded.pending_auctions[&[1u8; 12]].tx.nonce(), Some(&7.into()));
+        assert_eq!(loaded.pending_auctions[&[1u8; 12]].bumps, 2);
+        assert_eq!(loaded.pending_auctions[&[1u8; 12]].last_bumped_at, Some(1300));
+        assert_eq!(loaded.pending_cancellations[&8.into()].hash, TxHash::repeat_byte(6));
+        assert_eq!(loaded.pending_bundles[&[9u8; 12]].tx.nonce(), Some(&9.into()));
+        assert_eq!(loaded.pending_bundles[&[9u8; 12]].target_block, 101.into());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
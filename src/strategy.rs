@@ -0,0 +1,208 @@
+//! Liquidation strategy parameters
+//!
+//! The CLI sets the defaults; the config file can override them for an ilk,
+//! a base, or a (base, ilk) pair. The most specific override wins, field by
+//! field: pair, then ilk, then base, then the defaults.
+use crate::bindings::{BaseIdType, IlkIdType};
+
+use serde::Deserialize;
+use std::{collections::HashMap, convert::TryInto};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StrategyError {
+    #[error("invalid asset id {0}")]
+    InvalidAssetId(String),
+    #[error("strategy override without a Base or an Ilk")]
+    MissingKey,
+    #[error("several strategy overrides for Base {base:?} and Ilk {ilk:?}")]
+    DuplicateKey { base: Option<String>, ilk: Option<String> },
+}
+
+/// The parameters `Liquidator::buy` works with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StrategyParams {
+    /// The minimum ratio (collateral/debt) to trigger liquidation, percents
+    pub min_ratio: u16,
+    /// Buy an auction when this percentage of collateral is released
+    pub target_collateral_offer: u16,
+    /// Extra gas to use for txs, as percent of estimated gas cost
+    pub gas_boost: u16,
+}
+
+/// One entry of the config's `Strategies` list. At least one of `Base` and
+/// `Ilk` must be set; the parameters which are not set are inherited
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct StrategyOverride {
+    /// Base id, hex
+    pub base: Option<String>,
+    /// Ilk id, hex
+    pub ilk: Option<String>,
+    pub min_ratio: Option<u16>,
+    pub target_collateral_offer: Option<u16>,
+    pub gas_boost: Option<u16>,
+}
+
+impl StrategyOverride {
+    fn apply(&self, params: &mut StrategyParams) {
+        if let Some(x) = self.min_ratio {
+            params.min_ratio = x;
+        }
+        if let Some(x) = self.target_collateral_offer {
+            params.target_collateral_offer = x;
+        }
+        if let Some(x) = self.gas_boost {
+            params.gas_boost = x;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Strategy {
+    defaults: StrategyParams,
+    by_base: HashMap<BaseIdType, StrategyOverride>,
+    by_ilk: HashMap<IlkIdType, StrategyOverride>,
+    by_pair: HashMap<(BaseIdType, IlkIdType), StrategyOverride>,
+}
+
+impl Strategy {
+    /// Constructor
+    pub fn new(defaults: StrategyParams, overrides: Vec<StrategyOverride>) -> Result<Self, StrategyError> {
+        let mut strategy = Strategy {
+            defaults,
+            by_base: HashMap::new(),
+            by_ilk: HashMap::new(),
+            by_pair: HashMap::new(),
+        };
+        for x in overrides {
+            let duplicate = StrategyError::DuplicateKey {
+                base: x.base.clone(),
+                ilk: x.ilk.clone(),
+            };
+            let previous = match (parse_asset_id(&x.base)?, parse_asset_id(&x.ilk)?) {
+                (Some(base), Some(ilk)) => strategy.by_pair.insert((base, ilk), x),
+                (Some(base), None) => strategy.by_base.insert(base, x),
+                (None, Some(ilk)) => strategy.by_ilk.insert(ilk, x),
+                (None, None) => return Err(StrategyError::MissingKey),
+            };
+            // one of them would be silently ignored
+            if previous.is_some() {
+                return Err(duplicate);
+            }
+        }
+        Ok(strategy)
+    }
+
+    /// The effective parameters for an auction of `ilk_id` collateral for
+    /// `base_id` debt
+    pub fn resolve(&self, base_id: BaseIdType, ilk_id: IlkIdType) -> StrategyParams {
+        let mut params = self.defaults;
+        // least specific first, so that the more specific overrides win
+        for x in [
+            self.by_base.get(&base_id),
+            self.by_ilk.get(&ilk_id),
+            self.by_pair.get(&(base_id, ilk_id)),
+        ]
+        .iter()
+        .flatten()
+        {
+            x.apply(&mut params);
+        }
+        params
+    }
+}
+
+fn parse_asset_id(id: &Option<String>) -> Result<Option<[u8; 6]>, StrategyError> {
+    match id {
+        None => Ok(None),
+        Some(x) => hex::decode(x.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Some)
+            .ok_or_else(|| StrategyError::InvalidAssetId(x.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: StrategyParams = StrategyParams {
+        min_ratio: 110,
+        target_collateral_offer: 90,
+        gas_boost: 10,
+    };
+
+    #[test]
+    fn most_specific_override_wins() {
+        let overrides: Vec<StrategyOverride> = serde_json::from_str(
+            r#"[
+                {"Base": "303100000000", "MinRatio": 101, "GasBoost": 20},
+                {"Ilk": "303000000000", "MinRatio": 120, "TargetCollateralOffer": 80},
+                {"Base": "303100000000", "Ilk": "303000000000", "MinRatio": 130}
+            ]"#,
+        )
+        .unwrap();
+        let strategy = Strategy::new(DEFAULTS, overrides).unwrap();
+        let dai = [0x30, 0x31, 0, 0, 0, 0];
+        let eth = [0x30, 0x30, 0, 0, 0, 0];
+        let usdc = [0x30, 0x32, 0, 0, 0, 0];
+
+        assert_eq!(strategy.resolve(usdc, usdc), DEFAULTS);
+        assert_eq!(
+            strategy.resolve(dai, usdc),
+            StrategyParams {
+                min_ratio: 101,
+                gas_boost: 20,
+                ..DEFAULTS
+            }
+        );
+        assert_eq!(
+            strategy.resolve(usdc, eth),
+            StrategyParams {
+                min_ratio: 120,
+                target_collateral_offer: 80,
+                ..DEFAULTS
+            }
+        );
+        assert_eq!(
+            strategy.resolve(dai, eth),
+            StrategyParams {
+                min_ratio: 130,
+                target_collateral_offer: 80,
+                gas_boost: 20,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_overrides() {
+        assert!(matches!(
+            Strategy::new(DEFAULTS, vec![StrategyOverride::default()]),
+            Err(StrategyError::MissingKey)
+        ));
+        let bad_id = StrategyOverride {
+            ilk: Some("3030".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Strategy::new(DEFAULTS, vec![bad_id]),
+            Err(StrategyError::InvalidAssetId(_))
+        ));
+        // the same key twice, however it is spelled
+        for duplicates in [
+            r#"[{"Base": "303100000000", "MinRatio": 101}, {"Base": "0x303100000000", "GasBoost": 20}]"#,
+            r#"[{"Ilk": "303000000000", "MinRatio": 101}, {"Ilk": "303000000000", "MinRatio": 102}]"#,
+            r#"[{"Base": "303100000000", "Ilk": "303000000000"}, {"Ilk": "303000000000", "Base": "303100000000"}]"#,
+        ]
+        .iter()
+        {
+            let overrides: Vec<StrategyOverride> = serde_json::from_str(duplicates).unwrap();
+            assert!(matches!(
+                Strategy::new(DEFAULTS, overrides),
+                Err(StrategyError::DuplicateKey { .. })
+            ));
+        }
+    }
+}
@@ -1,79 +1,291 @@
-//! Immutable data cache
+//! Swap routing
 //!
+//! A bid's swap calldata can come from several route providers: the Node
+//! router binary, the in-process Uniswap V3 quoter, or an HTTP aggregator.
+//! They're all asked at once, and the route taking the least collateral wins.
+
+use crate::{
+    aggregator::AggregatorError,
+    health::HEALTH,
+    metrics,
+    uniswap::{decode_exact_output, UniswapError},
+};
 
 use async_process::Command;
+use async_trait::async_trait;
 use ethers::prelude::*;
+use futures_util::future::join_all;
+use std::{fmt, sync::Arc, time::Duration};
 use thiserror::Error;
-use tracing::instrument;
+use tracing::{debug, instrument, warn};
 
 use serde::Deserialize;
 
+/// What the router binary answers
 #[derive(Deserialize)]
-struct RouterResult {
+pub(crate) struct RouterResult {
     data: String,
+    /// The collateral the route takes, as quoted
+    amount_in: String,
 }
 
-#[derive(Clone)]
-pub struct SwapRouter {
-    pub rpc_url: String,
-    pub chain_id: u64,
-    pub uni_router_02: Address,
-    pub flash_liquidator: Address,
-    pub router_binary_path: String,
-    pub instance_name: String,
+impl RouterResult {
+    pub(crate) fn into_swap(self) -> std::result::Result<SwapCalldata, SwapRouterError> {
+        let calldata = hex::decode(self.data.trim_start_matches("0x")).map_err(|e| {
+            SwapRouterError::RouterError(format!("failed to deserialize hex calldata: {:?}", e))
+        })?;
+        let amount_in = U256::from_dec_str(&self.amount_in).map_err(|e| {
+            SwapRouterError::RouterError(format!("failed to deserialize amount in: {:?}", e))
+        })?;
+        Ok(SwapCalldata { calldata, amount_in })
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum SwapRouterError {
     #[error("router error")]
     RouterError(String),
+    #[error("uniswap error: {0}")]
+    Uniswap(#[from] UniswapError),
+    #[error("aggregator error: {0}")]
+    Aggregator(#[from] AggregatorError),
+    #[error("no route: {0}")]
+    NoRoute(String),
+    #[error("bad calldata: {0}")]
+    BadCalldata(String),
+    #[error("router unreachable: {0}")]
+    Unreachable(String),
     #[error("unknown error")]
     Unknown,
 }
 
+impl SwapRouterError {
+    /// Whether the provider couldn't be reached at all: it couldn't be started,
+    /// or the connection to it failed. Answering without a route doesn't count
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            SwapRouterError::Unreachable(_) => true,
+            SwapRouterError::Aggregator(AggregatorError::Http(x)) => x.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct SwapCalldata {
     pub calldata: Vec<u8>,
+    /// The collateral the swap takes, as quoted
+    pub amount_in: U256,
+}
+
+/// Something which builds the calldata of an exact-output swap
+#[async_trait]
+pub trait SwapRouteProvider: fmt::Debug + Send + Sync {
+    /// For the logs
+    fn name(&self) -> &str;
+
+    /// The calldata buying `amount_out` of `token_out` with `token_in`, for
+    /// `recipient`, with the quoted input
+    async fn build_swap_exact_out(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> std::result::Result<SwapCalldata, SwapRouterError>;
+}
+
+#[derive(Clone)]
+pub struct SwapRouter {
+    /// Asked at once for every swap
+    pub providers: Vec<Arc<dyn SwapRouteProvider>>,
+    /// How long to wait for the providers
+    pub timeout: Duration,
+    pub flash_liquidator: Address,
+    pub instance_name: String,
 }
 
 impl SwapRouter {
     /// Constructor
     pub fn new(
-        rpc_url: String,
-        chain_id: u64,
-        uni_router_02: Address,
+        providers: Vec<Arc<dyn SwapRouteProvider>>,
+        timeout: Duration,
         flash_liquidator: Address,
-        router_binary_path: String,
         instance_name: String,
     ) -> Self {
         SwapRouter {
-            rpc_url,
-            chain_id,
-            uni_router_02,
+            providers,
+            timeout,
             flash_liquidator,
-            router_binary_path,
             instance_name,
         }
     }
 
+    /// The same router, building swaps for another flash liquidator contract
+    pub fn for_flash_liquidator(&self, flash_liquidator: Address) -> SwapRouter {
+        SwapRouter {
+            flash_liquidator,
+            ..self.clone()
+        }
+    }
+
     #[instrument(skip(self), fields(self.instance_name))]
     pub async fn build_swap_exact_out(
         &self,
         token_in: Address,
         token_out: Address,
         amount_in: U256,
+    ) -> std::result::Result<SwapCalldata, SwapRouterError> {
+        let timer = metrics::SWAP_ROUTER_LATENCY.start_timer();
+        let result = self.best_route(token_in, token_out, amount_in).await;
+        timer.observe_duration();
+        if result.is_err() {
+            metrics::SWAP_ROUTER_FAILURES.inc();
+        }
+        result
+    }
+
+    /// Asks all the providers, and picks the route taking the least collateral.
+    /// Providers which fail or don't answer within `timeout` are left out, and
+    /// so are the routes which don't do what we asked (see `max_amount_in`).
+    /// Routes are compared by what their calldata may take, not by what their
+    /// provider says they will.
+    ///
+    /// The swap router is only reported unreachable when none of the providers
+    /// could be reached; timeouts tell nothing either way
+    async fn best_route(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> std::result::Result<SwapCalldata, SwapRouterError> {
+        let routes = join_all(self.providers.iter().map(|provider| async move {
+            let route = tokio::time::timeout(
+                self.timeout,
+                provider.build_swap_exact_out(self.flash_liquidator, token_in, token_out, amount_out),
+            )
+            .await;
+            (provider.name(), route)
+        }))
+        .await;
+
+        let mut best: Option<(&str, SwapCalldata, U256)> = None;
+        let mut errors = vec![];
+        let mut reachable = None;
+        for (name, route) in routes {
+            match route {
+                Ok(Ok(route)) => {
+                    reachable = Some(true);
+                    match self.max_amount_in(&route, token_in, token_out, amount_out) {
+                        Ok(max_amount_in) => {
+                            debug!(provider = name, amount_in = %route.amount_in, %max_amount_in, "Route quoted");
+                            if best.as_ref().map(|(_, _, x)| max_amount_in < *x).unwrap_or(true) {
+                                best = Some((name, route, max_amount_in));
+                            }
+                        }
+                        Err(x) => {
+                            warn!(provider = name, err = ?x, "Rejected a route");
+                            errors.push(format!("{}: {}", name, x));
+                        }
+                    }
+                }
+                Ok(Err(x)) => {
+                    reachable = Some(reachable.unwrap_or(false) || !x.is_unreachable());
+                    warn!(provider = name, err = ?x, "Failed to build a route");
+                    errors.push(format!("{}: {}", name, x));
+                }
+                Err(_) => {
+                    warn!(provider = name, timeout = ?self.timeout, "Route provider timed out");
+                    errors.push(format!("{}: timed out", name));
+                }
+            }
+        }
+        if let Some(reachable) = reachable {
+            HEALTH.set_swap_router_reachable(reachable);
+        }
+        match best {
+            Some((name, route, max_amount_in)) => {
+                debug!(provider = name, amount_in = %route.amount_in, %max_amount_in, "Route selected");
+                Ok(route)
+            }
+            None => Err(SwapRouterError::NoRoute(errors.join("; "))),
+        }
+    }
+
+    /// The most collateral `route` may take, if its calldata is a SwapRouter02
+    /// exact-output swap of `token_in` for `amount_out` of `token_out`, paying
+    /// the flash liquidator
+    fn max_amount_in(
+        &self,
+        route: &SwapCalldata,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> std::result::Result<U256, SwapRouterError> {
+        let swap = decode_exact_output(&route.calldata).ok_or_else(|| {
+            SwapRouterError::BadCalldata(format!("not a SwapRouter02 exact-output swap: 0x{}", hex::encode(&route.calldata)))
+        })?;
+        if !swap.pays(self.flash_liquidator) {
+            return Err(SwapRouterError::BadCalldata(format!(
+                "pays {:?} instead of {:?}", swap.recipient, self.flash_liquidator
+            )));
+        }
+        if swap.token_in != token_in || swap.token_out != token_out || swap.amount_out != amount_out {
+            return Err(SwapRouterError::BadCalldata(format!(
+                "swaps {:?} for {} {:?} instead of {:?} for {} {:?}",
+                swap.token_in, swap.amount_out, swap.token_out, token_in, amount_out, token_out
+            )));
+        }
+        Ok(swap.amount_in_maximum)
+    }
+}
+
+/// The Node router (`scripts/router.ts`), run once per swap
+#[derive(Clone, Debug)]
+pub struct BinaryRouteProvider {
+    pub rpc_url: String,
+    pub chain_id: u64,
+    pub router_binary_path: String,
+}
+
+impl BinaryRouteProvider {
+    /// Constructor
+    pub fn new(rpc_url: String, chain_id: u64, router_binary_path: String) -> Self {
+        BinaryRouteProvider {
+            rpc_url,
+            chain_id,
+            router_binary_path,
+        }
+    }
+}
+
+#[async_trait]
+impl SwapRouteProvider for BinaryRouteProvider {
+    fn name(&self) -> &str {
+        "binary"
+    }
+
+    async fn build_swap_exact_out(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
     ) -> std::result::Result<SwapCalldata, SwapRouterError> {
         let out = Command::new(self.router_binary_path.as_str())
             .arg(format!("--rpc_url={}", self.rpc_url))
             .arg(format!("--chain_id={}", self.chain_id))
-            .arg(format!("--from_address={:?}", self.flash_liquidator))
+            .arg(format!("--from_address={:?}", recipient))
             .arg(format!("--token_in={:?}", token_in))
             .arg(format!("--token_out={:?}", token_out))
-            .arg(format!("--amount_out={}", amount_in))
+            .arg(format!("--amount_out={}", amount_out))
             .arg(format!("--silent"))
+            // don't leave the router running if we time out
+            .kill_on_drop(true)
             .output()
             .await
             .map_err(|io_error| {
-                SwapRouterError::RouterError(format!(
+                SwapRouterError::Unreachable(format!(
                     "Failed to call external router: {:}",
                     io_error
                 ))
@@ -115,29 +327,165 @@ fn stdout_to_swap(stdout: &[u8]) -> std::result::Result<SwapCalldata, SwapRouter
             String::from_utf8(stdout.to_vec())
         ));
     })?;
-    let calldata = hex::decode(&router_result.data[2..]).map_err(|e| {
-        SwapRouterError::RouterError(format!("failed to deserialize hex calldata: {:?}", e))
-    })?;
-    return Ok(SwapCalldata { calldata });
+    router_result.into_swap()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::uniswap::{exact_output_calldata, Route};
     use std::str::FromStr;
 
+    /// Quotes `amount_in`, after `delay`, with calldata taking at most `max_amount_in`
+    #[derive(Debug)]
+    struct FixedProvider {
+        name: &'static str,
+        amount_in: Option<u64>,
+        max_amount_in: u64,
+        recipient: Address,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl SwapRouteProvider for FixedProvider {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn build_swap_exact_out(
+            &self,
+            _recipient: Address,
+            token_in: Address,
+            token_out: Address,
+            amount_out: U256,
+        ) -> std::result::Result<SwapCalldata, SwapRouterError> {
+            tokio::time::sleep(self.delay).await;
+            let route = Route { tokens: vec![token_in, token_out], fees: vec![500] };
+            let calldata = exact_output_calldata(&route, self.recipient, amount_out, self.max_amount_in.into());
+            match self.amount_in {
+                Some(x) => Ok(SwapCalldata { calldata, amount_in: x.into() }),
+                None => Err(SwapRouterError::Unknown),
+            }
+        }
+    }
+
+    fn provider(name: &'static str, amount_in: Option<u64>, delay_ms: u64) -> Arc<dyn SwapRouteProvider> {
+        Arc::new(FixedProvider {
+            name,
+            amount_in,
+            max_amount_in: amount_in.unwrap_or_default() * 2,
+            recipient: FLASH_LIQUIDATOR,
+            delay: Duration::from_millis(delay_ms),
+        })
+    }
+
+    const FLASH_LIQUIDATOR: Address = H160([9; 20]);
+
+    fn route_calldata(amount_in: u64) -> Vec<u8> {
+        let route = Route { tokens: vec![Address::zero(), Address::zero()], fees: vec![500] };
+        exact_output_calldata(&route, FLASH_LIQUIDATOR, U256::one(), (amount_in * 2).into())
+    }
+
+    #[tokio::test]
+    async fn picks_the_cheapest_route_in_time() {
+        let router = SwapRouter::new(
+            vec![
+                provider("expensive", Some(120), 0),
+                provider("cheap", Some(100), 10),
+                provider("failing", None, 0),
+                provider("slow", Some(50), 10_000),
+            ],
+            Duration::from_millis(500),
+            FLASH_LIQUIDATOR,
+            "".to_string(),
+        );
+        let route = router
+            .build_swap_exact_out(Address::zero(), Address::zero(), U256::one())
+            .await
+            .unwrap();
+        assert_eq!(route.calldata, route_calldata(100));
+        assert_eq!(route.amount_in, 100.into());
+    }
+
+    #[tokio::test]
+    async fn trusts_the_calldata_rather_than_the_quote() {
+        let provider = |name, amount_in, max_amount_in, recipient| -> Arc<dyn SwapRouteProvider> {
+            Arc::new(FixedProvider { name, amount_in: Some(amount_in), max_amount_in, recipient, delay: Duration::from_millis(0) })
+        };
+        let router = SwapRouter::new(
+            vec![
+                // quotes less than the others, but may take more
+                provider("greedy", 10, 300, FLASH_LIQUIDATOR),
+                provider("honest", 100, 200, FLASH_LIQUIDATOR),
+                provider("thief", 1, 1, Address::repeat_byte(6)),
+            ],
+            Duration::from_millis(500),
+            FLASH_LIQUIDATOR,
+            "".to_string(),
+        );
+        let route = router
+            .build_swap_exact_out(Address::zero(), Address::zero(), U256::one())
+            .await
+            .unwrap();
+        assert_eq!(route.amount_in, 100.into());
+
+        // a route for another amount, and calldata which isn't a swap
+        let router = SwapRouter::new(vec![provider("honest", 100, 200, FLASH_LIQUIDATOR)], Duration::from_millis(500),
+            FLASH_LIQUIDATOR, "".to_string());
+        let err = router
+            .build_swap_exact_out(Address::zero(), Address::zero(), 2.into())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, SwapRouterError::NoRoute(x) if x.contains("instead of")));
+        let err = router
+            .max_amount_in(&SwapCalldata { calldata: vec![1, 2], amount_in: 1.into() }, Address::zero(), Address::zero(), 1.into())
+            .err()
+            .unwrap();
+        assert!(matches!(err, SwapRouterError::BadCalldata(_)));
+    }
+
+    #[tokio::test]
+    async fn fails_without_routes() {
+        let router = SwapRouter::new(
+            vec![provider("failing", None, 0), provider("slow", Some(50), 10_000)],
+            Duration::from_millis(100),
+            Address::zero(),
+            "".to_string(),
+        );
+        let err = router
+            .build_swap_exact_out(Address::zero(), Address::zero(), U256::one())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, SwapRouterError::NoRoute(x) if x.contains("slow: timed out")));
+    }
+
+    #[test]
+    fn only_transport_failures_are_unreachable() {
+        assert!(SwapRouterError::Unreachable("spawn failed".into()).is_unreachable());
+        assert!(!SwapRouterError::NoRoute("binary: timed out".into()).is_unreachable());
+        assert!(!SwapRouterError::RouterError("no route".into()).is_unreachable());
+    }
+
+    #[test]
+    fn parses_router_output() {
+        let swap = stdout_to_swap(br#"{"data": "0x0102", "amount_in": "1000"}"#).unwrap();
+        assert_eq!(swap.calldata, vec![1, 2]);
+        assert_eq!(swap.amount_in, 1000.into());
+        assert!(stdout_to_swap(br#"{"data": "0x0102"}"#).is_err());
+    }
+
     #[tokio::test]
     async fn swap_weth_for_usdc() {
-        let sr = SwapRouter::new(
+        let provider = BinaryRouteProvider::new(
             "http://127.0.0.1:8545/".to_string(),
             1,
-            Address::from_str("0x68b3465833fb72A70ecDF485E0e4C7bD8665Fc45").unwrap(),
-            Address::zero(),
             "build/bin/router".to_string(),
-            "".to_string(),
         );
-        let maybe_swap = sr
+        let maybe_swap = provider
             .build_swap_exact_out(
+                Address::zero(),
                 Address::from_str("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2").unwrap(),
                 Address::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap(),
                 U256::one(), //U256::from(10).pow(U256::from(18))
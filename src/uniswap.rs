@@ -0,0 +1,482 @@
+//! Uniswap V3 route builder
+//!
+//! Builds the swap a flash liquidator makes without going through the Node
+//! router: exact-output quotes are asked from the QuoterV2 contract
+//! (`eth_call`) for every fee tier, directly or through a well-traded token,
+//! and the cheapest route is encoded as a SwapRouter02 `exactOutputSingle` or
+//! `exactOutput` call. The quotes go through the keeper's own client, so they
+//! use the same transport and endpoints as everything else.
+use crate::swap_router::{SwapCalldata, SwapRouteProvider, SwapRouterError};
+
+use async_trait::async_trait;
+use ethers::{
+    abi::{self, ParamType, Token},
+    prelude::*,
+    utils::id,
+};
+use ethers_core::types::transaction::eip2718::TypedTransaction;
+use futures_util::future::join_all;
+use std::{fmt, sync::Arc};
+use thiserror::Error;
+use tracing::{debug, instrument};
+
+/// The fee tiers of Uniswap V3 pools, in hundredths of a bip
+pub const DEFAULT_FEE_TIERS: [u32; 4] = [100, 500, 3000, 10000];
+
+/// QuoterV2 on mainnet
+pub const MAINNET_QUOTER_V2: &str = "0x61fFE014bA17989E743c5F6cB21bF9697530B21e";
+
+/// WETH, USDC and DAI on mainnet: two-hop routes go through them
+pub const MAINNET_INTERMEDIARIES: [&str; 3] = [
+    "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2",
+    "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+    "0x6B175474E89094C44Da98b954EedeAC495271d0F",
+];
+
+const QUOTE_EXACT_OUTPUT_SINGLE: &str = "quoteExactOutputSingle((address,address,uint256,uint24,uint160))";
+const QUOTE_EXACT_OUTPUT: &str = "quoteExactOutput(bytes,uint256)";
+const EXACT_OUTPUT_SINGLE: &str = "exactOutputSingle((address,address,uint24,address,uint256,uint256,uint160))";
+const EXACT_OUTPUT: &str = "exactOutput((bytes,address,uint256,uint256))";
+const MULTICALL: &str = "multicall(bytes[])";
+const MULTICALL_WITH_DEADLINE: &str = "multicall(uint256,bytes[])";
+
+/// The recipient SwapRouter02 replaces with `msg.sender`, i.e. the flash liquidator
+const MSG_SENDER: u64 = 1;
+
+#[derive(Error, Debug)]
+pub enum UniswapError {
+    #[error("rpc error: {0}")]
+    Rpc(String),
+    #[error("unexpected quoter output: {0}")]
+    BadQuote(String),
+    #[error("no route from {token_in:?} to {token_out:?}")]
+    NoRoute { token_in: Address, token_out: Address },
+}
+
+/// A path through Uniswap V3 pools
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    /// From the token we sell to the token we buy
+    pub tokens: Vec<Address>,
+    /// The fee tier of the pool between each pair of `tokens`
+    pub fees: Vec<u32>,
+}
+
+impl Route {
+    /// The path as `exactOutput` expects it: from the token we buy to the
+    /// token we sell, with the pools' fees (3 bytes) in between
+    pub fn exact_output_path(&self) -> Vec<u8> {
+        let mut path = Vec::with_capacity(self.tokens.len() * 23);
+        for (i, token) in self.tokens.iter().enumerate().rev() {
+            path.extend_from_slice(token.as_bytes());
+            if i > 0 {
+                path.extend_from_slice(&self.fees[i - 1].to_be_bytes()[1..]);
+            }
+        }
+        path
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.tokens[0])?;
+        for (token, fee) in self.tokens[1..].iter().zip(self.fees.iter()) {
+            write!(f, " -({})-> {:?}", fee, token)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a SwapRouter02 exact-output call does
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExactOutput {
+    pub token_in: Address,
+    pub token_out: Address,
+    pub recipient: Address,
+    pub amount_out: U256,
+    /// The most it may take, slippage included
+    pub amount_in_maximum: U256,
+}
+
+impl ExactOutput {
+    /// Whether the output goes to `recipient` when it makes the call
+    pub fn pays(&self, recipient: Address) -> bool {
+        self.recipient == recipient || self.recipient == Address::from_low_u64_be(MSG_SENDER)
+    }
+}
+
+/// The input a route needs for the output we want
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quote {
+    pub route: Route,
+    pub amount_in: U256,
+}
+
+#[derive(Debug)]
+pub struct UniswapV3Router<M> {
+    client: Arc<M>,
+    quoter: Address,
+    swap_router_02: Address,
+    fee_tiers: Vec<u32>,
+    /// Tokens two-hop routes go through
+    intermediaries: Vec<Address>,
+    /// How much more than quoted the swap may take, percent
+    slippage_pct: u16,
+}
+
+impl<M: Middleware> UniswapV3Router<M> {
+    /// Constructor
+    pub fn new(
+        client: Arc<M>,
+        quoter: Address,
+        swap_router_02: Address,
+        fee_tiers: Vec<u32>,
+        intermediaries: Vec<Address>,
+        slippage_pct: u16,
+    ) -> Self {
+        UniswapV3Router {
+            client,
+            quoter,
+            swap_router_02,
+            fee_tiers,
+            intermediaries,
+            slippage_pct,
+        }
+    }
+
+    /// The cheapest route to buy `amount_out` of `token_out` with `token_in`.
+    /// All the candidate routes are quoted at once; the ones without a pool
+    /// (whose quote reverts) are left out
+    #[instrument(skip(self))]
+    pub async fn quote_exact_out(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<Quote, UniswapError> {
+        let routes = candidate_routes(token_in, token_out, &self.fee_tiers, &self.intermediaries);
+        let quotes = join_all(routes.into_iter().map(|route| self.quote(route, amount_out))).await;
+        quotes
+            .into_iter()
+            .filter_map(|x| x.map_err(|e| debug!(err = ?e, "no quote")).ok())
+            .min_by_key(|x| x.amount_in)
+            .ok_or(UniswapError::NoRoute { token_in, token_out })
+    }
+
+    /// The SwapRouter02 calldata buying `amount_out` of `token_out` with
+    /// `token_in` for `recipient` through the cheapest route, with the quote
+    pub async fn build_swap_exact_out(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<(Vec<u8>, Quote), UniswapError> {
+        let quote = self.quote_exact_out(token_in, token_out, amount_out).await?;
+        let max_amount_in = with_slippage(quote.amount_in, self.slippage_pct);
+        debug!(route = %quote.route, amount_in = %quote.amount_in, %max_amount_in,
+            swap_router = ?self.swap_router_02, "Uniswap route selected");
+        let calldata = exact_output_calldata(&quote.route, recipient, amount_out, max_amount_in);
+        Ok((calldata, quote))
+    }
+
+    async fn quote(&self, route: Route, amount_out: U256) -> Result<Quote, UniswapError> {
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(self.quoter)
+            .data(quote_calldata(&route, amount_out))
+            .into();
+        let out = self
+            .client
+            .call(&tx, None)
+            .await
+            .map_err(|x| UniswapError::Rpc(x.to_string()))?;
+        let amount_in = decode_amount_in(&out)?;
+        Ok(Quote { route, amount_in })
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> SwapRouteProvider for UniswapV3Router<M> {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    async fn build_swap_exact_out(
+        &self,
+        recipient: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Result<SwapCalldata, SwapRouterError> {
+        let (calldata, quote) =
+            UniswapV3Router::build_swap_exact_out(self, recipient, token_in, token_out, amount_out).await?;
+        Ok(SwapCalldata { calldata, amount_in: quote.amount_in })
+    }
+}
+
+/// Every fee tier directly, and every pair of fee tiers through each
+/// intermediary which isn't one of the tokens
+pub fn candidate_routes(
+    token_in: Address,
+    token_out: Address,
+    fee_tiers: &[u32],
+    intermediaries: &[Address],
+) -> Vec<Route> {
+    let mut routes: Vec<Route> = fee_tiers
+        .iter()
+        .map(|fee| Route { tokens: vec![token_in, token_out], fees: vec![*fee] })
+        .collect();
+    for mid in intermediaries.iter().filter(|x| **x != token_in && **x != token_out) {
+        for fee_in in fee_tiers {
+            for fee_out in fee_tiers {
+                routes.push(Route {
+                    tokens: vec![token_in, *mid, token_out],
+                    fees: vec![*fee_in, *fee_out],
+                });
+            }
+        }
+    }
+    routes
+}
+
+/// `amount_in`, plus `slippage_pct` percent
+pub fn with_slippage(amount_in: U256, slippage_pct: u16) -> U256 {
+    amount_in * (100 + u64::from(slippage_pct)) / 100
+}
+
+/// The QuoterV2 call quoting `route`
+fn quote_calldata(route: &Route, amount_out: U256) -> Vec<u8> {
+    if route.fees.len() == 1 {
+        encode_call(
+            QUOTE_EXACT_OUTPUT_SINGLE,
+            vec![Token::Tuple(vec![
+                Token::Address(route.tokens[0]),
+                Token::Address(route.tokens[1]),
+                Token::Uint(amount_out),
+                Token::Uint(route.fees[0].into()),
+                Token::Uint(U256::zero()),
+            ])],
+        )
+    } else {
+        encode_call(
+            QUOTE_EXACT_OUTPUT,
+            vec![Token::Bytes(route.exact_output_path()), Token::Uint(amount_out)],
+        )
+    }
+}
+
+/// The SwapRouter02 call swapping at most `max_amount_in` for `amount_out` through `route`
+pub fn exact_output_calldata(route: &Route, recipient: Address, amount_out: U256, max_amount_in: U256) -> Vec<u8> {
+    if route.fees.len() == 1 {
+        encode_call(
+            EXACT_OUTPUT_SINGLE,
+            vec![Token::Tuple(vec![
+                Token::Address(route.tokens[0]),
+                Token::Address(route.tokens[1]),
+                Token::Uint(route.fees[0].into()),
+                Token::Address(recipient),
+                Token::Uint(amount_out),
+                Token::Uint(max_amount_in),
+                Token::Uint(U256::zero()),
+            ])],
+        )
+    } else {
+        encode_call(
+            EXACT_OUTPUT,
+            vec![Token::Tuple(vec![
+                Token::Bytes(route.exact_output_path()),
+                Token::Address(recipient),
+                Token::Uint(amount_out),
+                Token::Uint(max_amount_in),
+            ])],
+        )
+    }
+}
+
+fn encode_call(signature: &str, args: Vec<Token>) -> Vec<u8> {
+    let mut calldata = id(signature).to_vec();
+    calldata.extend(abi::encode(&args));
+    calldata
+}
+
+/// Decodes a SwapRouter02 `exactOutputSingle` or `exactOutput` call, alone or
+/// as the only call of a `multicall`, as the Node router sends them
+pub fn decode_exact_output(calldata: &[u8]) -> Option<ExactOutput> {
+    if calldata.len() < 4 {
+        return None;
+    }
+    let (selector, args) = calldata.split_at(4);
+    if selector == id(EXACT_OUTPUT_SINGLE) {
+        let tokens = abi::decode(
+            &[ParamType::Address, ParamType::Address, ParamType::Uint(24), ParamType::Address,
+                ParamType::Uint(256), ParamType::Uint(256), ParamType::Uint(160)],
+            args,
+        )
+        .ok()?;
+        Some(ExactOutput {
+            token_in: tokens[0].clone().into_address()?,
+            token_out: tokens[1].clone().into_address()?,
+            recipient: tokens[3].clone().into_address()?,
+            amount_out: tokens[4].clone().into_uint()?,
+            amount_in_maximum: tokens[5].clone().into_uint()?,
+        })
+    } else if selector == id(EXACT_OUTPUT) {
+        let params = abi::decode(
+            &[ParamType::Tuple(vec![ParamType::Bytes, ParamType::Address, ParamType::Uint(256), ParamType::Uint(256)])],
+            args,
+        )
+        .ok()?
+        .pop()?
+        .into_tuple()?;
+        // reversed: from the token we buy to the token we sell
+        let path = params[0].clone().into_bytes()?;
+        if path.len() < 43 || (path.len() - 20) % 23 != 0 {
+            return None;
+        }
+        Some(ExactOutput {
+            token_in: Address::from_slice(&path[path.len() - 20..]),
+            token_out: Address::from_slice(&path[..20]),
+            recipient: params[1].clone().into_address()?,
+            amount_out: params[2].clone().into_uint()?,
+            amount_in_maximum: params[3].clone().into_uint()?,
+        })
+    } else {
+        let calls = if selector == id(MULTICALL) {
+            abi::decode(&[ParamType::Array(Box::new(ParamType::Bytes))], args).ok()?.pop()?
+        } else if selector == id(MULTICALL_WITH_DEADLINE) {
+            abi::decode(&[ParamType::Uint(256), ParamType::Array(Box::new(ParamType::Bytes))], args).ok()?.pop()?
+        } else {
+            return None;
+        };
+        match calls.into_array()?.as_slice() {
+            [Token::Bytes(call)] => decode_exact_output(call),
+            _ => None,
+        }
+    }
+}
+
+/// Both quoter functions return the input amount first
+fn decode_amount_in(out: &[u8]) -> Result<U256, UniswapError> {
+    if out.len() < 32 {
+        return Err(UniswapError::BadQuote(hex::encode(out)));
+    }
+    Ok(U256::from_big_endian(&out[..32]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(x: u64) -> Address {
+        Address::from_low_u64_be(x)
+    }
+
+    #[test]
+    fn lists_direct_and_two_hop_routes() {
+        let routes = candidate_routes(addr(1), addr(2), &[500, 3000], &[addr(2), addr(3)]);
+        // 2 direct, 2x2 through addr(3); addr(2) is the output token
+        assert_eq!(routes.len(), 6);
+        assert_eq!(routes[1], Route { tokens: vec![addr(1), addr(2)], fees: vec![3000] });
+        assert_eq!(routes[3], Route { tokens: vec![addr(1), addr(3), addr(2)], fees: vec![500, 3000] });
+    }
+
+    #[test]
+    fn encodes_reversed_paths() {
+        let route = Route { tokens: vec![addr(1), addr(3), addr(2)], fees: vec![500, 3000] };
+        let path = route.exact_output_path();
+        assert_eq!(path.len(), 20 * 3 + 3 * 2);
+        assert_eq!(&path[..20], addr(2).as_bytes());
+        assert_eq!(&path[20..23], &[0x00, 0x0b, 0xb8]); // 3000
+        assert_eq!(&path[23..43], addr(3).as_bytes());
+        assert_eq!(&path[43..46], &[0x00, 0x01, 0xf4]); // 500
+        assert_eq!(&path[46..], addr(1).as_bytes());
+    }
+
+    #[test]
+    fn encodes_swaps() {
+        let single = Route { tokens: vec![addr(1), addr(2)], fees: vec![3000] };
+        let calldata = exact_output_calldata(&single, addr(9), 100.into(), 110.into());
+        assert_eq!(calldata[..4], [0x50, 0x23, 0xb4, 0xdf]);
+        let tokens = abi::decode(
+            &[ParamType::Address, ParamType::Address, ParamType::Uint(24), ParamType::Address,
+                ParamType::Uint(256), ParamType::Uint(256), ParamType::Uint(160)],
+            &calldata[4..],
+        )
+        .unwrap();
+        assert_eq!(tokens[3], Token::Address(addr(9)));
+        assert_eq!(tokens[5], Token::Uint(110.into()));
+
+        let multi = Route { tokens: vec![addr(1), addr(3), addr(2)], fees: vec![500, 3000] };
+        let calldata = exact_output_calldata(&multi, addr(9), 100.into(), 110.into());
+        assert_eq!(calldata[..4], [0x09, 0xb8, 0x13, 0x46]);
+        let tokens = abi::decode(
+            &[ParamType::Tuple(vec![ParamType::Bytes, ParamType::Address, ParamType::Uint(256), ParamType::Uint(256)])],
+            &calldata[4..],
+        )
+        .unwrap();
+        assert_eq!(
+            tokens[0],
+            Token::Tuple(vec![
+                Token::Bytes(multi.exact_output_path()),
+                Token::Address(addr(9)),
+                Token::Uint(100.into()),
+                Token::Uint(110.into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_swaps() {
+        let single = Route { tokens: vec![addr(1), addr(2)], fees: vec![3000] };
+        let expected = ExactOutput {
+            token_in: addr(1),
+            token_out: addr(2),
+            recipient: addr(9),
+            amount_out: 100.into(),
+            amount_in_maximum: 110.into(),
+        };
+        let calldata = exact_output_calldata(&single, addr(9), 100.into(), 110.into());
+        assert_eq!(decode_exact_output(&calldata), Some(expected.clone()));
+
+        let multi = Route { tokens: vec![addr(1), addr(3), addr(2)], fees: vec![500, 3000] };
+        let calldata = exact_output_calldata(&multi, addr(9), 100.into(), 110.into());
+        assert_eq!(decode_exact_output(&calldata), Some(expected.clone()));
+
+        // the Node router wraps it in a multicall
+        let wrapped = encode_call(
+            MULTICALL_WITH_DEADLINE,
+            vec![Token::Uint(1.into()), Token::Array(vec![Token::Bytes(calldata.clone())])],
+        );
+        assert_eq!(decode_exact_output(&wrapped), Some(expected));
+        let two_calls = encode_call(MULTICALL, vec![Token::Array(vec![Token::Bytes(calldata.clone()); 2])]);
+        assert_eq!(decode_exact_output(&two_calls), None);
+
+        // anything else, such as an approval, is rejected
+        let approve = encode_call("approve(address,uint256)", vec![Token::Address(addr(9)), Token::Uint(1.into())]);
+        assert_eq!(decode_exact_output(&approve), None);
+        assert_eq!(decode_exact_output(&calldata[..40]), None);
+
+        let msg_sender = exact_output_calldata(&single, Address::from_low_u64_be(MSG_SENDER), 100.into(), 110.into());
+        assert!(decode_exact_output(&msg_sender).unwrap().pays(addr(9)));
+        assert!(!decode_exact_output(&calldata).unwrap().pays(addr(8)));
+    }
+
+    #[test]
+    fn encodes_quotes() {
+        let single = Route { tokens: vec![addr(1), addr(2)], fees: vec![500] };
+        assert_eq!(quote_calldata(&single, 1.into())[..4], [0xbd, 0x21, 0x70, 0x4a]);
+        let multi = Route { tokens: vec![addr(1), addr(3), addr(2)], fees: vec![500, 500] };
+        assert_eq!(quote_calldata(&multi, 1.into())[..4], [0x2f, 0x80, 0xbb, 0x1d]);
+
+        let mut out = abi::encode(&[Token::Uint(42.into()), Token::Uint(7.into())]);
+        assert_eq!(decode_amount_in(&out).unwrap(), 42.into());
+        out.truncate(31);
+        assert!(decode_amount_in(&out).is_err());
+    }
+
+    #[test]
+    fn applies_slippage() {
+        assert_eq!(with_slippage(1000.into(), 3), 1030.into());
+        assert_eq!(with_slippage(1000.into(), 0), 1000.into());
+    }
+}